@@ -14,11 +14,11 @@ fn modify_my_struct(my_struct: &mut MyStruct, new_value: i32) {
 fn main() {
     unsafe { env::set_var("RUST_LOG", "info"); }
     env_logger::init();
-    let foo = AtomicUsize::new(0);
-    assert_eq!(foo.fetch_add(10, Ordering::SeqCst), 0);
-    assert_eq!(foo.load(Ordering::SeqCst), 10);
+    let counter = AtomicUsize::new(0);
+    assert_eq!(counter.fetch_add(10, Ordering::SeqCst), 0);
+    assert_eq!(counter.load(Ordering::SeqCst), 10);
 
-    info!("foo {}", foo.load(Ordering::SeqCst));
+    info!("counter {}", counter.load(Ordering::SeqCst));
 
     // 创建一个MyStruct的可变实例
     let mut my_instance = MyStruct { field: 42 };
@@ -1,8 +1,5 @@
-use std::io::Write;
-use chrono::Local;
-use env_logger::Builder;
 use log::{error, info};
-use cmpp::server::Config;
+use cmpp::server::{init_logger, Config};
 use cmpp::server::server::Server;
 use tokio::{io, signal};
 
@@ -10,31 +7,31 @@ use tokio::{io, signal};
 #[tokio::main]
 
 async fn main() -> io::Result<()> {
-    // 创建一个日志构建器
-    let mut builder = Builder::new();
-
-    // 设置日志格式，使用 chrono 获取本地时间
-    builder.format(|buf, record| {
-        let local_time = Local::now().format("%Y-%m-%d %H:%M:%S");
-        writeln!(
-            buf,
-            "{} [{}] {} {}",
-            local_time,
-            record.level(),
-            record.target(),
-            record.args()
-        )
-    });
-
-    // 设置日志级别
-    builder.filter(None, log::LevelFilter::Info);
-
-    // 初始化日志记录器
-    builder.init();
-
-    let cfg = Config::default();
+    // 配置文件路径: 第一个参数或 CMPP_CONFIG 环境变量, 未指定时使用默认配置
+    let cfg = match std::env::args().nth(1).or_else(|| std::env::var("CMPP_CONFIG").ok()) {
+        Some(path) => Config::from_file(&path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("load config {}: {}", path, e)))?,
+        None => Config::default(),
+    };
+    // 按配置的格式和级别输出日志
+    init_logger(&cfg.log);
+
     let mut srv = Server::new(cfg).await?;
 
+    // 收到 SIGHUP 时重新加载配置, 不断开已有会话
+    #[cfg(unix)]
+    {
+        let handle = srv.config_handle();
+        let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                if let Err(e) = handle.reload() {
+                    error!("reload config failed: {}", e);
+                }
+            }
+        });
+    }
+
     tokio::select! {
         res = srv.run() => {
             // If an error is received here, accepting connections from the TCP
@@ -47,11 +44,39 @@ async fn main() -> io::Result<()> {
                 error!("failed to accept : {:?}", err);
             }
         }
-        _ = signal::ctrl_c() => {
+        _ = shutdown_signal() => {
             // The shutdown signal has been received.
             info!("shutting down");
         }
     }
 
+    // 停止接收新连接后, 等待已有会话完成收尾
+    srv.shutdown().await;
+
     Ok(())
 }
+
+/// 等待 ctrl_c 或 SIGTERM (容器停止时发送)
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                error!("listen SIGTERM failed: {}", e);
+                let _ = signal::ctrl_c().await;
+                return;
+            }
+        };
+
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+    }
+}
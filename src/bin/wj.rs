@@ -1,5 +1,4 @@
 use std::sync::{Arc, Mutex};
-use std::thread::yield_now;
 use std::time::Duration;
 use tokio::io;
 
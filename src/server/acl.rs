@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+
+use crate::server::Config;
+
+// 单 IP 建连频率的统计窗口
+const CONN_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// IP 网段, 支持 CIDR ("10.0.0.0/8") 或单个地址 ("10.0.0.1")
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr(IpNet);
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.contains(&ip.to_canonical())
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(Cidr(net.trunc()));
+        }
+        s.parse::<IpAddr>()
+            .map(|ip| Cidr(IpNet::from(ip)))
+            .map_err(|_| format!("invalid ip or cidr: {}", s))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// 黑名单优先; 白名单为空时不限制
+pub fn ip_allowed(allow: &[Cidr], deny: &[Cidr], ip: &IpAddr) -> bool {
+    if deny.iter().any(|c| c.contains(ip)) {
+        return false;
+    }
+    allow.is_empty() || allow.iter().any(|c| c.contains(ip))
+}
+
+/// 接入保护: IP 黑白名单、单 IP 建连频率和未认证连接数
+#[derive(Default)]
+pub(crate) struct AcceptGuard {
+    // ip -> (窗口开始时间, 窗口内建连次数)
+    conn_rate: Mutex<HashMap<IpAddr, (Instant, usize)>>,
+    unauthenticated: Arc<AtomicUsize>,
+}
+
+/// 未认证连接的占位, 认证成功或连接关闭时释放
+pub(crate) struct UnauthPermit {
+    counter: Arc<AtomicUsize>,
+}
+
+impl Drop for UnauthPermit {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl AcceptGuard {
+    /// 检查是否接受来自 `ip` 的连接, 接受时返回未认证连接的占位
+    pub(crate) fn admit(&self, cfg: &Config, ip: IpAddr) -> Result<UnauthPermit, String> {
+        let ip = ip.to_canonical();
+        if !ip_allowed(&cfg.allow_ips, &cfg.deny_ips, &ip) {
+            return Err(format!("ip {} not allowed", ip));
+        }
+
+        let current = self.unauthenticated.fetch_add(1, Ordering::SeqCst);
+        let permit = UnauthPermit { counter: self.unauthenticated.clone() };
+        if cfg.max_unauthenticated > 0 && current >= cfg.max_unauthenticated {
+            return Err(format!("too many unauthenticated connections: {}", current));
+        }
+
+        // 被未认证连接数限制拒绝的连接不计入速率
+        if cfg.conn_rate_per_ip > 0 {
+            let now = Instant::now();
+            let mut conn_rate = self.conn_rate.lock().unwrap();
+            // 避免统计表无限增长
+            if conn_rate.len() > 10_000 {
+                conn_rate.retain(|_, (start, _)| now.duration_since(*start) < CONN_RATE_WINDOW);
+            }
+            let entry = conn_rate.entry(ip).or_insert((now, 0));
+            if now.duration_since(entry.0) >= CONN_RATE_WINDOW {
+                *entry = (now, 0);
+            }
+            if entry.1 >= cfg.conn_rate_per_ip {
+                return Err(format!("ip {} exceeds {} connections per minute", ip, cfg.conn_rate_per_ip));
+            }
+            entry.1 += 1;
+        }
+        Ok(permit)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::server::acl::{ip_allowed, AcceptGuard, Cidr};
+    use crate::server::Config;
+
+    #[test]
+    fn test_ip_allowed() {
+        let allow: Vec<Cidr> = vec!["10.0.0.0/8".parse().unwrap(), "192.168.1.7".parse().unwrap()];
+        let deny: Vec<Cidr> = vec!["10.1.0.0/16".parse().unwrap()];
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert!(ip_allowed(&allow, &deny, &ip("10.2.3.4")));
+        assert!(ip_allowed(&allow, &deny, &ip("::ffff:192.168.1.7")));
+        assert!(!ip_allowed(&allow, &deny, &ip("10.1.3.4")));
+        assert!(!ip_allowed(&allow, &deny, &ip("192.168.1.8")));
+        assert!(ip_allowed(&[], &deny, &ip("8.8.8.8")));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_admit() {
+        let guard = AcceptGuard::default();
+        let cfg = Config { conn_rate_per_ip: 2, max_unauthenticated: 1, ..Config::demo() };
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let permit = guard.admit(&cfg, ip).unwrap();
+        // 未认证连接数已满, 被拒绝的连接不计入速率
+        assert!(guard.admit(&cfg, ip).is_err());
+        drop(permit);
+        drop(guard.admit(&cfg, ip).unwrap());
+        // 一分钟内第三次建连
+        assert!(guard.admit(&cfg, ip).is_err());
+        assert!(guard.admit(&cfg, "10.0.0.2".parse().unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,489 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::server::cmd::submit::Cmpp3SubmitReqPkt;
+use crate::server::cmd::{ERRNO_SUBMIT_BALANCE, ERRNO_SUBMIT_FEE_CODE};
+use crate::server::config::ConfigRx;
+use crate::server::pipeline::{Flow, SubmitContext, SubmitStage};
+use crate::server::Result;
+
+// 余额有变化时写入余额文件的间隔
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 计费环节记录本条消息的扣费, 用于消息最终未发送时退还
+pub const ATTR_CHARGE: &str = "charge";
+
+/// 资费类别 (FeeType)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeType {
+    /// 01: 免费
+    Free,
+    /// 02: 按条计信息费
+    PerMessage,
+    /// 03: 按包月收取信息费, 单条不收费
+    Monthly,
+    /// 04: 按条计信息费, 由运营商封顶
+    Capped,
+    /// 05: 包月封顶, 按条计信息费直至达到包月费
+    MonthlyCapped,
+}
+
+impl FeeType {
+    pub fn parse(fee_type: &str) -> Option<FeeType> {
+        match fee_type {
+            "01" => Some(FeeType::Free),
+            "02" => Some(FeeType::PerMessage),
+            "03" => Some(FeeType::Monthly),
+            "04" => Some(FeeType::Capped),
+            "05" => Some(FeeType::MonthlyCapped),
+            _ => None,
+        }
+    }
+}
+
+/// 付费方 (Fee_UserType)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChargedParty {
+    /// 0: 接收号码
+    Destination,
+    /// 1: 源号码 (Src_Id)
+    Source,
+    /// 2: SP
+    Sp,
+    /// 3: Fee_terminal_Id 指定的号码
+    ThirdParty(String),
+}
+
+/// 一条 CMPP_SUBMIT 的资费
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Fee {
+    pub fee_type: FeeType,
+    /// 资费代码, 单位分
+    pub fee_code: u32,
+    pub party: ChargedParty,
+}
+
+impl Fee {
+    /// 解析资费字段, 不合法时返回 None
+    pub fn from_submit(submit: &Cmpp3SubmitReqPkt) -> Option<Fee> {
+        let fee_type = FeeType::parse(&submit.fee_type)?;
+        let fee_code = if submit.fee_code.is_empty() { 0 } else { submit.fee_code.parse().ok()? };
+        let party = match submit.fee_user_type {
+            0 => ChargedParty::Destination,
+            1 => ChargedParty::Source,
+            2 => ChargedParty::Sp,
+            3 if !submit.fee_terminal_id.is_empty() => ChargedParty::ThirdParty(submit.fee_terminal_id.clone()),
+            _ => return None,
+        };
+        Some(Fee { fee_type, fee_code, party })
+    }
+
+    /// 每个接收号码的每条短信收取的信息费, 单位分
+    pub fn per_message(&self) -> u32 {
+        match self.fee_type {
+            FeeType::Free | FeeType::Monthly => 0,
+            FeeType::PerMessage | FeeType::Capped | FeeType::MonthlyCapped => self.fee_code,
+        }
+    }
+
+    /// 发往 `dest` 的短信由谁付费, 按 SP 计费时为 SP 代码
+    pub fn charged(&self, sp_id: &str, src_id: &str, dest: &str) -> String {
+        match self.party {
+            ChargedParty::Destination => dest.to_string(),
+            ChargedParty::Source => src_id.to_string(),
+            ChargedParty::Sp => sp_id.to_string(),
+            ChargedParty::ThirdParty(ref terminal) => terminal.clone(),
+        }
+    }
+}
+
+/// SP 的累计用量
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    /// 计费条数, 每个接收号码的每条短信计 1
+    pub segments: u64,
+    /// 信息费, 单位分
+    pub info_fee: u64,
+}
+
+/// 一条消息的扣费, 保存在 `ATTR_CHARGE` 中
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Charge {
+    /// 扣减的预付费余额
+    pub prepaid: i64,
+    /// 每个接收号码实际收取的信息费, 与 `Dest_terminal_Id` 一一对应
+    pub fees: Vec<u32>,
+}
+
+impl Charge {
+    /// 计费环节记录的扣费, 未经过计费环节时返回 None
+    pub fn from_ctx(ctx: &SubmitContext) -> Option<Charge> {
+        serde_json::from_str(ctx.attrs.get(ATTR_CHARGE)?).ok()
+    }
+}
+
+/// 计费环节, 注册在 `Phase::Billing`
+///
+/// 解析资费字段并累计各 SP 的用量; 预付费账号 (配置了 `balance`) 按条扣减余额, 余额不足时拒绝。
+/// 封顶类资费按计费号码累计当月信息费, 达到 `billing.monthly_cap` 后不再收取。
+/// 消息之后被拒绝或过期时退还扣费。
+pub struct Billing {
+    config: ConfigRx,
+    inner: Mutex<BillingInner>,
+}
+
+#[derive(Default)]
+struct BillingInner {
+    // 预付费账号的余额, 首次使用时取账号配置的初始余额
+    balances: HashMap<String, i64>,
+    usage: HashMap<String, Usage>,
+    // 当月 (YYYYMM) 封顶类资费按 (SP, 计费号码) 累计的信息费, 跨月时清空, 重启后重新累计
+    month: String,
+    monthly: HashMap<(String, String), u64>,
+    // 余额变化后尚未写入文件
+    dirty: bool,
+}
+
+impl BillingInner {
+    // 切换到当前月份
+    fn roll(&mut self, month: &str) {
+        if self.month != month {
+            self.month = month.to_string();
+            self.monthly.clear();
+        }
+    }
+}
+
+impl Billing {
+    /// 读取余额文件并定期写回, 需在 tokio 运行时中调用
+    pub fn start(config: ConfigRx) -> Result<Arc<Billing>> {
+        let path = config.borrow().billing.balance_file.clone();
+        let balances = match path {
+            Some(ref path) if path.exists() => load_balances(path)?,
+            _ => HashMap::new(),
+        };
+        let billing = Arc::new(Billing {
+            config,
+            inner: Mutex::new(BillingInner { balances, ..BillingInner::default() }),
+        });
+        tokio::spawn(Self::run(Arc::downgrade(&billing)));
+        Ok(billing)
+    }
+
+    async fn run(billing: Weak<Billing>) {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match billing.upgrade() {
+                Some(billing) => billing.save(),
+                None => return,
+            }
+        }
+    }
+
+    /// 预付费账号的余额, 非预付费账号返回 None
+    pub fn balance(&self, sp_id: &str) -> Option<i64> {
+        let initial = self.config.borrow().account(sp_id)?.balance?;
+        Some(*self.inner.lock().unwrap().balances.get(sp_id).unwrap_or(&initial))
+    }
+
+    /// 为预付费账号充值 (`amount` 为负时扣减), 返回充值后的余额, 非预付费账号返回 None
+    pub fn top_up(&self, sp_id: &str, amount: i64) -> Option<i64> {
+        let initial = self.config.borrow().account(sp_id)?.balance?;
+        let mut inner = self.inner.lock().unwrap();
+        let balance = inner.balances.entry(sp_id.to_string()).or_insert(initial);
+        *balance += amount;
+        let balance = *balance;
+        inner.dirty = true;
+        log::info!("balance topped up, sp_id: {}, amount: {}, balance: {}", sp_id, amount, balance);
+        Some(balance)
+    }
+
+    pub fn usage(&self, sp_id: &str) -> Usage {
+        self.inner.lock().unwrap().usage.get(sp_id).copied().unwrap_or_default()
+    }
+
+    /// 余额有变化时写入余额文件
+    pub fn save(&self) {
+        let path = match self.config.borrow().billing.balance_file.clone() {
+            Some(path) => path,
+            None => return,
+        };
+        let balances = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return;
+            }
+            inner.dirty = false;
+            inner.balances.iter().map(|(k, v)| (k.clone(), *v)).collect::<BTreeMap<_, _>>()
+        };
+        if let Err(e) = save_balances(&path, &balances) {
+            log::error!("save balances to {} failed: {}", path.display(), e);
+            self.inner.lock().unwrap().dirty = true;
+        }
+    }
+}
+
+#[async_trait]
+impl SubmitStage for Billing {
+    fn name(&self) -> &str {
+        "billing"
+    }
+
+    async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+        let fee = match Fee::from_submit(&ctx.submit) {
+            Some(fee) => fee,
+            None => return Flow::Reject(ERRNO_SUBMIT_FEE_CODE),
+        };
+        let segments = ctx.submit.dest_terminal_id.len() as i64;
+        let (initial, result, cap) = {
+            let cfg = self.config.borrow();
+            let initial = cfg.account(&ctx.sp_id).and_then(|a| a.balance);
+            (initial, cfg.billing.result.unwrap_or(ERRNO_SUBMIT_BALANCE), cfg.billing.monthly_cap)
+        };
+        let month = Local::now().format("%Y%m").to_string();
+
+        let mut charge = Charge::default();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(initial) = initial {
+                let balance = inner.balances.entry(ctx.sp_id.clone()).or_insert(initial);
+                if *balance < segments {
+                    log::warn!("insufficient balance, sp_id: {}, balance: {}, segments: {}", ctx.sp_id, balance, segments);
+                    return Flow::Reject(result);
+                }
+                *balance -= segments;
+                charge.prepaid = segments;
+                inner.dirty = true;
+            }
+
+            inner.roll(&month);
+            let submit = &ctx.submit;
+            for dest in &submit.dest_terminal_id {
+                let info_fee = match (fee.fee_type, cap) {
+                    (FeeType::Capped | FeeType::MonthlyCapped, Some(cap)) => {
+                        let key = (ctx.sp_id.clone(), fee.charged(&ctx.sp_id, &submit.src_id, dest));
+                        let total = inner.monthly.entry(key).or_default();
+                        let info_fee = (fee.per_message() as u64).min(cap.saturating_sub(*total));
+                        *total += info_fee;
+                        info_fee as u32
+                    }
+                    _ => fee.per_message(),
+                };
+                charge.fees.push(info_fee);
+            }
+            let usage = inner.usage.entry(ctx.sp_id.clone()).or_default();
+            usage.segments += segments as u64;
+            usage.info_fee += charge.fees.iter().map(|&f| f as u64).sum::<u64>();
+        }
+        if let Ok(charge) = serde_json::to_string(&charge) {
+            ctx.attrs.insert(ATTR_CHARGE.to_string(), charge);
+        }
+        Flow::Continue
+    }
+
+    async fn cancel(&self, ctx: &mut SubmitContext, stat: &str) {
+        let charge = match Charge::from_ctx(ctx) {
+            Some(charge) => charge,
+            None => return,
+        };
+        ctx.attrs.remove(ATTR_CHARGE);
+        let fee = Fee::from_submit(&ctx.submit);
+        let initial = self.config.borrow().account(&ctx.sp_id).and_then(|a| a.balance);
+        let month = Local::now().format("%Y%m").to_string();
+
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(initial) = initial.filter(|_| charge.prepaid > 0) {
+            *inner.balances.entry(ctx.sp_id.clone()).or_insert(initial) += charge.prepaid;
+            inner.dirty = true;
+        }
+        // 跨月后不再退还上月的封顶累计
+        if let Some(ref fee) = fee.filter(|_| inner.month == month) {
+            for (dest, &info_fee) in ctx.submit.dest_terminal_id.iter().zip(&charge.fees) {
+                let key = (ctx.sp_id.clone(), fee.charged(&ctx.sp_id, &ctx.submit.src_id, dest));
+                if let Some(total) = inner.monthly.get_mut(&key) {
+                    *total = total.saturating_sub(info_fee as u64);
+                }
+            }
+        }
+        let usage = inner.usage.entry(ctx.sp_id.clone()).or_default();
+        usage.segments = usage.segments.saturating_sub(charge.fees.len() as u64);
+        usage.info_fee = usage.info_fee.saturating_sub(charge.fees.iter().map(|&f| f as u64).sum());
+        log::info!("charge refunded, sp_id: {}, msg_id: {}, stat: {}, prepaid: {}", ctx.sp_id, ctx.msg_id, stat, charge.prepaid);
+    }
+}
+
+fn load_balances(path: &Path) -> Result<HashMap<String, i64>> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// 先写临时文件再替换, 避免写入中途退出时损坏余额文件
+fn save_balances(path: &Path, balances: &BTreeMap<String, i64>) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(balances)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use crate::server::billing::{Billing, Charge, ChargedParty, Fee, FeeType};
+    use crate::server::cmd::submit::Cmpp3SubmitReqPkt;
+    use crate::server::cmd::ERRNO_SUBMIT_BALANCE;
+    use crate::server::config::{BillingConfig, Config, ConfigHandle};
+    use crate::server::pipeline::{Flow, Phase, Pipeline, SubmitContext, SubmitStage};
+
+    struct Reject;
+
+    #[async_trait]
+    impl SubmitStage for Reject {
+        fn name(&self) -> &str {
+            "reject"
+        }
+
+        async fn process(&self, _ctx: &mut SubmitContext) -> Flow {
+            Flow::Reject(8)
+        }
+    }
+
+    fn submit(fee_user_type: u8, fee_type: &str, fee_code: &str, dests: usize) -> Cmpp3SubmitReqPkt {
+        let mut submit = Cmpp3SubmitReqPkt::default();
+        submit.fee_user_type = fee_user_type;
+        submit.fee_type = fee_type.to_string();
+        submit.fee_code = fee_code.to_string();
+        submit.src_id = "10690001".to_string();
+        submit.dest_terminal_id = (0..dests).map(|i| format!("1380013800{}", i)).collect();
+        submit
+    }
+
+    #[test]
+    fn test_fee() {
+        let fee = Fee::from_submit(&submit(0, "02", "10", 1)).unwrap();
+        assert_eq!(fee, Fee { fee_type: FeeType::PerMessage, fee_code: 10, party: ChargedParty::Destination });
+        assert_eq!(fee.per_message(), 10);
+        assert_eq!(fee.charged("900001", "10690001", "13800138000"), "13800138000");
+
+        let fee = Fee::from_submit(&submit(2, "03", "500", 1)).unwrap();
+        assert_eq!(fee.per_message(), 0);
+        assert_eq!(fee.charged("900001", "10690001", "13800138000"), "900001");
+
+        let mut pkt = submit(3, "01", "", 1);
+        assert!(Fee::from_submit(&pkt).is_none());
+        pkt.fee_terminal_id = "13900139000".to_string();
+        let fee = Fee::from_submit(&pkt).unwrap();
+        assert_eq!(fee.party, ChargedParty::ThirdParty("13900139000".to_string()));
+        assert_eq!(fee.charged("900001", "10690001", "13800138000"), "13900139000");
+
+        assert!(Fee::from_submit(&submit(0, "09", "10", 1)).is_none());
+        assert!(Fee::from_submit(&submit(0, "02", "1x", 1)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_prepaid() {
+        let path = std::env::temp_dir().join(format!("cmpp-balances-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut cfg = Config {
+            billing: BillingConfig { balance_file: Some(path.clone()), ..BillingConfig::default() },
+            ..Config::demo()
+        };
+        cfg.accounts[0].balance = Some(3);
+        let handle = ConfigHandle::new(cfg);
+        let billing = Billing::start(handle.subscribe()).unwrap();
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Phase::Billing, billing.clone());
+        let peer = "127.0.0.1:5000".parse().unwrap();
+
+        let mut ctx = SubmitContext::new("900001", peer, submit(0, "02", "10", 2));
+        assert_eq!(pipeline.process(&mut ctx).await, 0);
+        assert_eq!(billing.balance("900001"), Some(1));
+        let mut ctx = SubmitContext::new("900001", peer, submit(0, "02", "10", 2));
+        assert_eq!(pipeline.process(&mut ctx).await, ERRNO_SUBMIT_BALANCE);
+        assert_eq!(billing.usage("900001").segments, 2);
+        assert_eq!(billing.usage("900001").info_fee, 20);
+
+        assert_eq!(billing.top_up("900001", 10), Some(11));
+        assert_eq!(billing.top_up("999999", 10), None);
+        billing.save();
+
+        // 重启后沿用保存的余额
+        let billing = Billing::start(handle.subscribe()).unwrap();
+        assert_eq!(billing.balance("900001"), Some(11));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_refund() {
+        let mut cfg = Config::demo();
+        cfg.accounts[0].balance = Some(3);
+        let handle = ConfigHandle::new(cfg);
+        let billing = Billing::start(handle.subscribe()).unwrap();
+        let mut pipeline = Pipeline::new().stage(Phase::Dispatch, Reject);
+        pipeline.add(Phase::Billing, billing.clone());
+        let peer = "127.0.0.1:5000".parse().unwrap();
+
+        // 之后的环节拒绝时退还余额和用量
+        let mut ctx = SubmitContext::new("900001", peer, submit(0, "02", "10", 2));
+        assert_eq!(pipeline.process(&mut ctx).await, 8);
+        assert_eq!(billing.balance("900001"), Some(3));
+        assert_eq!(billing.usage("900001").segments, 0);
+        assert_eq!(billing.usage("900001").info_fee, 0);
+        assert!(Charge::from_ctx(&ctx).is_none());
+
+        // 暂停后最终未发送, 如定时消息过期
+        let mut ctx = SubmitContext::new("900001", peer, submit(0, "02", "10", 1));
+        assert_eq!(billing.process(&mut ctx).await, Flow::Continue);
+        assert_eq!(billing.balance("900001"), Some(2));
+        pipeline.cancel(&mut ctx, Phase::Persistence, "EXPIRED").await;
+        assert_eq!(billing.balance("900001"), Some(3));
+        // 已退还的不再重复退还
+        pipeline.cancel(&mut ctx, Phase::Persistence, "EXPIRED").await;
+        assert_eq!(billing.balance("900001"), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_monthly_cap() {
+        let cfg = Config { billing: BillingConfig { monthly_cap: Some(25), ..BillingConfig::default() }, ..Config::demo() };
+        let handle = ConfigHandle::new(cfg);
+        let billing = Billing::start(handle.subscribe()).unwrap();
+        let peer = "127.0.0.1:5000".parse().unwrap();
+
+        // 按计费号码累计当月信息费, 达到上限后不再收取
+        let mut fees = vec![];
+        for _ in 0..3 {
+            let mut ctx = SubmitContext::new("900001", peer, submit(0, "04", "10", 2));
+            assert_eq!(billing.process(&mut ctx).await, Flow::Continue);
+            fees.push(Charge::from_ctx(&ctx).unwrap().fees);
+        }
+        assert_eq!(fees, vec![vec![10, 10], vec![10, 10], vec![5, 5]]);
+        assert_eq!(billing.usage("900001").info_fee, 50);
+
+        // 退还后重新计入上限
+        let mut ctx = SubmitContext::new("900001", peer, submit(0, "05", "10", 1));
+        assert_eq!(billing.process(&mut ctx).await, Flow::Continue);
+        assert_eq!(Charge::from_ctx(&ctx).unwrap().fees, vec![0]);
+        let mut ctx = SubmitContext::new("900001", peer, submit(2, "05", "10", 1));
+        assert_eq!(billing.process(&mut ctx).await, Flow::Continue);
+        assert_eq!(Charge::from_ctx(&ctx).unwrap().fees, vec![10]);
+        billing.cancel(&mut ctx, "RJ:0008").await;
+        let mut ctx = SubmitContext::new("900001", peer, submit(2, "05", "20", 1));
+        assert_eq!(billing.process(&mut ctx).await, Flow::Continue);
+        assert_eq!(Charge::from_ctx(&ctx).unwrap().fees, vec![20]);
+
+        // 非封顶类资费不受上限限制
+        let mut ctx = SubmitContext::new("900001", peer, submit(0, "02", "30", 1));
+        assert_eq!(billing.process(&mut ctx).await, Flow::Continue);
+        assert_eq!(Charge::from_ctx(&ctx).unwrap().fees, vec![30]);
+    }
+}
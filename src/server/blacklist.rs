@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+
+use async_trait::async_trait;
+
+use crate::server::cmd::ERRNO_SUBMIT_DEST_TERMINAL_ID;
+use crate::server::config::{BlacklistAction, Config, ConfigRx};
+use crate::server::pipeline::{Flow, SubmitContext, SubmitStage};
+use crate::server::router::normalize;
+use crate::server::Result;
+
+/// 被去掉的接收号码, 多个以逗号分隔
+pub const ATTR_BLOCKED: &str = "blocked";
+
+/// 被去掉的接收号码的状态报告
+pub const STAT_BLOCKED: &str = "BLKLIST";
+
+/// 接收号码黑白名单, 注册在 `Phase::Filtering`
+///
+/// 依次检查账号白名单、全局黑名单 (配置和文件)、账号黑名单和退订名单。
+/// 按 `Drop` 处理时去掉的号码记录在 `ATTR_BLOCKED` 中, 回复 SP 后按要求下发 BLKLIST 状态报告。
+/// 用户上行退订指令时号码自动加入对应 SP 的退订名单, 并追加到 `unsubscribe_file`。
+pub struct Blacklist {
+    config: ConfigRx,
+    // 全局黑名单文件中的号码, 配置热加载时重新读取
+    file: RwLock<Arc<HashSet<String>>>,
+    // SP -> 退订的号码
+    unsubscribed: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl Blacklist {
+    /// 加载黑名单文件和退订名单, 并在配置变化时重新加载黑名单文件, 需在 tokio 运行时中调用
+    pub fn start(mut config: ConfigRx) -> Result<Arc<Blacklist>> {
+        let cfg = config.borrow_and_update().clone();
+        let unsubscribed = match cfg.blacklist.unsubscribe_file {
+            Some(ref path) if path.exists() => load_unsubscribed(path)?,
+            _ => HashMap::new(),
+        };
+        let blacklist = Arc::new(Blacklist {
+            config: config.clone(),
+            file: RwLock::new(Arc::new(load_file(&cfg)?)),
+            unsubscribed: Mutex::new(unsubscribed),
+        });
+
+        let weak = Arc::downgrade(&blacklist);
+        tokio::spawn(async move {
+            while config.changed().await.is_ok() {
+                let blacklist = match weak.upgrade() {
+                    Some(blacklist) => blacklist,
+                    None => return,
+                };
+                let res = load_file(&config.borrow_and_update());
+                match res {
+                    Ok(numbers) => *blacklist.file.write().unwrap() = Arc::new(numbers),
+                    Err(e) => log::error!("reload blacklist failed, keep old blacklist: {}", e),
+                }
+            }
+        });
+        Ok(blacklist)
+    }
+
+    /// 账号是否不能发往该号码
+    pub fn is_blocked(&self, sp_id: &str, msisdn: &str) -> bool {
+        self.blocked(&self.config.borrow(), sp_id, normalize(msisdn))
+    }
+
+    fn blocked(&self, cfg: &Config, sp_id: &str, msisdn: &str) -> bool {
+        let contains = |list: &[String]| list.iter().any(|n| normalize(n) == msisdn);
+        let account = cfg.account(sp_id);
+        if let Some(account) = account.filter(|a| !a.whitelist.is_empty()) {
+            return !contains(&account.whitelist);
+        }
+        contains(&cfg.blacklist.numbers)
+            || self.file.read().unwrap().contains(msisdn)
+            || account.is_some_and(|a| contains(&a.blacklist))
+            || self.unsubscribed.lock().unwrap().get(sp_id).is_some_and(|s| s.contains(msisdn))
+    }
+
+    /// 号码加入 SP 的退订名单, 已存在时返回 false
+    pub fn unsubscribe(&self, sp_id: &str, msisdn: &str) -> bool {
+        let msisdn = normalize(msisdn);
+        let mut unsubscribed = self.unsubscribed.lock().unwrap();
+        if !unsubscribed.entry(sp_id.to_string()).or_default().insert(msisdn.to_string()) {
+            return false;
+        }
+        log::info!("{} unsubscribed from {}", msisdn, sp_id);
+
+        if let Some(ref path) = self.config.borrow().blacklist.unsubscribe_file {
+            let res = OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut f| writeln!(f, "{},{}", sp_id, msisdn));
+            if let Err(e) = res {
+                log::error!("save unsubscribe to {} failed: {}", path.display(), e);
+            }
+        }
+        true
+    }
+
+    /// 号码从 SP 的退订名单中移除, 不存在时返回 false
+    pub fn resubscribe(&self, sp_id: &str, msisdn: &str) -> bool {
+        let msisdn = normalize(msisdn);
+        let mut unsubscribed = self.unsubscribed.lock().unwrap();
+        if !unsubscribed.get_mut(sp_id).is_some_and(|s| s.remove(msisdn)) {
+            return false;
+        }
+
+        if let Some(ref path) = self.config.borrow().blacklist.unsubscribe_file {
+            let content: String = unsubscribed.iter()
+                .flat_map(|(sp_id, numbers)| numbers.iter().map(move |n| format!("{},{}\n", sp_id, n)))
+                .collect();
+            if let Err(e) = std::fs::write(path, content) {
+                log::error!("save unsubscribe to {} failed: {}", path.display(), e);
+            }
+        }
+        true
+    }
+
+    /// 处理用户发往 SP 的上行, 内容为退订指令时加入退订名单并返回 true
+    pub fn on_mo(&self, sp_id: &str, msisdn: &str, content: &str) -> bool {
+        let content = content.trim();
+        let words = self.config.borrow().blacklist.unsubscribe_words.clone();
+        if !words.iter().any(|w| w.eq_ignore_ascii_case(content)) {
+            return false;
+        }
+        self.unsubscribe(sp_id, msisdn);
+        true
+    }
+}
+
+fn load_file(cfg: &Config) -> Result<HashSet<String>> {
+    let path = match cfg.blacklist.file {
+        Some(ref path) => path,
+        None => return Ok(HashSet::new()),
+    };
+    let content = std::fs::read_to_string(path)?;
+    Ok(content.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| normalize(l).to_string())
+        .collect())
+}
+
+fn load_unsubscribed<P: AsRef<Path>>(path: P) -> Result<HashMap<String, HashSet<String>>> {
+    let content = std::fs::read_to_string(path.as_ref())?;
+    let mut unsubscribed: HashMap<String, HashSet<String>> = HashMap::new();
+    for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        match line.split_once(',') {
+            Some((sp_id, msisdn)) => {
+                unsubscribed.entry(sp_id.trim().to_string()).or_default().insert(normalize(msisdn.trim()).to_string());
+            }
+            None => log::warn!("invalid unsubscribe record: {}", line),
+        }
+    }
+    Ok(unsubscribed)
+}
+
+#[async_trait]
+impl SubmitStage for Blacklist {
+    fn name(&self) -> &str {
+        "blacklist"
+    }
+
+    async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+        let (blocked, allowed, action, result) = {
+            let cfg = self.config.borrow();
+            let (blocked, allowed): (Vec<String>, Vec<String>) = ctx.submit.dest_terminal_id.iter()
+                .cloned()
+                .partition(|d| self.blocked(&cfg, &ctx.sp_id, normalize(d)));
+            (blocked, allowed, cfg.blacklist.action, cfg.blacklist.result.unwrap_or(ERRNO_SUBMIT_DEST_TERMINAL_ID))
+        };
+        if blocked.is_empty() {
+            return Flow::Continue;
+        }
+
+        log::info!("blocked dest, sp_id: {}, dests: {:?}", ctx.sp_id, blocked);
+        if action == BlacklistAction::Reject || allowed.is_empty() {
+            return Flow::Reject(result);
+        }
+        ctx.submit.dest_usr_tl = allowed.len() as u8;
+        ctx.submit.dest_terminal_id = allowed;
+        ctx.attrs.insert(ATTR_BLOCKED.to_string(), blocked.join(","));
+        Flow::Continue
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::server::blacklist::Blacklist;
+    use crate::server::config::{Config, ConfigHandle};
+
+    #[tokio::test]
+    async fn test_blacklist() {
+        let mut cfg = Config::demo();
+        cfg.blacklist.numbers = vec!["13800000000".to_string()];
+        cfg.accounts[0].blacklist = vec!["13800000001".to_string()];
+        let handle = ConfigHandle::new(cfg.clone());
+        let blacklist = Blacklist::start(handle.subscribe()).unwrap();
+
+        assert!(blacklist.is_blocked("900001", "+8613800000000"));
+        assert!(blacklist.is_blocked("900001", "13800000001"));
+        assert!(!blacklist.is_blocked("900002", "13800000001"));
+        assert!(!blacklist.is_blocked("900001", "13800000002"));
+
+        // 退订指令
+        assert!(!blacklist.on_mo("900001", "13800000002", "hello"));
+        assert!(blacklist.on_mo("900001", "13800000002", " td "));
+        assert!(blacklist.is_blocked("900001", "13800000002"));
+        assert!(!blacklist.is_blocked("900002", "13800000002"));
+        assert!(blacklist.resubscribe("900001", "13800000002"));
+        assert!(!blacklist.is_blocked("900001", "13800000002"));
+
+        // 测试账号只能发往白名单号码
+        cfg.accounts[0].whitelist = vec!["13800000000".to_string()];
+        handle.update(cfg);
+        assert!(!blacklist.is_blocked("900001", "13800000000"));
+        assert!(blacklist.is_blocked("900001", "13800000002"));
+    }
+}
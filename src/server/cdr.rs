@@ -0,0 +1,389 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local, NaiveDateTime};
+use serde::Serialize;
+
+use crate::server::billing::{Charge, Fee};
+use crate::server::cmd::deliver::Cmpp3DeliverReqPkt;
+use crate::server::config::{CdrConfig, CdrFormat, CdrRotate, ConfigRx};
+use crate::server::msgid::MsgIdGen;
+use crate::server::pipeline::{Flow, SubmitContext, SubmitStage, ATTR_MSG_ID};
+use crate::server::session::DeliverHook;
+
+// 等待状态报告的最大话单数, 超出时最早的以 UNKNOWN 状态写入
+const MAX_PENDING: usize = 1_000_000;
+// 检查等待超时的间隔
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// 未收到状态报告的话单状态
+const STAT_UNKNOWN: &str = "UNKNOWN";
+// 话单中的时间格式
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// CSV 表头, 与 `CdrRecord` 的字段顺序一致
+const CSV_HEADER: &str = "msg_id,submit_time,sp_id,service_id,fee_user_type,fee_type,fee_code,fee_terminal_id,\
+                          charged,info_fee,dest_terminal_id,segments,stat,done_time";
+
+/// 一条 CMPP_SUBMIT 中一个接收号码的话单
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CdrRecord {
+    pub msg_id: u64,
+    pub submit_time: String,
+    pub sp_id: String,
+    pub service_id: String,
+    pub fee_user_type: u8,
+    pub fee_type: String,
+    pub fee_code: String,
+    pub fee_terminal_id: String,
+    /// 付费号码, 按 SP 计费时为 SP 代码
+    pub charged: String,
+    /// 信息费, 单位分
+    pub info_fee: u32,
+    pub dest_terminal_id: String,
+    /// 计费条数, 长短信的每个分段各有一条话单, 恒为 1
+    pub segments: u8,
+    /// 状态报告的 Stat, 超时未收到时为 UNKNOWN
+    pub stat: String,
+    /// 状态报告的完成时间
+    pub done_time: String,
+}
+
+impl CdrRecord {
+    fn to_csv(&self) -> String {
+        [
+            self.msg_id.to_string(),
+            self.submit_time.clone(),
+            self.sp_id.clone(),
+            self.service_id.clone(),
+            self.fee_user_type.to_string(),
+            self.fee_type.clone(),
+            self.fee_code.clone(),
+            self.fee_terminal_id.clone(),
+            self.charged.clone(),
+            self.info_fee.to_string(),
+            self.dest_terminal_id.clone(),
+            self.segments.to_string(),
+            self.stat.clone(),
+            self.done_time.clone(),
+        ].iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(",")
+    }
+}
+
+/// 话单
+///
+/// 注册在 `Phase::Persistence`, 为通过前序环节的提交分配 Msg_Id (已分配时沿用) 并记录;
+/// 作为投递拦截在收到状态报告后写入话单文件, 超时未收到状态报告的以 UNKNOWN 状态写入,
+/// 被之后的环节拒绝的以拒绝时的状态写入。未配置 `cdr` 时不记录。
+pub struct Cdr {
+    config: ConfigRx,
+    ids: Arc<MsgIdGen>,
+    inner: Mutex<CdrInner>,
+    // 当前话单文件, 切换周期或目录变化时重新打开
+    file: Mutex<Option<(PathBuf, File)>>,
+}
+
+#[derive(Default)]
+struct CdrInner {
+    pending: HashMap<(u64, String), CdrRecord>,
+    // 按提交顺序, 用于超时检查
+    order: VecDeque<((u64, String), Instant)>,
+}
+
+impl Cdr {
+    /// 创建话单并定期写入等待超时的话单, 需在 tokio 运行时中调用
+    pub fn start(config: ConfigRx, ids: Arc<MsgIdGen>) -> Arc<Cdr> {
+        let cdr = Arc::new(Cdr {
+            ids,
+            config,
+            inner: Mutex::default(),
+            file: Mutex::new(None),
+        });
+        tokio::spawn(Self::run(Arc::downgrade(&cdr)));
+        cdr
+    }
+
+    async fn run(cdr: Weak<Cdr>) {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let cdr = match cdr.upgrade() {
+                Some(cdr) => cdr,
+                None => return,
+            };
+            let timeout = cdr.config.borrow().cdr.as_ref().map(|c| c.report_timeout);
+            if let Some(hours) = timeout {
+                cdr.sweep(Duration::from_secs(hours * 3600));
+            }
+        }
+    }
+
+    /// 等待状态报告的话单数
+    pub fn pending(&self) -> usize {
+        self.inner.lock().unwrap().pending.len()
+    }
+
+    /// 以 UNKNOWN 状态写入等待超过 `timeout` 的话单
+    fn sweep(&self, timeout: Duration) {
+        let mut expired = vec![];
+        {
+            let mut inner = self.inner.lock().unwrap();
+            while let Some((_, at)) = inner.order.front() {
+                if inner.pending.len() <= MAX_PENDING && at.elapsed() < timeout {
+                    break;
+                }
+                let (key, _) = inner.order.pop_front().unwrap();
+                if let Some(record) = inner.pending.remove(&key) {
+                    expired.push(record);
+                }
+            }
+        }
+        for mut record in expired {
+            record.stat = STAT_UNKNOWN.to_string();
+            self.write(&record);
+        }
+    }
+
+    fn write(&self, record: &CdrRecord) {
+        let cfg = match self.config.borrow().cdr.clone() {
+            Some(cfg) => cfg,
+            None => return,
+        };
+        let path = file_path(&cfg, Local::now());
+        let mut file = self.file.lock().unwrap();
+        if file.as_ref().is_none_or(|(p, _)| *p != path) {
+            match open(&cfg, &path) {
+                Ok(f) => *file = Some((path, f)),
+                Err(e) => {
+                    log::error!("open cdr file {} failed: {}, drop msg_id: {}", path.display(), e, record.msg_id);
+                    return;
+                }
+            }
+        }
+        if let Some((_, ref mut f)) = *file {
+            let line = match cfg.format {
+                CdrFormat::Csv => record.to_csv(),
+                CdrFormat::Jsonl => serde_json::to_string(record).unwrap_or_default(),
+            };
+            if let Err(e) = writeln!(f, "{}", line) {
+                log::error!("write cdr failed: {}, msg_id: {}", e, record.msg_id);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SubmitStage for Cdr {
+    fn name(&self) -> &str {
+        "cdr"
+    }
+
+    async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+        if self.config.borrow().cdr.is_none() {
+            return Flow::Continue;
+        }
+        // 话单以回复给 SP 的 Msg_Id 匹配状态报告, 须在转发上游之前确定
+        if !ctx.attrs.contains_key(ATTR_MSG_ID) {
+            ctx.msg_id = self.ids.next_id();
+            ctx.attrs.insert(ATTR_MSG_ID.to_string(), "cdr".to_string());
+        }
+        let submit = &ctx.submit;
+        let fee = Fee::from_submit(submit);
+        // 经过计费环节时记录实际收取的信息费 (封顶后可能少于资费代码)
+        let fees = Charge::from_ctx(ctx).map(|charge| charge.fees);
+        let submit_time = Local::now().format(TIME_FORMAT).to_string();
+        let mut inner = self.inner.lock().unwrap();
+        for (i, dest) in submit.dest_terminal_id.iter().enumerate() {
+            let key = (ctx.msg_id, dest.clone());
+            inner.pending.insert(key.clone(), CdrRecord {
+                msg_id: ctx.msg_id,
+                submit_time: submit_time.clone(),
+                sp_id: ctx.sp_id.clone(),
+                service_id: submit.service_id.clone(),
+                fee_user_type: submit.fee_user_type,
+                fee_type: submit.fee_type.clone(),
+                fee_code: submit.fee_code.clone(),
+                fee_terminal_id: submit.fee_terminal_id.clone(),
+                charged: fee.as_ref().map(|f| f.charged(&ctx.sp_id, &submit.src_id, dest)).unwrap_or_default(),
+                info_fee: fees.as_ref().and_then(|fees| fees.get(i).copied())
+                    .unwrap_or_else(|| fee.as_ref().map_or(0, Fee::per_message)),
+                dest_terminal_id: dest.clone(),
+                segments: 1,
+                stat: String::new(),
+                done_time: String::new(),
+            });
+            inner.order.push_back((key, Instant::now()));
+        }
+        Flow::Continue
+    }
+
+    async fn cancel(&self, ctx: &mut SubmitContext, stat: &str) {
+        let records: Vec<CdrRecord> = {
+            let mut inner = self.inner.lock().unwrap();
+            ctx.submit.dest_terminal_id.iter()
+                .filter_map(|dest| inner.pending.remove(&(ctx.msg_id, dest.clone())))
+                .collect()
+        };
+        let done_time = Local::now().format(TIME_FORMAT).to_string();
+        for mut record in records {
+            record.stat = stat.to_string();
+            record.done_time = done_time.clone();
+            self.write(&record);
+        }
+    }
+}
+
+impl DeliverHook for Cdr {
+    fn intercept(&self, _sp_id: &str, deliver: Cmpp3DeliverReqPkt) -> Option<Cmpp3DeliverReqPkt> {
+        if let Some(ref report) = deliver.report {
+            let key = (report.msg_id, report.dest_terminal_id.clone());
+            let record = self.inner.lock().unwrap().pending.remove(&key);
+            if let Some(mut record) = record {
+                record.stat = report.stat.clone();
+                record.done_time = done_time(&report.done_time);
+                self.write(&record);
+            }
+        }
+        Some(deliver)
+    }
+}
+
+/// 按切换周期生成的话单文件路径
+fn file_path(cfg: &CdrConfig, now: DateTime<Local>) -> PathBuf {
+    let period = match cfg.rotate {
+        CdrRotate::Hourly => now.format("%Y%m%d%H"),
+        CdrRotate::Daily => now.format("%Y%m%d"),
+    };
+    let ext = match cfg.format {
+        CdrFormat::Csv => "csv",
+        CdrFormat::Jsonl => "jsonl",
+    };
+    cfg.dir.join(format!("cdr-{}.{}", period, ext))
+}
+
+/// 追加打开话单文件, 新建的 CSV 文件先写入表头
+fn open(cfg: &CdrConfig, path: &Path) -> std::io::Result<File> {
+    std::fs::create_dir_all(&cfg.dir)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if cfg.format == CdrFormat::Csv && file.metadata()?.len() == 0 {
+        writeln!(file, "{}", CSV_HEADER)?;
+    }
+    Ok(file)
+}
+
+/// 状态报告中 YYMMDDHHMM 格式的时间, 无法解析时使用当前时间
+fn done_time(time: &str) -> String {
+    match NaiveDateTime::parse_from_str(&format!("{}00", time), "%y%m%d%H%M%S") {
+        Ok(time) => time.format(TIME_FORMAT).to_string(),
+        Err(_) => Local::now().format(TIME_FORMAT).to_string(),
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use chrono::{Local, TimeZone};
+
+    use crate::server::cdr::{file_path, Cdr};
+    use crate::server::cmd::deliver::{Cmpp3DeliverReqPkt, CmppReport};
+    use crate::server::cmd::submit::Cmpp3SubmitReqPkt;
+    use crate::server::config::{CdrConfig, CdrFormat, CdrRotate, Config, ConfigHandle};
+    use crate::server::msgid::MsgIdGen;
+    use crate::server::pipeline::{Flow, Phase, Pipeline, SubmitContext, SubmitStage, ATTR_MSG_ID};
+    use crate::server::session::DeliverHook;
+
+    #[test]
+    fn test_file_path() {
+        let now = Local.with_ymd_and_hms(2024, 1, 2, 13, 4, 5).unwrap();
+        let cfg = CdrConfig::default();
+        assert_eq!(file_path(&cfg, now).to_str(), Some("cdr/cdr-2024010213.csv"));
+        let cfg = CdrConfig { rotate: CdrRotate::Daily, format: CdrFormat::Jsonl, ..CdrConfig::default() };
+        assert_eq!(file_path(&cfg, now).to_str(), Some("cdr/cdr-20240102.jsonl"));
+    }
+
+    #[tokio::test]
+    async fn test_cdr() {
+        let dir = std::env::temp_dir().join(format!("cmpp-cdr-{}", std::process::id()));
+        let cfg = Config { cdr: Some(CdrConfig { dir: dir.clone(), ..CdrConfig::default() }), ..Config::demo() };
+        let handle = ConfigHandle::new(cfg);
+        let cdr = Cdr::start(handle.subscribe(), Arc::new(MsgIdGen::new(1)));
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Phase::Persistence, cdr.clone());
+
+        let mut submit = Cmpp3SubmitReqPkt::default();
+        submit.service_id = "svc".to_string();
+        submit.fee_type = "02".to_string();
+        submit.fee_code = "10".to_string();
+        // 长短信的一个分段只计一条
+        submit.pk_total = 3;
+        submit.pk_number = 1;
+        submit.dest_terminal_id = vec!["13800138000".to_string(), "13800138001".to_string()];
+        let mut ctx = SubmitContext::new("900001", "127.0.0.1:5000".parse().unwrap(), submit);
+        ctx.msg_id = 42;
+        ctx.attrs.insert(ATTR_MSG_ID.to_string(), "test".to_string());
+        assert_eq!(pipeline.process(&mut ctx).await, 0);
+        assert_eq!(ctx.msg_id, 42);
+        assert_eq!(cdr.pending(), 2);
+
+        let mut deliver = Cmpp3DeliverReqPkt::new();
+        deliver.report = Some(CmppReport {
+            msg_id: 42,
+            stat: "DELIVRD".to_string(),
+            done_time: "2401021304".to_string(),
+            dest_terminal_id: "13800138000".to_string(),
+            ..CmppReport::default()
+        });
+        assert!(cdr.intercept("900001", deliver).is_some());
+        cdr.sweep(Duration::ZERO);
+        assert_eq!(cdr.pending(), 0);
+
+        let path = file_path(&handle.current().cdr.clone().unwrap(), Local::now());
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("msg_id,submit_time,sp_id"));
+        assert!(lines[1].starts_with("42,")
+                    && lines[1].ends_with(",900001,svc,0,02,10,,13800138000,10,13800138000,1,DELIVRD,2024-01-02 13:04:00"),
+                "{}", lines[1]);
+        assert!(lines[2].ends_with(",13800138001,1,UNKNOWN,"), "{}", lines[2]);
+
+        // 被之后的环节拒绝时以拒绝的结果写入
+        pipeline.add(Phase::Dispatch, Arc::new(Reject));
+        let mut ctx = SubmitContext::new("900001", "127.0.0.1:5000".parse().unwrap(), Cmpp3SubmitReqPkt::default());
+        ctx.submit.dest_terminal_id = vec!["13800138002".to_string()];
+        assert_eq!(pipeline.process(&mut ctx).await, 8);
+        assert_eq!(cdr.pending(), 0);
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.lines().nth(3).unwrap().contains(",13800138002,1,RJ:0008,"), "{}", content);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    struct Reject;
+
+    #[async_trait]
+    impl SubmitStage for Reject {
+        fn name(&self) -> &str {
+            "reject"
+        }
+
+        async fn process(&self, _ctx: &mut SubmitContext) -> Flow {
+            Flow::Reject(8)
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use bytes::BufMut;
 
-use crate::server::cmd::CMPP_ACTIVE_TEST;
+use crate::server::cmd::{CMPP_ACTIVE_TEST, CMPP_ACTIVE_TEST_RESP, CMPP_HEADER_LEN};
 
 #[derive(Debug, Clone)]
 pub struct CmppActiveTestReqPkt {
@@ -33,11 +33,24 @@ impl CmppActiveTestReqPkt {
 
 }
 
-#[allow(unused)]
 #[derive(Debug, Clone)]
 pub struct CmppActiveTestRspPkt {
     reserved: u8,
     // session info
-    seq_id: u32
+    pub(crate) seq_id: u32
+}
+
+impl CmppActiveTestRspPkt {
+
+    pub fn pack(&self) -> crate::server::Result<Vec<u8>> {
+        let pkt_len = CMPP_HEADER_LEN + 1;
+        let mut buffer = Vec::with_capacity(pkt_len as usize);
+        buffer.put_u32(pkt_len);
+        buffer.put_u32(CMPP_ACTIVE_TEST_RESP);
+        buffer.put_u32(self.seq_id);
+        buffer.put_u8(self.reserved);
+        Ok(buffer)
+    }
+
 }
 
@@ -1,10 +1,16 @@
+use std::fmt;
+
 use bytes::{Buf, BufMut};
 
-use crate::server::cmd::{CMPP3CONN_RSP_PKT_LEN, CMPP_CONNECT_RESP};
+use crate::server::cmd::{CMPP3CONN_RSP_PKT_LEN, CMPP_CONNECT, CMPP_CONNECT_RESP, CMPP_HEADER_LEN};
+use crate::server::logging::redact;
 use crate::server::Result;
 use crate::util::str::{oct_string, octet_string};
 
-#[derive(Debug, Clone)]
+// CMPP_CONNECT 消息体长度: Source_Addr, AuthenticatorSource, Version, Timestamp
+const CONN_REQ_BODY_LEN: usize = 6 + 16 + 1 + 4;
+
+#[derive(Clone)]
 pub struct CmppConnReqPkt {
     pub src_addr: String,
     pub auth_src: Vec<u8>,
@@ -14,8 +20,58 @@ pub struct CmppConnReqPkt {
     pub seq_id: u32,
 }
 
+// 日志中不输出 AuthenticatorSource 和密码
+impl fmt::Debug for CmppConnReqPkt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CmppConnReqPkt")
+            .field("src_addr", &self.src_addr)
+            .field("auth_src", &redact(self.auth_src.len()))
+            .field("version", &self.version)
+            .field("timestamp", &self.timestamp)
+            .field("secret", &redact(self.secret.len()))
+            .field("seq_id", &self.seq_id)
+            .finish()
+    }
+}
+
+/// AuthenticatorSource = MD5(Source_Addr + 9 字节 0 + secret + timestamp)
+pub fn authenticator(sp_id: &str, password: &str, timestamp: u32) -> [u8; 16] {
+    let mut buf = Vec::with_capacity(6 + 9 + password.len() + 10);
+    buf.extend_from_slice(octet_string(sp_id.to_string(), 6).as_bytes());
+    buf.extend_from_slice(&[0u8; 9]);
+    buf.extend_from_slice(password.as_bytes());
+    buf.extend_from_slice(format!("{:010}", timestamp).as_bytes());
+    md5::compute(&buf).0
+}
+
 impl CmppConnReqPkt {
 
+    /// 作为客户端登录上游网关的请求, timestamp 为 MMDDHHMMSS
+    pub fn login(sp_id: &str, password: &str, version: u8, timestamp: u32, seq_id: u32) -> CmppConnReqPkt {
+        CmppConnReqPkt {
+            src_addr: sp_id.to_string(),
+            auth_src: authenticator(sp_id, password, timestamp).to_vec(),
+            version,
+            timestamp,
+            secret: password.to_string(),
+            seq_id,
+        }
+    }
+
+    pub fn pack(&self) -> Result<Vec<u8>> {
+        let pkt_len = CMPP_HEADER_LEN + 6 + 16 + 1 + 4;
+        let mut buffer = Vec::with_capacity(pkt_len as usize);
+        buffer.put_u32(pkt_len);
+        buffer.put_u32(CMPP_CONNECT);
+        buffer.put_u32(self.seq_id);
+
+        buffer.put_slice(octet_string(self.src_addr.clone(), 6).as_bytes());
+        buffer.put_slice(&self.auth_src);
+        buffer.put_u8(self.version);
+        buffer.put_u32(self.timestamp);
+        Ok(buffer)
+    }
+
     fn new() -> CmppConnReqPkt {
         CmppConnReqPkt {
             src_addr: "".to_string(),
@@ -27,8 +83,12 @@ impl CmppConnReqPkt {
         }
     }
 
-    pub(crate) fn parse_frame(_seq_id: u32, data: &mut Vec<u8>) -> Result<CmppConnReqPkt>{
+    pub(crate) fn parse_frame(seq_id: u32, data: &mut [u8]) -> Result<CmppConnReqPkt>{
+        if data.len() < CONN_REQ_BODY_LEN {
+            return Err(format!("connect body too short: {}", data.len()).into());
+        }
         let mut pkt = CmppConnReqPkt::new();
+        pkt.seq_id = seq_id;
 
         let mut buf = bytes::BytesMut::with_capacity(data.len());
         buf.extend_from_slice(data);
@@ -73,6 +133,24 @@ pub struct Cmpp3ConnRspPkt {
 }
 
 impl Cmpp3ConnRspPkt {
+
+    pub(crate) fn parse_frame(seq_id: u32, data: &[u8]) -> Result<Cmpp3ConnRspPkt> {
+        if data.len() < 4 + 16 + 1 {
+            return Err("connect resp too short".into());
+        }
+        let mut buf = data;
+        let status = buf.get_u32();
+        buf.advance(16);
+        let version = buf.get_u8();
+        Ok(Cmpp3ConnRspPkt {
+            status,
+            auth_ismg: "".to_string(),
+            version,
+            secret: "".to_string(),
+            auth_src: "".to_string(),
+            seq_id,
+        })
+    }
     pub fn pack(self) -> Result<Vec<u8>> {
         // pack header
         let mut buffer = Vec::with_capacity(CMPP3CONN_RSP_PKT_LEN as usize);
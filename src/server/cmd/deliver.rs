@@ -1,10 +1,71 @@
+use std::fmt;
+
 use bytes::{Buf, BufMut};
+use serde::{Deserialize, Serialize};
 
-use crate::server::cmd::{CMPP_DELIVER, CMPP_HEADER_LEN};
+use crate::server::cmd::submit::decode_content;
+use crate::server::cmd::{CMPP_DELIVER, CMPP_DELIVER_RES, CMPP_HEADER_LEN};
+use crate::server::logging::redact;
 use crate::server::Result;
-use crate::util::str::octet_string;
+use crate::util::str::{oct_string, octet_string};
 
-#[derive(Debug, Clone)]
+// 状态报告内容长度
+const CMPP_REPORT_LEN: usize = 8 + 7 + 10 + 10 + 32 + 4;
+// Msg_Length 之前的定长部分
+const DELIVER_FIXED_LEN: usize = 8 + 21 + 10 + 3 + 32 + 3;
+
+/// Registered_Delivery 为 1 时 Msg_Content 中的状态报告
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CmppReport {
+    /// 对应 CMPP_SUBMIT_RESP 中的 Msg_Id
+    pub msg_id: u64,
+    pub stat: String,
+    /// YYMMDDHHMM
+    pub submit_time: String,
+    pub done_time: String,
+    pub dest_terminal_id: String,
+    pub smsc_sequence: u32,
+}
+
+impl CmppReport {
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(CMPP_REPORT_LEN);
+        buffer.put_u64(self.msg_id);
+        buffer.put_slice(octet_string(self.stat.clone(), 7).as_bytes());
+        buffer.put_slice(octet_string(self.submit_time.clone(), 10).as_bytes());
+        buffer.put_slice(octet_string(self.done_time.clone(), 10).as_bytes());
+        buffer.put_slice(octet_string(self.dest_terminal_id.clone(), 32).as_bytes());
+        buffer.put_u32(self.smsc_sequence);
+        buffer
+    }
+
+    pub fn parse(data: &[u8]) -> Result<CmppReport> {
+        if data.len() < CMPP_REPORT_LEN {
+            return Err(format!("report too short: {}", data.len()).into());
+        }
+        let mut buf = data;
+        let msg_id = buf.get_u64();
+        let mut field = |len: usize| {
+            let s = oct_string(buf[..len].to_vec());
+            buf.advance(len);
+            s
+        };
+        let stat = field(7);
+        let submit_time = field(10);
+        let done_time = field(10);
+        let dest_terminal_id = field(32);
+        Ok(CmppReport {
+            msg_id,
+            stat,
+            submit_time,
+            done_time,
+            dest_terminal_id,
+            smsc_sequence: buf.get_u32(),
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Cmpp3DeliverReqPkt {
     pub msg_id: u64,
     pub dest_id: String,
@@ -17,12 +78,47 @@ pub struct Cmpp3DeliverReqPkt {
     pub register_delivery: u8,
     pub msg_length: u8,
     pub msg_content: String,
+    /// 按 Msg_Fmt 编码的消息内容, 为空时按 UTF-8 打包 `msg_content`
+    #[serde(default)]
+    pub msg_bytes: Vec<u8>,
     pub link_id: String,
+    /// 状态报告, 存在时按状态报告打包
+    #[serde(default)]
+    pub report: Option<CmppReport>,
 
     //session info
     pub seq_id: u32,
 }
 
+// 日志中不输出上行内容, 状态报告照常输出
+impl fmt::Debug for Cmpp3DeliverReqPkt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let content = self.msg_bytes.len().max(self.msg_content.len());
+        f.debug_struct("Cmpp3DeliverReqPkt")
+            .field("msg_id", &self.msg_id)
+            .field("dest_id", &self.dest_id)
+            .field("service_id", &self.service_id)
+            .field("tp_pid", &self.tp_pid)
+            .field("tp_udhi", &self.tp_udhi)
+            .field("msg_fmt", &self.msg_fmt)
+            .field("src_terminal_id", &self.src_terminal_id)
+            .field("src_terminal_type", &self.src_terminal_type)
+            .field("register_delivery", &self.register_delivery)
+            .field("msg_length", &self.msg_length)
+            .field("msg_content", &redact(content))
+            .field("link_id", &self.link_id)
+            .field("report", &self.report)
+            .field("seq_id", &self.seq_id)
+            .finish()
+    }
+}
+
+impl Default for Cmpp3DeliverReqPkt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Cmpp3DeliverReqPkt {
 
     pub fn new() -> Cmpp3DeliverReqPkt {
@@ -38,13 +134,61 @@ impl Cmpp3DeliverReqPkt {
             register_delivery: 0,
             msg_length: 0,
             msg_content: "".to_string(),
+            msg_bytes: vec![],
             link_id: "".to_string(),
+            report: None,
             seq_id: 0,
         }
     }
 
+    /// 作为客户端解析上游网关下发的 CMPP_DELIVER
+    pub(crate) fn parse_frame(seq_id: u32, data: &[u8]) -> Result<Cmpp3DeliverReqPkt> {
+        if data.len() < DELIVER_FIXED_LEN + 20 {
+            return Err(format!("deliver body too short: {}", data.len()).into());
+        }
+        let mut buf = data;
+        let mut pkt = Cmpp3DeliverReqPkt::new();
+        pkt.seq_id = seq_id;
+        pkt.msg_id = buf.get_u64();
+        pkt.dest_id = oct_string(buf[..21].to_vec());
+        buf.advance(21);
+        pkt.service_id = oct_string(buf[..10].to_vec());
+        buf.advance(10);
+        pkt.tp_pid = buf.get_u8();
+        pkt.tp_udhi = buf.get_u8();
+        pkt.msg_fmt = buf.get_u8();
+        pkt.src_terminal_id = oct_string(buf[..32].to_vec());
+        buf.advance(32);
+        pkt.src_terminal_type = buf.get_u8();
+        pkt.register_delivery = buf.get_u8();
+        pkt.msg_length = buf.get_u8();
+        if buf.remaining() != pkt.msg_length as usize + 20 {
+            return Err(format!("deliver msg_length {} mismatch", pkt.msg_length).into());
+        }
+        let content = &buf[..pkt.msg_length as usize];
+        if pkt.register_delivery == 1 {
+            pkt.report = Some(CmppReport::parse(content)?);
+        } else {
+            pkt.msg_content = decode_content(pkt.msg_fmt, content);
+            pkt.msg_bytes = content.to_vec();
+        }
+        buf.advance(pkt.msg_length as usize);
+        pkt.link_id = oct_string(buf[..20].to_vec());
+        Ok(pkt)
+    }
+
     pub fn pack(&self) -> Result<Vec<u8>> {
-        let pkt_len = CMPP_HEADER_LEN + 77 + self.msg_length as u32 + 20u32;
+        if let Some(ref report) = self.report {
+            return self.pack_content(1, &report.pack());
+        }
+        if !self.msg_bytes.is_empty() {
+            return self.pack_content(self.register_delivery, &self.msg_bytes);
+        }
+        self.pack_content(self.register_delivery, self.msg_content.as_bytes())
+    }
+
+    fn pack_content(&self, register_delivery: u8, content: &[u8]) -> Result<Vec<u8>> {
+        let pkt_len = CMPP_HEADER_LEN + 77 + content.len() as u32 + 20u32;
         let mut buffer = Vec::with_capacity(pkt_len as usize);
 
         buffer.put_u32(pkt_len);
@@ -59,9 +203,9 @@ impl Cmpp3DeliverReqPkt {
         buffer.put_u8(self.msg_fmt);
         buffer.put_slice(octet_string(self.src_terminal_id.clone(), 32).as_bytes());
         buffer.put_u8(self.src_terminal_type);
-        buffer.put_u8(self.register_delivery);
-        buffer.put_u8(self.msg_length);
-        buffer.put_slice(self.msg_content.clone().as_bytes());
+        buffer.put_u8(register_delivery);
+        buffer.put_u8(content.len() as u8);
+        buffer.put_slice(content);
         buffer.put_slice(octet_string(self.link_id.clone(), 20).as_bytes());
 
         Ok(buffer)
@@ -76,8 +220,21 @@ pub struct Cmpp3DeliverResPkt {
 }
 
 impl Cmpp3DeliverResPkt {
-    pub(crate) fn parse_frame(seq_id: u32, data: &Vec<u8>) -> Result<Cmpp3DeliverResPkt> {
+    pub fn pack(&self) -> Result<Vec<u8>> {
+        let pkt_len = CMPP_HEADER_LEN + 8 + 4;
+        let mut buffer = Vec::with_capacity(pkt_len as usize);
+        buffer.put_u32(pkt_len);
+        buffer.put_u32(CMPP_DELIVER_RES);
+        buffer.put_u32(self.seq_id);
+        buffer.put_u64(self.msg_id);
+        buffer.put_u32(self.result);
+        Ok(buffer)
+    }
 
+    pub(crate) fn parse_frame(seq_id: u32, data: &[u8]) -> Result<Cmpp3DeliverResPkt> {
+        if data.len() < 8 + 4 {
+            return Err(format!("deliver resp body too short: {}", data.len()).into());
+        }
         let mut buf = bytes::BytesMut::with_capacity(data.len());
         buf.extend_from_slice(data);
 
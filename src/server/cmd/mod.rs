@@ -2,17 +2,21 @@ use crate::server::cmd::active::{CmppActiveTestReqPkt, CmppActiveTestRspPkt};
 use crate::server::cmd::connect::{Cmpp3ConnRspPkt, CmppConnReqPkt};
 use crate::server::cmd::deliver::{Cmpp3DeliverReqPkt, Cmpp3DeliverResPkt};
 use crate::server::cmd::submit::{Cmpp3SubmitReqPkt, Cmpp3SubmitRspPkt};
-use crate::server::cmd::unknown::Unknown;
+use crate::server::cmd::terminate::{CmppTerminateReqPkt, CmppTerminateRspPkt};
+use crate::server::cmd::unknown::{CmppNackPkt, Unknown};
 use crate::server::Result;
 
 pub mod connect;
-mod unknown;
-pub(crate) mod submit;
+pub mod unknown;
+pub mod submit;
 pub mod deliver;
 pub mod active;
+pub mod terminate;
 
 pub const CMPP_CONNECT: u32 = 1;
 pub const CMPP_CONNECT_RESP: u32 = 2147483649;
+pub const CMPP_TERMINATE: u32 = 2;
+pub const CMPP_TERMINATE_RESP: u32 = 2147483650;
 pub const CMPP_SUBMIT: u32 = 4;
 pub const CMPP_SUBMIT_RESP: u32 = 2147483652;
 
@@ -26,8 +30,8 @@ pub const CMPP_HEADER_LEN: u32 = 12;
 //39d, 0x27
 const CMPP3CONN_RSP_PKT_LEN: u32 = 4 + 4 + 4 + 4 + 16 + 1;    //33d, 0x21
 
-const CMPP_DELIVER: u32 = 5;
-const CMPP_DELIVER_RES: u32 = 2147483653;
+pub const CMPP_DELIVER: u32 = 5;
+pub const CMPP_DELIVER_RES: u32 = 2147483653;
 
 
 // 连接失败枚举
@@ -37,6 +41,22 @@ pub const ERRNO_CONN_AUTH_FAILED: u8 = 3;
 pub const ERRNO_CONN_VER_TOO_HIGH: u8 = 4;
 pub const ERRNO_CONN_OTHERS: u8 = 5;
 
+// 提交结果枚举
+pub const ERRNO_SUBMIT_STRUCTURE: u32 = 1;
+pub const ERRNO_SUBMIT_COMMAND: u32 = 2;
+pub const ERRNO_SUBMIT_MSG_LENGTH: u32 = 4;
+pub const ERRNO_SUBMIT_FEE_CODE: u32 = 5;
+pub const ERRNO_SUBMIT_OVERSIZE: u32 = 6;
+pub const ERRNO_SUBMIT_SERVICE_ID: u32 = 7;
+pub const ERRNO_SUBMIT_FLOW_CONTROL: u32 = 8;
+pub const ERRNO_SUBMIT_SRC_ID: u32 = 10;
+pub const ERRNO_SUBMIT_MSG_SRC: u32 = 11;
+pub const ERRNO_SUBMIT_FEE_TERMINAL_ID: u32 = 12;
+pub const ERRNO_SUBMIT_DEST_TERMINAL_ID: u32 = 13;
+// 以下为规范外的错误码
+pub const ERRNO_SUBMIT_KEYWORD: u32 = 14;
+pub const ERRNO_SUBMIT_BALANCE: u32 = 15;
+
 
 #[derive(Debug, Clone)]
 pub enum Command {
@@ -48,19 +68,31 @@ pub enum Command {
     ActiveTestRsp(CmppActiveTestRspPkt),
     DeliverReq(Cmpp3DeliverReqPkt),
     DeliverRes(Cmpp3DeliverResPkt),
+    Terminate(CmppTerminateReqPkt),
+    TerminateRsp(CmppTerminateRspPkt),
     Unknown(Unknown),
+    Nack(CmppNackPkt),
 }
 
 
 impl  Command {
-    pub fn parse_frame(command_id: u32, seq_id: u32, frame: &mut Vec<u8>) -> Result<Command> {
+    pub fn parse_frame(command_id: u32, seq_id: u32, frame: &mut [u8]) -> Result<Command> {
         let command = match command_id {
             CMPP_CONNECT => Command::Connect(CmppConnReqPkt::parse_frame(seq_id, frame)?),
-            CMPP_SUBMIT => Command::Submit(Cmpp3SubmitReqPkt::parse_frame(seq_id, frame)?),
+            CMPP_SUBMIT => match Cmpp3SubmitReqPkt::parse_frame(seq_id, frame) {
+                Ok(pkt) => Command::Submit(pkt),
+                // 消息结构错误时直接生成响应, 由会话回复给客户端
+                Err(e) => {
+                    log::warn!("invalid submit, seq_id: {}, {}", seq_id, e);
+                    Command::SubmitRsp(Cmpp3SubmitRspPkt::malformed(seq_id, frame))
+                }
+            },
             CMPP_ACTIVE_TEST => Command::ActiveTest(CmppActiveTestReqPkt::parse_frame(seq_id)?),
             CMPP_DELIVER_RES => Command::DeliverRes(Cmpp3DeliverResPkt::parse_frame(seq_id, frame)?),
+            CMPP_TERMINATE => Command::Terminate(CmppTerminateReqPkt::parse_frame(seq_id)?),
+            CMPP_TERMINATE_RESP => Command::TerminateRsp(CmppTerminateRspPkt::parse_frame(seq_id)?),
             _ => {
-                return Ok(Command::Unknown(Unknown::new(command_id)));
+                return Ok(Command::Unknown(Unknown::parse_frame(command_id, seq_id, frame)));
             }
         };
 
@@ -72,6 +104,10 @@ impl  Command {
             Command::ConnectRsp(res) => res.pack(),
             Command::SubmitRsp(res) => res.pack(),
             Command::DeliverReq(res) => res.pack(),
+            Command::ActiveTestRsp(res) => res.pack(),
+            Command::Terminate(res) => res.pack(),
+            Command::TerminateRsp(res) => res.pack(),
+            Command::Nack(res) => res.pack(),
             _ => {Ok(vec![])}
         }
     }
@@ -89,8 +125,29 @@ impl  Command {
                 cmd.apply().map(|t| { Command::ActiveTestRsp(t) })
             }
 
+            Command::Terminate(ref cmd) => {
+                cmd.apply().map(|t| { Command::TerminateRsp(t) })
+            }
+
             _ => Ok(Command::Unknown(Unknown::new(0)))
         }
     }
 
+    pub fn seq_id(&self) -> u32 {
+        match self {
+            Command::Connect(cmd) => cmd.seq_id,
+            Command::ConnectRsp(cmd) => cmd.seq_id,
+            Command::Submit(cmd) => cmd.seq_id,
+            Command::SubmitRsp(cmd) => cmd.seq_id,
+            Command::ActiveTest(cmd) => cmd.seq_id,
+            Command::ActiveTestRsp(cmd) => cmd.seq_id,
+            Command::DeliverReq(cmd) => cmd.seq_id,
+            Command::DeliverRes(cmd) => cmd.seq_id,
+            Command::Terminate(cmd) => cmd.seq_id,
+            Command::TerminateRsp(cmd) => cmd.seq_id,
+            Command::Unknown(cmd) => cmd.seq_id,
+            Command::Nack(cmd) => cmd.seq_id,
+        }
+    }
+
 }
\ No newline at end of file
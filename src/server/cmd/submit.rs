@@ -1,10 +1,55 @@
+use std::fmt;
+
 use bytes::{Buf, BufMut};
 
-use crate::server::cmd::{CMPP_HEADER_LEN, CMPP_SUBMIT_RESP};
+use crate::server::cmd::{CMPP_HEADER_LEN, CMPP_SUBMIT, CMPP_SUBMIT_RESP, ERRNO_SUBMIT_STRUCTURE};
+use crate::server::logging::redact;
 use crate::server::Result;
-use crate::util::str::{oct_string, ucs2_to_utf8};
+use crate::util::str::{oct_string, octet_string, ucs2_to_utf8, utf8_to_ucs2};
+
+// Dest_Usr_tl 及之前的定长部分
+const SUBMIT_FIXED_LEN: usize = 129;
+
+/// 按 Msg_Fmt 解码消息内容, 仅用于日志和内容检查
+pub(crate) fn decode_content(msg_fmt: u8, data: &[u8]) -> String {
+    match msg_fmt {
+        // UCS2
+        8 if data.len().is_multiple_of(2) => ucs2_to_utf8(data).unwrap_or_default(),
+        // 含汉字
+        15 => encoding_rs::GBK.decode_without_bom_handling(data).0.into_owned(),
+        _ => String::from_utf8_lossy(data).into_owned(),
+    }
+}
 
-#[derive(Debug, Clone)]
+/// 长短信的分段, 取自 UDH 中的级联信息单元
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Segment {
+    /// 参考号, 同一条长短信的各分段相同
+    pub reference: u16,
+    pub total: u8,
+    /// 序号, 从 1 开始
+    pub number: u8,
+}
+
+/// `tp_udhi` 为 1 时内容开头的 UDH 长度 (含长度字节)
+fn udh_len(tp_udhi: u8, msg_bytes: &[u8]) -> usize {
+    match msg_bytes.first() {
+        Some(len) if tp_udhi == 1 => (*len as usize + 1).min(msg_bytes.len()),
+        _ => 0,
+    }
+}
+
+/// 按 Msg_Fmt 编码消息内容, 只支持 ASCII 和 UCS2
+pub(crate) fn encode_content(msg_fmt: u8, content: &str) -> Result<Vec<u8>> {
+    match msg_fmt {
+        0 if content.is_ascii() => Ok(content.as_bytes().to_vec()),
+        0 => Err("content is not ascii".into()),
+        8 => Ok(utf8_to_ucs2(content)),
+        _ => Err(format!("unsupported msg_fmt: {}", msg_fmt).into()),
+    }
+}
+
+#[derive(Clone)]
 pub struct Cmpp3SubmitReqPkt {
     pub msg_id: u64,
     pub pk_total: u8,
@@ -28,6 +73,8 @@ pub struct Cmpp3SubmitReqPkt {
     pub dest_terminal_id: Vec<String>,
     pub dest_terminal_type: u8,
     msg_length: u8,
+    /// 原始消息内容
+    pub msg_bytes: Vec<u8>,
     pub msg_content: String,
     pub link_id: String,
 
@@ -36,6 +83,45 @@ pub struct Cmpp3SubmitReqPkt {
 }
 
 
+// 日志中不输出消息内容
+impl fmt::Debug for Cmpp3SubmitReqPkt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cmpp3SubmitReqPkt")
+            .field("msg_id", &self.msg_id)
+            .field("pk_total", &self.pk_total)
+            .field("pk_number", &self.pk_number)
+            .field("registered_delivery", &self.registered_delivery)
+            .field("msg_level", &self.msg_level)
+            .field("service_id", &self.service_id)
+            .field("fee_user_type", &self.fee_user_type)
+            .field("fee_terminal_id", &self.fee_terminal_id)
+            .field("fee_terminal_type", &self.fee_terminal_type)
+            .field("tp_pid", &self.tp_pid)
+            .field("tp_udhi", &self.tp_udhi)
+            .field("msg_fmt", &self.msg_fmt)
+            .field("msg_src", &self.msg_src)
+            .field("fee_type", &self.fee_type)
+            .field("fee_code", &self.fee_code)
+            .field("valid_time", &self.valid_time)
+            .field("at_time", &self.at_time)
+            .field("src_id", &self.src_id)
+            .field("dest_usr_tl", &self.dest_usr_tl)
+            .field("dest_terminal_id", &self.dest_terminal_id)
+            .field("dest_terminal_type", &self.dest_terminal_type)
+            .field("msg_length", &self.msg_length)
+            .field("msg_content", &redact(self.msg_bytes.len().max(self.msg_content.len())))
+            .field("link_id", &self.link_id)
+            .field("seq_id", &self.seq_id)
+            .finish()
+    }
+}
+
+impl Default for Cmpp3SubmitReqPkt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Cmpp3SubmitReqPkt {
 
     fn new() -> Cmpp3SubmitReqPkt {
@@ -62,16 +148,20 @@ impl Cmpp3SubmitReqPkt {
             dest_terminal_id: vec![],
             dest_terminal_type: 0,
             msg_length: 0,
+            msg_bytes: vec![],
             msg_content: "".to_string(),
             link_id: "".to_string(),
             seq_id: 0,
         }
     }
 
-    pub(crate) fn parse_frame(seq_id: u32, data: &mut Vec<u8>) -> Result<Cmpp3SubmitReqPkt> {
+    pub(crate) fn parse_frame(seq_id: u32, data: &mut [u8]) -> Result<Cmpp3SubmitReqPkt> {
         let mut pkt = Cmpp3SubmitReqPkt::new();
         pkt.seq_id = seq_id;
 
+        if data.len() < SUBMIT_FIXED_LEN {
+            return Err(format!("submit body too short: {}", data.len()).into());
+        }
         let mut buf = bytes::BytesMut::with_capacity(data.len());
         buf.extend_from_slice(data);
 
@@ -124,6 +214,10 @@ impl Cmpp3SubmitReqPkt {
         pkt.src_id = oct_string(src_id_vec);
 
         pkt.dest_usr_tl = buf.get_u8();
+        // Dest_terminal_Id 之后至少还有 Dest_terminal_type, Msg_Length 和 LinkID
+        if buf.remaining() < 32 * pkt.dest_usr_tl as usize + 2 + 20 {
+            return Err(format!("submit body too short for {} dest terminals", pkt.dest_usr_tl).into());
+        }
         let mut dest_terminal_ids = Vec::with_capacity(pkt.dest_usr_tl as usize);
         for _i in 0..pkt.dest_usr_tl {
             let mut dest_terminal_id_vec = vec![0u8; 32];
@@ -134,13 +228,11 @@ impl Cmpp3SubmitReqPkt {
 
         pkt.dest_terminal_type = buf.get_u8();
 
+        // 消息内容以实际长度为准, 与 Msg_Length 不一致时由校验环节处理
         pkt.msg_length = buf.get_u8();
-        let mut msg_content_vec = vec![0u8; pkt.msg_length as usize];
-        buf.copy_to_slice(&mut msg_content_vec);
-        match ucs2_to_utf8(msg_content_vec.as_slice()) {
-            Ok(content) => { pkt.msg_content = content }
-            Err(_e) => { return Err("解析msg_content失败".into())}
-        }
+        let content_len = buf.remaining() - 20;
+        pkt.msg_bytes = buf.split_to(content_len).to_vec();
+        pkt.msg_content = decode_content(pkt.msg_fmt, &pkt.msg_bytes[udh_len(pkt.tp_udhi, &pkt.msg_bytes)..]);
 
         let mut link_id_vec = vec![0u8; 20];
         buf.copy_to_slice(&mut link_id_vec);
@@ -148,6 +240,75 @@ impl Cmpp3SubmitReqPkt {
         Ok(pkt)
     }
 
+    /// 作为客户端向上游网关提交, Msg_Length 取实际内容长度
+    pub fn pack(&self) -> Result<Vec<u8>> {
+        let dest_len = 32 * self.dest_terminal_id.len();
+        let pkt_len = CMPP_HEADER_LEN as usize + SUBMIT_FIXED_LEN + dest_len + 2 + self.msg_bytes.len() + 20;
+        let mut buffer = Vec::with_capacity(pkt_len);
+        buffer.put_u32(pkt_len as u32);
+        buffer.put_u32(CMPP_SUBMIT);
+        buffer.put_u32(self.seq_id);
+
+        buffer.put_u64(self.msg_id);
+        buffer.put_u8(self.pk_total);
+        buffer.put_u8(self.pk_number);
+        buffer.put_u8(self.registered_delivery);
+        buffer.put_u8(self.msg_level);
+        buffer.put_slice(octet_string(self.service_id.clone(), 10).as_bytes());
+        buffer.put_u8(self.fee_user_type);
+        buffer.put_slice(octet_string(self.fee_terminal_id.clone(), 32).as_bytes());
+        buffer.put_u8(self.fee_terminal_type);
+        buffer.put_u8(self.tp_pid);
+        buffer.put_u8(self.tp_udhi);
+        buffer.put_u8(self.msg_fmt);
+        buffer.put_slice(octet_string(self.msg_src.clone(), 6).as_bytes());
+        buffer.put_slice(octet_string(self.fee_type.clone(), 2).as_bytes());
+        buffer.put_slice(octet_string(self.fee_code.clone(), 6).as_bytes());
+        buffer.put_slice(octet_string(self.valid_time.clone(), 17).as_bytes());
+        buffer.put_slice(octet_string(self.at_time.clone(), 17).as_bytes());
+        buffer.put_slice(octet_string(self.src_id.clone(), 21).as_bytes());
+        buffer.put_u8(self.dest_terminal_id.len() as u8);
+        for dest in &self.dest_terminal_id {
+            buffer.put_slice(octet_string(dest.clone(), 32).as_bytes());
+        }
+        buffer.put_u8(self.dest_terminal_type);
+        buffer.put_u8(self.msg_bytes.len() as u8);
+        buffer.put_slice(&self.msg_bytes);
+        buffer.put_slice(octet_string(self.link_id.clone(), 20).as_bytes());
+        Ok(buffer)
+    }
+
+    /// 客户端声明的 Msg_Length
+    pub fn msg_length(&self) -> u8 {
+        self.msg_length
+    }
+
+    /// 设置按 `msg_fmt` 编码的消息内容, `tp_udhi` 为 1 时内容以 UDH 开头
+    pub fn set_msg_bytes(&mut self, msg_bytes: Vec<u8>) {
+        self.msg_content = decode_content(self.msg_fmt, &msg_bytes[udh_len(self.tp_udhi, &msg_bytes)..]);
+        self.msg_length = msg_bytes.len() as u8;
+        self.msg_bytes = msg_bytes;
+    }
+
+    /// 长短信的分段, UDH 中没有级联信息单元 (0x00 或 0x08) 时返回 None
+    pub fn segment(&self) -> Option<Segment> {
+        let udh_len = udh_len(self.tp_udhi, &self.msg_bytes);
+        let mut ies = self.msg_bytes.get(1..udh_len)?;
+        while let [iei, len, rest @ ..] = ies {
+            let (data, next) = rest.split_at_checked(*len as usize)?;
+            match (iei, data) {
+                (0x00, &[reference, total, number]) => {
+                    return Some(Segment { reference: reference as u16, total, number });
+                }
+                (0x08, &[hi, lo, total, number]) => {
+                    return Some(Segment { reference: u16::from_be_bytes([hi, lo]), total, number });
+                }
+                _ => ies = next,
+            }
+        }
+        None
+    }
+
     pub(crate) fn apply(&self) -> Result<Cmpp3SubmitRspPkt> {
         let res = Cmpp3SubmitRspPkt{
             msg_id: self.msg_id,
@@ -170,6 +331,16 @@ pub struct Cmpp3SubmitRspPkt {
 
 impl  Cmpp3SubmitRspPkt {
 
+    /// 无法解析的 CMPP_SUBMIT 的响应, Msg_Id 尽量取自消息体
+    pub(crate) fn malformed(seq_id: u32, data: &[u8]) -> Cmpp3SubmitRspPkt {
+        let msg_id = data.get(..8).map(|b| u64::from_be_bytes(b.try_into().unwrap())).unwrap_or(0);
+        Cmpp3SubmitRspPkt {
+            msg_id,
+            result: ERRNO_SUBMIT_STRUCTURE,
+            seq_id,
+        }
+    }
+
     pub(crate) fn pack(self) -> Result<Vec<u8>> {
         let pkt_len = CMPP_HEADER_LEN + 8 + 4;
         let mut buffer = Vec::with_capacity(pkt_len as usize);
@@ -185,5 +356,16 @@ impl  Cmpp3SubmitRspPkt {
         Ok(buffer)
     }
 
+    pub(crate) fn parse_frame(seq_id: u32, data: &[u8]) -> Result<Cmpp3SubmitRspPkt> {
+        if data.len() < 12 {
+            return Err("submit resp too short".into());
+        }
+        let mut buf = data;
+        Ok(Cmpp3SubmitRspPkt {
+            msg_id: buf.get_u64(),
+            result: buf.get_u32(),
+            seq_id,
+        })
+    }
 
 }
\ No newline at end of file
@@ -0,0 +1,48 @@
+use bytes::BufMut;
+
+use crate::server::cmd::{CMPP_HEADER_LEN, CMPP_TERMINATE, CMPP_TERMINATE_RESP};
+use crate::server::Result;
+
+#[derive(Debug, Clone)]
+pub struct CmppTerminateReqPkt {
+    pub seq_id: u32,
+}
+
+impl CmppTerminateReqPkt {
+
+    pub(crate) fn parse_frame(seq_id: u32) -> Result<CmppTerminateReqPkt> {
+        Ok(CmppTerminateReqPkt { seq_id })
+    }
+
+    pub fn pack(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(CMPP_HEADER_LEN as usize);
+        buffer.put_u32(CMPP_HEADER_LEN);
+        buffer.put_u32(CMPP_TERMINATE);
+        buffer.put_u32(self.seq_id);
+        Ok(buffer)
+    }
+
+    pub(crate) fn apply(&self) -> Result<CmppTerminateRspPkt> {
+        Ok(CmppTerminateRspPkt { seq_id: self.seq_id })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CmppTerminateRspPkt {
+    pub seq_id: u32,
+}
+
+impl CmppTerminateRspPkt {
+
+    pub(crate) fn parse_frame(seq_id: u32) -> Result<CmppTerminateRspPkt> {
+        Ok(CmppTerminateRspPkt { seq_id })
+    }
+
+    pub fn pack(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(CMPP_HEADER_LEN as usize);
+        buffer.put_u32(CMPP_HEADER_LEN);
+        buffer.put_u32(CMPP_TERMINATE_RESP);
+        buffer.put_u32(self.seq_id);
+        Ok(buffer)
+    }
+}
@@ -1,8 +1,17 @@
+use bytes::BufMut;
 
+use crate::server::cmd::{CMPP_HEADER_LEN, ERRNO_SUBMIT_COMMAND};
+use crate::server::Result;
+
+// 响应命令字的最高位
+const CMPP_RESP_FLAG: u32 = 0x8000_0000;
 
 #[derive(Debug, Clone)]
 pub struct Unknown {
     pub command_id: u32,
+    pub seq_id: u32,
+    /// 原始消息体, 用于排查问题
+    pub body: Vec<u8>,
 }
 
 impl Unknown {
@@ -11,6 +20,73 @@ impl Unknown {
     pub(crate) fn new(command_id: u32) -> Unknown {
         Unknown {
             command_id,
+            seq_id: 0,
+            body: vec![],
         }
     }
-}
\ No newline at end of file
+
+    pub(crate) fn parse_frame(command_id: u32, seq_id: u32, data: &[u8]) -> Unknown {
+        Unknown {
+            command_id,
+            seq_id,
+            body: data.to_vec(),
+        }
+    }
+
+    /// 对未知请求回复通用错误响应, 未知命令本身是响应时返回 None
+    pub fn nack(&self) -> Option<CmppNackPkt> {
+        if self.command_id & CMPP_RESP_FLAG != 0 {
+            return None;
+        }
+        Some(CmppNackPkt {
+            command_id: self.command_id | CMPP_RESP_FLAG,
+            result: ERRNO_SUBMIT_COMMAND,
+            seq_id: self.seq_id,
+        })
+    }
+
+    /// 原始消息的十六进制表示, 最多输出前 64 字节
+    pub fn hex_dump(&self) -> String {
+        let dump: String = self.body.iter().take(64).map(|b| format!("{:02x}", b)).collect();
+        if self.body.len() > 64 {
+            return format!("{}...({} bytes)", dump, self.body.len());
+        }
+        dump
+    }
+}
+
+/// 通用错误响应: 命令字为请求命令字加响应标志位, 消息体为 4 字节的错误码
+#[derive(Debug, Clone)]
+pub struct CmppNackPkt {
+    pub command_id: u32,
+    pub result: u32,
+    pub seq_id: u32,
+}
+
+impl CmppNackPkt {
+    pub fn pack(&self) -> Result<Vec<u8>> {
+        let pkt_len = CMPP_HEADER_LEN + 4;
+        let mut buffer = Vec::with_capacity(pkt_len as usize);
+        buffer.put_u32(pkt_len);
+        buffer.put_u32(self.command_id);
+        buffer.put_u32(self.seq_id);
+        buffer.put_u32(self.result);
+        Ok(buffer)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::server::cmd::unknown::Unknown;
+
+    #[test]
+    fn test_nack() {
+        let unknown = Unknown::parse_frame(0x0000_0010, 9, &[1, 2, 3]);
+        let nack = unknown.nack().unwrap();
+        assert_eq!(nack.pack().unwrap(), vec![0, 0, 0, 16, 0x80, 0, 0, 0x10, 0, 0, 0, 9, 0, 0, 0, 2]);
+        assert_eq!(unknown.hex_dump(), "010203");
+
+        assert!(Unknown::parse_frame(0x8000_0010, 9, &[]).nack().is_none());
+    }
+}
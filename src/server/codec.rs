@@ -70,7 +70,7 @@ impl Decoder for CmppDecoder {
         let seq_id = head.seq_id;
         let command_id = head.command_id;
 
-        if total_length < CMPP3_PACKET_MIN || total_length > CMPP3_PACKET_MAX {
+        if !(CMPP3_PACKET_MIN..=CMPP3_PACKET_MAX).contains(&total_length) {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid length"));
         }
 
@@ -1,18 +1,723 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-pub const DEFAULT_LISTENING_ADDR: &'static str = "0.0.0.0:8888";
+use serde::{Deserialize, Deserializer};
+use tokio::sync::watch;
 
-#[derive(Clone, Debug, PartialEq)]
+use crate::server::acl::Cidr;
+use crate::server::Result;
+
+pub const DEFAULT_LISTENING_ADDR: &str = "0.0.0.0:8888";
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
 pub struct Config {
+    /// 未配置 `listeners` 时使用的监听地址
     pub addr: String,
     pub rate: usize,
+    pub listeners: Vec<ListenerConfig>,
+    pub accounts: Vec<Account>,
+    /// 停机时等待会话退出的最长秒数
+    pub shutdown_timeout: u64,
+    /// 停机时未投递或未确认的状态报告保存路径, 启动时读取后补发
+    pub report_store: Option<PathBuf>,
+    /// 等待 SP 回复 CMPP_DELIVER_RESP 的秒数, 超时后重发
+    pub deliver_timeout: u64,
+    /// CMPP_DELIVER 超时后最多重发的次数
+    pub deliver_retries: u32,
+    /// 全局 IP 白名单, 为空时不限制
+    pub allow_ips: Vec<Cidr>,
+    /// 全局 IP 黑名单, 优先于白名单
+    pub deny_ips: Vec<Cidr>,
+    /// 单个 IP 每分钟最多建立的连接数, 0 表示不限制
+    pub conn_rate_per_ip: usize,
+    /// 未完成认证的连接数上限, 0 表示不限制
+    pub max_unauthenticated: usize,
+    /// 建连后等待 CMPP_CONNECT 的最长秒数, 0 表示不限制
+    pub connect_timeout: u64,
+    /// 收到未知命令时的处理方式
+    pub unknown_command: UnknownCommandPolicy,
+    /// `unknown_command = "disconnect"` 时, 会话累计收到多少个未知命令后断开
+    pub unknown_command_limit: usize,
+    /// 本网关代码, 用于生成 Msg_Id
+    pub ismg_id: u32,
+    /// 上游网关, 修改后需要重启
+    pub upstreams: Vec<UpstreamConfig>,
+    /// 等待上游网关 CMPP_SUBMIT_RESP 的最长秒数
+    pub submit_timeout: u64,
+    /// 号段路由文件, 配置热加载时重新读取
+    pub routes: Option<PathBuf>,
+    /// 敏感词过滤
+    pub keywords: KeywordConfig,
+    /// 接收号码黑名单
+    pub blacklist: BlacklistConfig,
+    /// HTTP 接口, 需启用 `http` 特性
+    pub http: Option<HttpConfig>,
+    /// webhook 回调的重试策略, 需启用 `http` 特性
+    pub webhook: WebhookConfig,
+    /// 日志格式和级别, 启动时生效
+    pub log: LogConfig,
+    /// 话单, 配置后为每个接收号码记录一条
+    pub cdr: Option<CdrConfig>,
+    /// 计费
+    pub billing: BillingConfig,
+
+    // 配置文件路径, 热加载时重新读取
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+/// 未知命令的处理方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownCommandPolicy {
+    /// 记录日志后忽略
+    Ignore,
+    /// 回复通用错误响应
+    Nack,
+    /// 回复通用错误响应, 累计超过阈值后断开连接
+    Disconnect,
+}
+
+/// 敏感词过滤配置, 未配置任何词时不过滤
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct KeywordConfig {
+    /// 全局禁止词文件, 每行一个词, `#` 开头为注释, 配置热加载时重新读取
+    pub file: Option<PathBuf>,
+    /// 全局禁止词
+    pub words: Vec<String>,
+    /// 命中后的处理方式
+    pub action: KeywordAction,
+    /// `action = "reject"` 时回复的 result, 未配置时为 `ERRNO_SUBMIT_KEYWORD`
+    pub result: Option<u32>,
+    /// 命中记录, 每行一条 JSON, 未配置时只写日志
+    pub audit_log: Option<PathBuf>,
+}
+
+/// 命中敏感词后的处理方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeywordAction {
+    /// 以 `result` 拒绝提交
+    #[default]
+    Reject,
+    /// 回复成功, 暂存等待人工审核
+    Review,
+}
+
+/// HTTP 接口配置
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct HttpConfig {
+    /// 监听地址, 修改后需要重启
+    pub addr: String,
+    /// 运维 token, 配置后请求需带 `Authorization: Bearer <token>`; 提交短信需使用账号的 `http_token`
+    #[serde(default)]
+    pub token: Option<String>,
+    /// 管理接口 `/admin/` 使用的 token, 未配置时不开放管理接口
+    #[serde(default)]
+    pub admin_token: Option<String>,
+}
+
+/// webhook 回调的重试策略
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// 最多尝试次数, 之后写入死信文件
+    pub max_attempts: u32,
+    /// 首次重试前等待的秒数, 之后每次翻倍
+    pub backoff: u64,
+    /// 重试等待的最长秒数
+    pub max_backoff: u64,
+    /// 单次请求的超时秒数
+    pub timeout: u64,
+    /// 等待推送的最大条数, 超出时直接写入死信文件
+    pub queue_size: usize,
+    /// 死信文件, 每行一条 JSON, 未配置时只写日志
+    pub dead_letter: Option<PathBuf>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            max_attempts: 6,
+            backoff: 1,
+            max_backoff: 300,
+            timeout: 10,
+            queue_size: 10000,
+            dead_letter: None,
+        }
+    }
+}
+
+/// 计费配置
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BillingConfig {
+    /// 预付费账号的余额文件, 启动时读取, 运行中定期写入, 未配置时重启后按账号的初始余额重新计算
+    pub balance_file: Option<PathBuf>,
+    /// 余额不足时回复的 result, 未配置时为 `ERRNO_SUBMIT_BALANCE`
+    pub result: Option<u32>,
+    /// 封顶类资费 (FeeType 04/05) 每个计费号码每月在同一 SP 的信息费上限, 单位分, 未配置时不封顶
+    pub monthly_cap: Option<u64>,
+}
+
+/// 话单配置
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CdrConfig {
+    /// 话单文件目录
+    pub dir: PathBuf,
+    pub format: CdrFormat,
+    pub rotate: CdrRotate,
+    /// 等待状态报告的最长小时数, 超时后以 UNKNOWN 状态写入
+    pub report_timeout: u64,
+}
+
+impl Default for CdrConfig {
+    fn default() -> Self {
+        CdrConfig {
+            dir: PathBuf::from("cdr"),
+            format: CdrFormat::Csv,
+            rotate: CdrRotate::Hourly,
+            report_timeout: 72,
+        }
+    }
+}
+
+/// 话单文件格式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CdrFormat {
+    /// 带表头的 CSV
+    #[default]
+    Csv,
+    /// 每行一个 JSON 对象
+    Jsonl,
+}
+
+/// 话单文件的切换周期
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CdrRotate {
+    /// 每小时一个文件, 如 cdr-2024010112.csv
+    #[default]
+    Hourly,
+    /// 每天一个文件, 如 cdr-20240101.csv
+    Daily,
+}
+
+/// 日志配置
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// 输出格式
+    pub format: LogFormat,
+    /// 级别过滤, 与 `RUST_LOG` 语法相同, 如 "info,cmpp::server::upstream=debug"
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::Text,
+            level: "info".to_string(),
+        }
+    }
+}
+
+/// 日志输出格式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// 单行文本
+    #[default]
+    Text,
+    /// 每行一个 JSON 对象
+    Json,
+}
+
+/// 账号的 webhook 回调
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct WebhookTarget {
+    /// 回调地址, 支持 http 和 https
+    pub url: String,
+    /// 签名密钥, 请求头 `X-Signature` 为 `sha256=` 加上 "时间戳.请求体" 的 HMAC-SHA256 (小写 hex)
+    pub secret: String,
+}
+
+/// 接收号码黑名单配置
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BlacklistConfig {
+    /// 全局黑名单
+    pub numbers: Vec<String>,
+    /// 全局黑名单文件, 每行一个号码, 配置热加载时重新读取
+    pub file: Option<PathBuf>,
+    /// 用户退订后自动加入的 SP 黑名单, 每行 "sp_id,号码"
+    pub unsubscribe_file: Option<PathBuf>,
+    /// 退订指令, 上行内容去掉首尾空白后忽略大小写完全相同时生效
+    pub unsubscribe_words: Vec<String>,
+    /// 接收号码在黑名单中时的处理方式
+    pub action: BlacklistAction,
+    /// 拒绝时回复的 result, 未配置时为 `ERRNO_SUBMIT_DEST_TERMINAL_ID`
+    pub result: Option<u32>,
+}
+
+impl Default for BlacklistConfig {
+    fn default() -> Self {
+        BlacklistConfig {
+            numbers: vec![],
+            file: None,
+            unsubscribe_file: None,
+            unsubscribe_words: vec!["TD".to_string(), "0000".to_string()],
+            action: BlacklistAction::Reject,
+            result: None,
+        }
+    }
+}
+
+/// 接收号码在黑名单中时的处理方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlacklistAction {
+    /// 以 `result` 拒绝整条提交
+    #[default]
+    Reject,
+    /// 只去掉黑名单中的号码并为其下发 BLKLIST 状态报告, 全部被去掉时拒绝
+    Drop,
+}
+
+/// 监听端口配置, 每个端口可限定账号、协议版本和限流
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ListenerConfig {
+    pub addr: String,
+    /// 允许的协议版本, 为空时不限制; 目前只支持 0x30 (CMPP 3.0)
+    #[serde(default, deserialize_with = "deserialize_versions")]
+    pub versions: Vec<u8>,
+    /// 允许登录的账号, 为空时不限制
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    /// 端口的默认提交速率, 未配置时使用全局 `rate`
+    #[serde(default)]
+    pub rate: Option<usize>,
+    /// 端口的最大连接数, 未配置时不限制
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// 配置后端口只接受 TLS 连接
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// 端口位于 L4 负载均衡之后, 连接以 PROXY protocol v1/v2 头部开始
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+// 会话只按 CMPP 3.0 编解码, 配置其他版本时拒绝加载
+fn deserialize_versions<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<u8>, D::Error> {
+    let versions = Vec::<u8>::deserialize(deserializer)?;
+    match versions.iter().find(|v| **v != 0x30) {
+        Some(v) => Err(serde::de::Error::custom(format!("unsupported protocol version: {:#x}", v))),
+        None => Ok(versions),
+    }
+}
+
+/// 端口的 TLS 配置, 证书文件在配置热加载时重新读取
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct TlsConfig {
+    /// 服务端证书链 (PEM)
+    pub cert: PathBuf,
+    /// 服务端私钥 (PEM)
+    pub key: PathBuf,
+    /// 校验客户端证书的 CA (PEM), 配置后客户端必须提供证书
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// 客户端证书 SHA-256 指纹 (小写 hex) 到账号的映射, 配置后账号只能使用映射的证书登录
+    #[serde(default)]
+    pub client_accounts: HashMap<String, String>,
+}
+
+impl TlsConfig {
+    /// 指纹为 `fingerprint` 的客户端证书是否可以登录账号 `sp_id`
+    pub fn allow_cert(&self, fingerprint: Option<&str>, sp_id: &str) -> bool {
+        if self.client_accounts.is_empty() {
+            return true;
+        }
+        fingerprint.and_then(|fp| self.client_accounts.get(fp)).is_some_and(|a| a == sp_id)
+    }
+}
+
+impl ListenerConfig {
+    pub fn new(addr: &str) -> ListenerConfig {
+        ListenerConfig {
+            addr: addr.to_string(),
+            versions: vec![],
+            accounts: vec![],
+            rate: None,
+            max_connections: None,
+            tls: None,
+            proxy_protocol: false,
+        }
+    }
+
+    pub fn allow_version(&self, version: u8) -> bool {
+        self.versions.is_empty() || self.versions.contains(&version)
+    }
+
+    pub fn allow_account(&self, sp_id: &str) -> bool {
+        self.accounts.is_empty() || self.accounts.iter().any(|a| a == sp_id)
+    }
+}
+
+/// 上游网关 (运营商 ISMG) 配置, 本网关作为 SP 登录
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct UpstreamConfig {
+    pub name: String,
+    pub addr: String,
+    pub sp_id: String,
+    pub password: String,
+    #[serde(default = "default_upstream_version")]
+    pub version: u8,
+    /// 连接数
+    #[serde(default = "default_upstream_connections")]
+    pub connections: usize,
+    /// 每个连接未收到响应的最大提交数
+    #[serde(default = "default_upstream_window")]
+    pub window: usize,
+    /// 链路检测间隔秒数
+    #[serde(default = "default_upstream_active_test")]
+    pub active_test: u64,
+}
+
+fn default_upstream_version() -> u8 {
+    0x30
+}
+
+fn default_upstream_connections() -> usize {
+    1
+}
+
+fn default_upstream_window() -> usize {
+    16
+}
+
+fn default_upstream_active_test() -> u64 {
+    30
+}
+
+/// SP 账号
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Account {
+    pub sp_id: String,
+    pub password: String,
+    /// 每秒提交条数上限, 未配置时使用全局 `rate`
+    #[serde(default)]
+    pub rate: Option<usize>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// 账号允许登录的 IP, 为空时不限制
+    #[serde(default)]
+    pub allow_ips: Vec<Cidr>,
+    /// 账号禁止登录的 IP, 优先于 `allow_ips`
+    #[serde(default)]
+    pub deny_ips: Vec<Cidr>,
+    /// 允许使用的 Src_Id 前缀 (服务代码), 为空时不校验
+    #[serde(default)]
+    pub src_ids: Vec<String>,
+    /// 允许使用的业务代码, 为空时只要求非空
+    #[serde(default)]
+    pub service_ids: Vec<String>,
+    /// 账号额外的禁止词
+    #[serde(default)]
+    pub deny_words: Vec<String>,
+    /// 账号允许的词, 命中的禁止词完全包含在允许的词中时放行
+    #[serde(default)]
+    pub allow_words: Vec<String>,
+    /// 账号的接收号码黑名单
+    #[serde(default)]
+    pub blacklist: Vec<String>,
+    /// 测试账号的接收号码白名单, 配置后只能发往这些号码, 且不受黑名单限制
+    #[serde(default)]
+    pub whitelist: Vec<String>,
+    /// 配置后状态报告和上行以 HTTP POST 推送到回调地址, 不再通过 CMPP_DELIVER 投递
+    #[serde(default)]
+    pub webhook: Option<WebhookTarget>,
+    /// 配置后为预付费账号, 值为初始余额 (条), 每个接收号码的每条短信扣减 1;
+    /// 运行中的余额保存在 `billing.balance_file`
+    #[serde(default)]
+    pub balance: Option<i64>,
+    /// 允许使用的最高 Msg_Level, 超过时按该级别发送, 未配置时不限制
+    #[serde(default)]
+    pub max_msg_level: Option<u8>,
+    /// HTTP 接口的账号 token, 以此 token 只能提交和查询本账号的短信
+    #[serde(default)]
+    pub http_token: Option<String>,
 }
 
+fn default_enabled() -> bool {
+    true
+}
+
+impl Account {
+    pub fn new(sp_id: &str, password: &str) -> Account {
+        Account {
+            sp_id: sp_id.to_string(),
+            password: password.to_string(),
+            rate: None,
+            enabled: true,
+            allow_ips: vec![],
+            deny_ips: vec![],
+            src_ids: vec![],
+            service_ids: vec![],
+            deny_words: vec![],
+            allow_words: vec![],
+            blacklist: vec![],
+            whitelist: vec![],
+            webhook: None,
+            balance: None,
+            max_msg_level: None,
+            http_token: None,
+        }
+    }
+}
 
 impl Default for Config {
     fn default() -> Self {
         Config{
             addr: DEFAULT_LISTENING_ADDR.to_owned(),
             rate: 6000,
+            listeners: vec![],
+            accounts: vec![],
+            shutdown_timeout: 10,
+            report_store: None,
+            deliver_timeout: 60,
+            deliver_retries: 3,
+            allow_ips: vec![],
+            deny_ips: vec![],
+            conn_rate_per_ip: 0,
+            max_unauthenticated: 0,
+            connect_timeout: 30,
+            unknown_command: UnknownCommandPolicy::Ignore,
+            unknown_command_limit: 3,
+            ismg_id: 1,
+            upstreams: vec![],
+            submit_timeout: 10,
+            routes: None,
+            keywords: KeywordConfig::default(),
+            blacklist: BlacklistConfig::default(),
+            http: None,
+            webhook: WebhookConfig::default(),
+            log: LogConfig::default(),
+            cdr: None,
+            billing: BillingConfig::default(),
+            path: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// 带演示账号 900001 的配置, 仅用于测试
+    pub(crate) fn demo() -> Config {
+        Config { accounts: vec![Account::new("900001", "888888")], ..Config::default() }
+    }
+}
+
+impl Config {
+    /// 从 toml 文件加载配置
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let content = std::fs::read_to_string(path.as_ref())?;
+        let mut cfg: Config = toml::from_str(&content)?;
+        cfg.path = Some(path.as_ref().to_path_buf());
+        Ok(cfg)
+    }
+
+    pub fn account(&self, sp_id: &str) -> Option<&Account> {
+        self.accounts.iter().find(|a| a.sp_id == sp_id)
+    }
+
+    /// 按 `src_ids` 最长前缀匹配接入号所属的账号
+    pub fn owner_of(&self, dest_id: &str) -> Option<&Account> {
+        self.accounts.iter()
+            .filter_map(|a| {
+                let len = a.src_ids.iter().filter(|p| dest_id.starts_with(p.as_str())).map(|p| p.len()).max()?;
+                Some((len, a))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, a)| a)
+    }
+
+    /// 实际生效的监听端口, 未配置 `listeners` 时使用 `addr`
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if self.listeners.is_empty() {
+            return vec![ListenerConfig::new(&self.addr)];
         }
+        self.listeners.clone()
+    }
+
+    pub fn listener(&self, addr: &str) -> Option<ListenerConfig> {
+        self.listeners().into_iter().find(|l| l.addr == addr)
+    }
+
+    /// 账号在指定端口上的提交速率, 依次取账号、端口、全局配置, 0 表示不限流
+    pub fn rate_of(&self, listener: &str, sp_id: &str) -> usize {
+        self.account(sp_id).and_then(|a| a.rate)
+            .or_else(|| self.listener(listener).and_then(|l| l.rate))
+            .unwrap_or(self.rate)
+    }
+}
+
+/// 运行中配置的只读视图, 配置替换后 `changed()` 会被唤醒
+pub type ConfigRx = watch::Receiver<Arc<Config>>;
+
+/// 运行中配置的句柄, 用于原子替换账号、限流等配置而不断开已有会话
+#[derive(Clone)]
+pub struct ConfigHandle {
+    tx: Arc<watch::Sender<Arc<Config>>>,
+}
+
+impl ConfigHandle {
+    pub fn new(cfg: Config) -> ConfigHandle {
+        let (tx, _) = watch::channel(Arc::new(cfg));
+        ConfigHandle { tx: Arc::new(tx) }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.tx.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> ConfigRx {
+        self.tx.subscribe()
+    }
+
+    /// 原子替换当前配置, 所有会话在下一次轮询时生效
+    pub fn update(&self, mut cfg: Config) {
+        let current = self.current();
+        if cfg.addr != current.addr {
+            log::warn!("listening addr change requires restart, keep: {}", current.addr);
+            cfg.addr = current.addr.clone();
+        }
+        let addrs = |c: &Config| c.listeners().into_iter().map(|l| l.addr).collect::<Vec<_>>();
+        if addrs(&cfg) != addrs(&current) {
+            log::warn!("listeners change requires restart, keep: {:?}", addrs(&current));
+            cfg.listeners = current.listeners.clone();
+        }
+        if cfg.upstreams != current.upstreams || cfg.ismg_id != current.ismg_id {
+            log::warn!("upstreams change requires restart");
+            cfg.upstreams = current.upstreams.clone();
+            cfg.ismg_id = current.ismg_id;
+        }
+        if cfg.http.as_ref().map(|h| &h.addr) != current.http.as_ref().map(|h| &h.addr) {
+            log::warn!("http addr change requires restart");
+            match (cfg.http.as_mut(), current.http.as_ref()) {
+                (Some(http), Some(old)) => http.addr = old.addr.clone(),
+                _ => cfg.http = current.http.clone(),
+            }
+        }
+        if cfg.path.is_none() {
+            cfg.path = current.path.clone();
+        }
+        self.tx.send_replace(Arc::new(cfg));
+    }
+
+    /// 启用或禁用账号, 禁用后已登录的会话被终止; 重新加载配置文件后以文件为准
+    pub fn set_enabled(&self, sp_id: &str, enabled: bool) -> bool {
+        let mut cfg = (*self.current()).clone();
+        match cfg.accounts.iter_mut().find(|a| a.sp_id == sp_id) {
+            Some(account) => account.enabled = enabled,
+            None => return false,
+        }
+        log::info!("account {} {}", sp_id, if enabled { "enabled" } else { "disabled" });
+        self.update(cfg);
+        true
+    }
+
+    /// 重新读取配置文件并替换当前配置
+    pub fn reload(&self) -> Result<()> {
+        let path = match self.current().path.clone() {
+            Some(path) => path,
+            None => return Err("no config file to reload".into()),
+        };
+        let cfg = Config::from_file(&path)?;
+        self.update(cfg);
+        log::info!("config reloaded from {}", path.display());
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::server::config::{Config, ConfigHandle};
+
+    #[test]
+    fn test_update_keeps_addr() {
+        let handle = ConfigHandle::new(Config::default());
+        let mut rx = handle.subscribe();
+
+        let cfg: Config = toml::from_str(r#"
+            addr = "127.0.0.1:1"
+            rate = 10
+            [[listeners]]
+            addr = "127.0.0.1:2"
+            [[accounts]]
+            sp_id = "900002"
+            password = "123456"
+            rate = 5
+        "#).unwrap();
+        handle.update(cfg);
+
+        assert!(rx.has_changed().unwrap());
+        let cfg = rx.borrow_and_update().clone();
+        assert_eq!(cfg.addr, Config::default().addr);
+        assert!(cfg.listeners.is_empty());
+        assert_eq!(cfg.rate_of(&cfg.addr, "900002"), 5);
+        assert_eq!(cfg.rate_of(&cfg.addr, "900001"), 10);
+        assert!(cfg.account("900001").is_none());
+    }
+
+    #[test]
+    fn test_owner_of() {
+        let cfg: Config = toml::from_str(r#"
+            [[accounts]]
+            sp_id = "900001"
+            password = "1"
+            src_ids = ["1069"]
+            [[accounts]]
+            sp_id = "900002"
+            password = "2"
+            src_ids = ["10690002"]
+        "#).unwrap();
+        assert_eq!(cfg.owner_of("106900021234").unwrap().sp_id, "900002");
+        assert_eq!(cfg.owner_of("10690001").unwrap().sp_id, "900001");
+        assert!(cfg.owner_of("95555").is_none());
+    }
+
+    #[test]
+    fn test_listener_rate() {
+        let cfg: Config = toml::from_str(r#"
+            rate = 10
+            [[listeners]]
+            addr = "0.0.0.0:7890"
+            versions = [0x30]
+            accounts = ["900001"]
+            rate = 3
+            [[listeners]]
+            addr = "0.0.0.0:7891"
+        "#).unwrap();
+
+        let listener = cfg.listener("0.0.0.0:7890").unwrap();
+        assert!(listener.allow_version(0x30));
+        assert!(!listener.allow_version(0x20));
+        assert!(!listener.allow_account("900002"));
+        assert_eq!(cfg.rate_of("0.0.0.0:7890", "900001"), 3);
+        assert_eq!(cfg.rate_of("0.0.0.0:7891", "900001"), 10);
+
+        // 尚不支持 CMPP 2.0
+        assert!(toml::from_str::<Config>(r#"
+            [[listeners]]
+            addr = "0.0.0.0:7890"
+            versions = [0x30, 0x20]
+        "#).is_err());
     }
 }
@@ -1,129 +1,535 @@
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
 use bytes::BytesMut;
 use tokio::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadHalf};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::Sender;
+use tokio::time::Instant;
 use tokio_util::codec::Decoder;
 
-use crate::server::{cmd, CmppDecoder};
+use crate::server::{cmd, ip_allowed, CmppDecoder};
+use crate::server::acl::UnauthPermit;
 use crate::server::cmd::Command;
+use crate::server::cmd::deliver::Cmpp3DeliverReqPkt;
+use crate::server::cmd::submit::Cmpp3SubmitRspPkt;
+use crate::server::cmd::terminate::CmppTerminateReqPkt;
+use crate::server::cmd::unknown::Unknown;
+use crate::server::config::{ConfigRx, ListenerConfig, UnknownCommandPolicy};
 use crate::server::handler::MsgInHandler;
+use crate::server::limit::RateLimiter;
+use crate::server::logging::{self, SessionLog};
+use crate::server::metrics::{Counter, Metrics};
+use crate::server::pipeline::Pipeline;
+use crate::server::session::{SessionGuard, SessionStats, Sessions};
+use crate::server::shutdown::Shutdown;
+use crate::server::transport::Transport;
 use crate::server::Result;
-use crate::util::str::octet_string;
 
 pub trait AuthHandler: Send + Sync {
     fn auth(&self, req: &cmd::connect::CmppConnReqPkt, res: &mut cmd::connect::Cmpp3ConnRspPkt) -> bool;
 }
 
-pub struct DefaultAuthHandler {}
+/// 客户端连接信息
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    /// 接入的监听端口
+    pub listener: String,
+    pub addr: SocketAddr,
+    /// TLS 客户端证书的 SHA-256 指纹
+    pub cert: Option<String>,
+}
+
+/// 按配置中的账号校验 AuthenticatorSource, 并检查监听端口允许的账号、协议版本和客户端证书
+pub struct DefaultAuthHandler {
+    config: ConfigRx,
+    peer: PeerInfo,
+}
+
+impl DefaultAuthHandler {
+    pub fn new(config: ConfigRx, peer: PeerInfo) -> DefaultAuthHandler {
+        DefaultAuthHandler { config, peer }
+    }
+}
 
 impl AuthHandler for DefaultAuthHandler {
     fn auth(&self, req: &cmd::connect::CmppConnReqPkt, res: &mut cmd::connect::Cmpp3ConnRspPkt) -> bool {
-        let user = "900001".to_string();
-        let password = "888888".to_string();
+        let cfg = self.config.borrow().clone();
+        let listener = cfg.listener(&self.peer.listener).unwrap_or_else(|| ListenerConfig::new(&self.peer.listener));
 
-        let  octet_user = octet_string(user, 6);
-        let ts_str = format!("{:010}", req.timestamp);
+        if !listener.allow_version(req.version) {
+            let too_high = listener.versions.iter().all(|v| req.version > *v);
+            res.status = if too_high { cmd::ERRNO_CONN_VER_TOO_HIGH } else { cmd::ERRNO_CONN_OTHERS } as u32;
+            return false;
+        }
 
-        let len = octet_user.len() + 9 + password.len() + 10;
-        let mut buf = BytesMut::with_capacity(len);
-        buf.extend_from_slice(octet_user.as_bytes());
-        buf.extend_from_slice(&vec![0u8; 9]);
-        buf.extend_from_slice(password.as_bytes());
-        buf.extend_from_slice(ts_str.as_bytes());
+        let ip = self.peer.addr.ip();
+        let account = match cfg.account(&req.src_addr) {
+            Some(account) if account.enabled && listener.allow_account(&account.sp_id)
+                && ip_allowed(&account.allow_ips, &account.deny_ips, &ip) => account,
+            _ => {
+                res.status = cmd::ERRNO_CONN_INVALID_SRC_ADDR as u32;
+                return false;
+            }
+        };
 
-        let auth_src: &[u8] = buf.iter().as_slice();
-        let hasher = md5::compute(auth_src);
+        let cert = self.peer.cert.as_deref();
+        match listener.tls {
+            Some(ref tls) if !tls.allow_cert(cert, &account.sp_id) => {
+                log::warn!("account {} login with unmapped cert: {:?}", account.sp_id, cert);
+                res.status = cmd::ERRNO_CONN_AUTH_FAILED as u32;
+                return false;
+            }
+            _ => {}
+        }
 
-        if req.auth_src != hasher.as_slice() {
-            res.status = 1;
+        let auth_src = cmd::connect::authenticator(&account.sp_id, &account.password, req.timestamp);
+        if req.auth_src != auth_src {
+            res.status = cmd::ERRNO_CONN_AUTH_FAILED as u32;
             return false;
         }
 
         res.status = 0;
+        res.version = req.version;
         res.auth_ismg = "认证成功".to_string();
         true
     }
 }
 
+// 停机时等待客户端回复 CMPP_TERMINATE_RESP 的时长
+const TERMINATE_WAIT: Duration = Duration::from_secs(3);
+
+/// 会话状态: 建连后须先完成 CMPP_CONNECT, 拆除连接时只接收 CMPP_TERMINATE_RESP 和状态报告确认
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    AwaitingConnect,
+    Authenticated,
+    Terminating,
+    Closed,
+}
+
 pub struct Conn {
     buf: BytesMut,
+    decoder: CmppDecoder,
     auth_handler: Box<dyn AuthHandler>,
+    config: ConfigRx,
+    peer: PeerInfo,
+    // 认证成功后的账号
+    account: Option<String>,
+    // 与处理器共享的认证账号
+    session_account: Arc<OnceLock<String>>,
+    pipeline: Arc<Pipeline>,
+    sessions: Arc<Sessions>,
+    // 认证成功后注册到 `sessions`, 用于接收状态报告
+    session_guard: Option<SessionGuard>,
+    // 与处理器共享的会话计数
+    stats: Arc<SessionStats>,
+    metrics: Arc<Metrics>,
+    // 日志中的会话信息
+    log: Arc<SessionLog>,
+    limiter: RateLimiter,
+    seq_id: u32,
+    shutdown: Shutdown,
+    // 未认证连接的占位, 认证成功后释放
+    unauth_permit: Option<UnauthPermit>,
+    state: SessionState,
+    // 会话收到的未知命令数
+    unknown_commands: usize,
 }
 
 impl Conn {
-    pub fn new() -> Conn {
+    /// 创建会话, 可运行在任意 `Transport` 之上
+    pub fn new(config: ConfigRx, peer: PeerInfo) -> Conn {
         let buf = BytesMut::with_capacity(2048);
-        Conn { buf, auth_handler: Box::new(DefaultAuthHandler {}) }
+        let rate = config.borrow().rate;
+        let log = SessionLog::new(peer.addr);
+        Conn {
+            buf,
+            decoder: CmppDecoder::default(),
+            auth_handler: Box::new(DefaultAuthHandler::new(config.clone(), peer.clone())),
+            config,
+            peer,
+            account: None,
+            session_account: Arc::new(OnceLock::new()),
+            pipeline: Arc::new(Pipeline::default()),
+            sessions: Arc::new(Sessions::default()),
+            session_guard: None,
+            stats: Arc::default(),
+            metrics: Arc::default(),
+            log,
+            limiter: RateLimiter::new(rate),
+            seq_id: 0,
+            shutdown: Shutdown::never(),
+            unauth_permit: None,
+            state: SessionState::AwaitingConnect,
+            unknown_commands: 0,
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
     }
 
-    pub async fn run(&mut self, stream: TcpStream) -> Result<()> {
+    pub fn unknown_commands(&self) -> usize {
+        self.unknown_commands
+    }
+
+    pub(crate) fn with_permit(mut self, permit: UnauthPermit) -> Conn {
+        self.unauth_permit = Some(permit);
+        self
+    }
+
+    /// CMPP_SUBMIT 经由该流水线处理
+    pub fn with_pipeline(mut self, pipeline: Arc<Pipeline>) -> Conn {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// 认证成功后注册到会话表, 接收发给该账号的状态报告
+    pub fn with_sessions(mut self, sessions: Arc<Sessions>) -> Conn {
+        self.sessions = sessions;
+        self
+    }
+
+    /// 会话的运行指标计入 `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Conn {
+        self.metrics = metrics;
+        self
+    }
+
+    /// 收到停机通知 (发送端发送或被 drop) 时, 会话发送 CMPP_TERMINATE 并收尾退出
+    pub fn with_shutdown(mut self, notify: broadcast::Receiver<()>) -> Conn {
+        self.shutdown = Shutdown::new(notify);
+        self
+    }
+
+    pub async fn run<S: Transport>(&mut self, stream: S) -> Result<()> {
+        let log = self.log.clone();
+        log.scope(self.run_session(stream)).await
+    }
+
+    async fn run_session<S: Transport>(&mut self, stream: S) -> Result<()> {
         let (mut reader, mut writer) = io::split(stream);
 
         let (tx_in, rx_in) = tokio::sync::mpsc::channel(1024);
         let (tx_out, mut rx_out) = tokio::sync::mpsc::channel(1024);
 
         // 根据客户端IP 创建限流
-        let mut handler = MsgInHandler::new(rx_in, tx_out.clone());
-        tokio::spawn(async move {
-            handler.run().await;
+        let mut handler = MsgInHandler::new(rx_in, tx_out.clone(), self.config.clone(), self.peer.addr,
+                                            self.session_account.clone(), self.stats.clone())
+            .with_pipeline(self.pipeline.clone())
+            .with_sessions(self.sessions.clone())
+            .with_metrics(self.metrics.clone());
+        let log = self.log.clone();
+        let handler_task = tokio::spawn(async move {
+            log.scope(handler.run()).await
         });
 
         // 独立处理发送数据
-        tokio::spawn(async move {
+        let writer_task = tokio::spawn(async move {
             while let Some(req) = rx_out.recv().await {
                 let _ = writer.write_all(&req.into_frame().unwrap()).await;
                 let _ = writer.flush().await;
             }
+            let _ = writer.shutdown().await;
         });
 
-        let mut empty_frame_count = 0;
+        let res = self.serve(&mut reader, &tx_in, &tx_out).await;
+        // 不再接收新的状态报告
+        self.session_guard = None;
 
-        let sender = tx_in.clone();
-        loop {
-            if empty_frame_count > 3000 {
-                return Err("连接不活跃关闭".into());
-            }
-
-            if let Some(req) = self.read_frame(&mut reader).await? {
-                empty_frame_count = 0;
-                match req {
-                    Command::Connect(ref req_c) => {
-                        log::info!("connect req: {:?}", req_c);
-
-                        let mut res = req.apply()?;
-                        if let Command::ConnectRsp(ref mut res_c) = res {
-                            let auth_result = self.auth_handler.auth(&req_c, res_c);
-                            tx_out.clone().send(res).await?;
-                            if !auth_result {
-                                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-                                return Err("认证失败".into());
-                            }
-                        }
+        // 会话结束: 等待处理器清空请求队列, 未确认的状态报告交还会话表, 最后冲刷发送队列
+        drop(tx_in);
+        if let Ok(pending) = handler_task.await {
+            self.requeue(pending);
+        }
+        drop(tx_out);
+        let _ = writer_task.await;
+
+        res
+    }
+
+    async fn serve<S: AsyncRead>(&mut self, reader: &mut ReadHalf<S>, sender: &Sender<Command>, tx_out: &Sender<Command>) -> Result<()> {
+        let mut config = self.config.clone();
+        // 停机后等待客户端确认 CMPP_TERMINATE 的截止时间
+        let mut terminate_deadline: Option<Instant> = None;
+        let mut config_closed = false;
+        // 建连后须在期限内完成 CMPP_CONNECT
+        let connect_timeout = self.config.borrow().connect_timeout;
+        let connect_deadline = Instant::now() + Duration::from_secs(connect_timeout);
+
+        while self.state != SessionState::Closed {
+            let kick = self.session_guard.as_ref().map(SessionGuard::kicked);
+            let frame = tokio::select! {
+                frame = Self::read_frame(&mut self.buf, &mut self.decoder, reader, &self.metrics) => frame?,
+                changed = config.changed(), if !config_closed => {
+                    match changed {
+                        Ok(()) => self.apply_config(&config, tx_out).await?,
+                        Err(_) => config_closed = true,
+                    }
+                    continue;
+                }
+                _ = self.shutdown.recv(), if terminate_deadline.is_none() => {
+                    if self.state == SessionState::AwaitingConnect {
+                        self.state = SessionState::Closed;
+                        continue;
                     }
+                    self.terminate(tx_out).await?;
+                    terminate_deadline = Some(Instant::now() + TERMINATE_WAIT);
+                    continue;
+                }
+                _ = async { kick.as_ref().unwrap().notified().await }, if kick.is_some() && terminate_deadline.is_none() => {
+                    log::info!("session kicked, peer: {}", self.peer.addr);
+                    self.terminate(tx_out).await?;
+                    terminate_deadline = Some(Instant::now() + TERMINATE_WAIT);
+                    continue;
+                }
+                _ = tokio::time::sleep_until(terminate_deadline.unwrap_or_else(Instant::now)), if terminate_deadline.is_some() => {
+                    self.state = SessionState::Closed;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(connect_deadline), if connect_timeout > 0 && self.state == SessionState::AwaitingConnect => {
+                    self.metrics.inc(Counter::AuthFailures, &[("reason", "timeout")]);
+                    return Err(format!("no CMPP_CONNECT within {}s", connect_timeout).into());
+                }
+            };
+
+            match frame {
+                Some(req) => {
+                    logging::set_seq_id(req.seq_id());
+                    self.handle(req, sender, tx_out).await?
+                }
+                // 对端关闭连接
+                None => self.state = SessionState::Closed,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 按会话状态处理请求, 状态不符的请求回复错误响应后断开连接
+    async fn handle(&mut self, req: Command, sender: &Sender<Command>, tx_out: &Sender<Command>) -> Result<()> {
+        match (self.state, req) {
+            // 对端主动拆除连接
+            (_, Command::Terminate(ref req_t)) => {
+                tx_out.send(Command::TerminateRsp(req_t.apply()?)).await?;
+                self.state = SessionState::Closed;
+            }
+
+            (SessionState::AwaitingConnect, ref req @ Command::Connect(ref req_c)) => {
+                log::info!("connect req: {:?}", req_c);
 
-                    _ => {
-                        sender.send(req).await?;
+                let mut res = req.apply()?;
+                if let Command::ConnectRsp(ref mut res_c) = res {
+                    let auth_result = self.auth_handler.auth(req_c, res_c);
+                    if auth_result {
+                        self.metrics.inc(Counter::ConnectionsAuthenticated, &[("account", &req_c.src_addr)]);
+                    } else {
+                        self.metrics.inc(Counter::AuthFailures, &[("reason", auth_failure(res_c.status))]);
                     }
+                    tx_out.send(res).await?;
+                    if !auth_result {
+                        self.state = SessionState::Closed;
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                        return Err("认证失败".into());
+                    }
+                    self.limiter.set_rate(self.config.borrow().rate_of(&self.peer.listener, &req_c.src_addr));
+                    self.account = Some(req_c.src_addr.clone());
+                    let _ = self.session_account.set(req_c.src_addr.clone());
+                    self.log.set_account(&req_c.src_addr);
+                    let guard = self.sessions.register(&req_c.src_addr, self.peer.addr, sender.clone());
+                    guard.describe(&self.peer.listener, req_c.version, self.stats.clone());
+                    self.session_guard = Some(guard);
+                    self.unauth_permit = None;
+                    self.state = SessionState::Authenticated;
                 }
-            }else {
-                empty_frame_count += 1;
-                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
             }
+
+            (SessionState::Authenticated, Command::ActiveTest(ref req_a)) => {
+                tx_out.send(Command::ActiveTestRsp(req_a.apply()?)).await?;
+            }
+
+            (SessionState::Authenticated, Command::Submit(ref submit)) if !self.limiter.try_acquire() => {
+                self.stats.submits.fetch_add(1, Ordering::Relaxed);
+                self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                self.count_submit(cmd::ERRNO_SUBMIT_FLOW_CONTROL);
+                let res = Cmpp3SubmitRspPkt {
+                    msg_id: submit.msg_id,
+                    result: cmd::ERRNO_SUBMIT_FLOW_CONTROL,
+                    seq_id: submit.seq_id,
+                };
+                tx_out.send(Command::SubmitRsp(res)).await?;
+            }
+
+            // 无法解析的 CMPP_SUBMIT, 解析时已生成错误响应
+            (SessionState::Authenticated, Command::SubmitRsp(res)) => {
+                self.metrics.inc(Counter::DecodeErrors, &[]);
+                self.count_submit(res.result);
+                tx_out.send(Command::SubmitRsp(res)).await?;
+            }
+
+            (SessionState::Authenticated, Command::Unknown(ref unknown)) => {
+                return self.handle_unknown(unknown, tx_out).await;
+            }
+
+            (SessionState::Authenticated, req @ (Command::Connect(_) | Command::TerminateRsp(_))) |
+            (SessionState::AwaitingConnect, req) => {
+                return self.reject(req, tx_out).await;
+            }
+
+            (SessionState::Authenticated, req) => {
+                if sender.capacity() == 0 {
+                    let account = self.account.as_deref().unwrap_or_default();
+                    self.metrics.inc(Counter::WindowSaturation, &[("account", account)]);
+                }
+                sender.send(req).await?;
+            }
+
+            (SessionState::Terminating, Command::TerminateRsp(_)) => {
+                self.state = SessionState::Closed;
+            }
+
+            // 拆除连接中只接收状态报告的确认
+            (SessionState::Terminating, req @ Command::DeliverRes(_)) => {
+                sender.send(req).await?;
+            }
+
+            (SessionState::Terminating, req) => {
+                log::warn!("session terminating, drop req: {:?}", req);
+            }
+
+            (SessionState::Closed, _) => {}
         }
+        Ok(())
+    }
 
+    fn count_submit(&self, result: u32) {
+        let account = self.account.as_deref().unwrap_or_default();
+        self.metrics.inc(Counter::Submits, &[("account", account), ("result", &result.to_string())]);
     }
 
+    /// 按配置忽略、回复通用错误或在超过阈值后断开连接
+    async fn handle_unknown(&mut self, unknown: &Unknown, tx_out: &Sender<Command>) -> Result<()> {
+        self.unknown_commands += 1;
+        log::warn!("unknown command: {:#010x}, seq_id: {}, count: {}, body: {}",
+            unknown.command_id, unknown.seq_id, self.unknown_commands, unknown.hex_dump());
+
+        let (policy, limit) = {
+            let cfg = self.config.borrow();
+            (cfg.unknown_command, cfg.unknown_command_limit)
+        };
+        if policy == UnknownCommandPolicy::Ignore {
+            return Ok(());
+        }
+
+        if let Some(nack) = unknown.nack() {
+            tx_out.send(Command::Nack(nack)).await?;
+        }
 
-    async fn read_frame(&mut self, reader: &mut ReadHalf<TcpStream>) -> Result<Option<Command>> {
-        let mut decoder = CmppDecoder::default();
+        if policy == UnknownCommandPolicy::Disconnect && self.unknown_commands >= limit {
+            self.terminate(tx_out).await?;
+            self.state = SessionState::Closed;
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            return Err(format!("too many unknown commands: {}", self.unknown_commands).into());
+        }
+        Ok(())
+    }
+
+    /// 回复状态不符的请求并断开连接
+    async fn reject(&mut self, req: Command, tx_out: &Sender<Command>) -> Result<()> {
+        log::warn!("unexpected req in state {:?}: {:?}", self.state, req);
+
+        let res = match req {
+            Command::Connect(ref req_c) => {
+                let mut res = req_c.apply()?;
+                res.status = cmd::ERRNO_CONN_OTHERS as u32;
+                Some(Command::ConnectRsp(res))
+            }
+            Command::Submit(ref submit) => {
+                let mut res = submit.apply()?;
+                res.result = cmd::ERRNO_SUBMIT_COMMAND;
+                Some(Command::SubmitRsp(res))
+            }
+            _ => None,
+        };
+        if let Some(res) = res {
+            tx_out.send(res).await?;
+        }
+
+        let err = format!("unexpected command in state {:?}", self.state);
+        self.state = SessionState::Closed;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        Err(err.into())
+    }
+
+    /// 发送 CMPP_TERMINATE, 进入拆除连接状态
+    async fn terminate(&mut self, tx_out: &Sender<Command>) -> Result<()> {
+        self.seq_id = self.seq_id.wrapping_add(1);
+        let terminate = CmppTerminateReqPkt { seq_id: self.seq_id };
+        tx_out.send(Command::Terminate(terminate)).await?;
+        self.state = SessionState::Terminating;
+        Ok(())
+    }
+
+    /// 未确认的状态报告交还会话表, 由同一 SP 的其他会话或重新登录后补发, 停机时统一保存
+    fn requeue(&self, reports: Vec<Cmpp3DeliverReqPkt>) {
+        if reports.is_empty() {
+            return;
+        }
+        if let Some(ref sp_id) = self.account {
+            log::info!("requeue {} unacknowledged reports", reports.len());
+            self.sessions.requeue(sp_id, reports);
+        }
+    }
+
+    /// 配置热加载后刷新限流, 账号被禁用或删除时终止会话
+    async fn apply_config(&mut self, config: &ConfigRx, tx_out: &Sender<Command>) -> Result<()> {
+        let cfg = config.borrow().clone();
+        let sp_id = match self.account {
+            Some(ref sp_id) => sp_id.clone(),
+            None => return Ok(()),
+        };
+
+        let allowed = cfg.listener(&self.peer.listener).is_none_or(|l| l.allow_account(&sp_id));
+        match cfg.account(&sp_id) {
+            Some(account) if account.enabled && allowed => {
+                let rate = cfg.rate_of(&self.peer.listener, &sp_id);
+                if rate != self.limiter.rate() {
+                    log::info!("account {} rate changed to {}", sp_id, rate);
+                    self.limiter.set_rate(rate);
+                }
+                Ok(())
+            }
+            _ => {
+                self.terminate(tx_out).await?;
+                self.state = SessionState::Closed;
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                Err(format!("account {} disabled", sp_id).into())
+            }
+        }
+    }
+
+    async fn read_frame<S: AsyncRead>(buf: &mut BytesMut, decoder: &mut CmppDecoder, reader: &mut ReadHalf<S>,
+                                      metrics: &Metrics) -> Result<Option<Command>> {
         loop {
-            if let Some(mut frame) = decoder.decode(&mut self.buf)? {
-                let req = Command::parse_frame(frame.command_id, frame.seq_id, &mut frame.body_data)?;
-                return Ok(Some(req));
+            let decoded = decoder.decode(buf).map_err(Into::into).and_then(|frame| match frame {
+                Some(mut frame) => Command::parse_frame(frame.command_id, frame.seq_id, &mut frame.body_data).map(Some),
+                None => Ok(None),
+            });
+            match decoded {
+                Ok(Some(req)) => return Ok(Some(req)),
+                Ok(None) => {}
+                Err(e) => {
+                    metrics.inc(Counter::DecodeErrors, &[]);
+                    return Err(e);
+                }
             }
 
-            if 0 == reader.read_buf(&mut self.buf).await? {
-                return if self.buf.is_empty() {
+            if 0 == reader.read_buf(buf).await? {
+                return if buf.is_empty() {
                     Ok(None)
                 } else {
                     let s = "connection reset by peer".into();
@@ -137,3 +543,13 @@ impl Conn {
     }
 }
 
+/// CMPP_CONNECT_RESP 的 Status 对应的认证失败原因
+fn auth_failure(status: u32) -> &'static str {
+    match status as u8 {
+        cmd::ERRNO_CONN_INVALID => "invalid",
+        cmd::ERRNO_CONN_INVALID_SRC_ADDR => "account",
+        cmd::ERRNO_CONN_AUTH_FAILED => "auth",
+        cmd::ERRNO_CONN_VER_TOO_HIGH => "version",
+        _ => "other",
+    }
+}
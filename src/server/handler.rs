@@ -1,43 +1,225 @@
-use log::info;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use log::{error, info, warn};
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::{self, Instant};
 
+use crate::server::blacklist::{ATTR_BLOCKED, STAT_BLOCKED};
 use crate::server::cmd::Command;
 use crate::server::cmd::deliver::Cmpp3DeliverReqPkt;
+use crate::server::config::ConfigRx;
+use crate::server::logging;
+use crate::server::metrics::{Counter, Metrics};
+use crate::server::pipeline::{Pipeline, SubmitContext};
+use crate::server::session::{SessionStats, Sessions};
+
+// 同时处理的 CMPP_SUBMIT 数, 达到后暂停读取请求队列
+const SUBMIT_WINDOW: usize = 16;
+// 检查 CMPP_DELIVER_RESP 超时的间隔
+const RETRANSMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 已下发未确认的 CMPP_DELIVER
+struct PendingDeliver {
+    deliver: Cmpp3DeliverReqPkt,
+    // 下次重发的时间, 达到重发次数后为空
+    resend_at: Option<Instant>,
+    retries: u32,
+}
 
 pub struct MsgInHandler {
     request_rx: Receiver<Command>, // 请求命令队列
     response_tx: Sender<Command>,  // 响应命令队列
+    pending_reports: HashMap<u64, PendingDeliver>, // 已下发未确认的状态报告
+    submits: JoinSet<(SubmitContext, u32)>, // 流水线处理中的 CMPP_SUBMIT
+    config: ConfigRx,
+    pipeline: Arc<Pipeline>,
+    sessions: Arc<Sessions>,
+    peer: SocketAddr,
+    account: Arc<OnceLock<String>>, // 认证成功后由会话设置
+    seq_id: u32, // 下发 CMPP_DELIVER 的序列号
+    stats: Arc<SessionStats>, // 与会话共享的计数
+    metrics: Arc<Metrics>,
 }
 
 impl MsgInHandler {
-    pub fn new(rx: Receiver<Command>, tx: Sender<Command>) -> Self {
+    pub fn new(rx: Receiver<Command>, tx: Sender<Command>, config: ConfigRx, peer: SocketAddr,
+               account: Arc<OnceLock<String>>, stats: Arc<SessionStats>) -> Self {
         Self {
             request_rx: rx,
             response_tx: tx,
+            pending_reports: HashMap::new(),
+            submits: JoinSet::new(),
+            config,
+            pipeline: Arc::new(Pipeline::new()),
+            sessions: Arc::default(),
+            peer,
+            account,
+            seq_id: 0,
+            stats,
+            metrics: Arc::default(),
         }
     }
 
-    #[allow(unused_variables)]
-    pub async fn run(&mut self) {
+    /// CMPP_SUBMIT 经由该流水线处理
+    pub fn with_pipeline(mut self, pipeline: Arc<Pipeline>) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// 经 `sessions` 下发本网关的状态报告, 请求队列处理完后从中补发暂存的 CMPP_DELIVER
+    pub fn with_sessions(mut self, sessions: Arc<Sessions>) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// 处理器的运行指标计入 `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// 处理请求直到请求队列关闭, 返回客户端尚未确认的状态报告
+    ///
+    /// CMPP_SUBMIT 在各自的任务中经过流水线, 最多同时处理 `SUBMIT_WINDOW` 个, 处理完即回复。
+    pub async fn run(&mut self) -> Vec<Cmpp3DeliverReqPkt> {
+        let mut ticker = time::interval(RETRANSMIT_INTERVAL);
+        loop {
+            tokio::select! {
+                req = self.request_rx.recv(), if self.submits.len() < SUBMIT_WINDOW => match req {
+                    Some(req) => {
+                        self.handle(req).await;
+                        if self.request_rx.is_empty() {
+                            if let Some(sp_id) = self.account.get() {
+                                self.sessions.flush(sp_id);
+                            }
+                        }
+                    }
+                    None => break,
+                },
+                Some(done) = self.submits.join_next() => self.submitted(done),
+                _ = ticker.tick() => self.retransmit().await,
+            }
+        }
+        // 等待处理中的 CMPP_SUBMIT 回复
+        while let Some(done) = self.submits.join_next().await {
+            self.submitted(done);
+        }
+
+        self.pending_reports.drain().map(|(_, pending)| pending.deliver).collect()
+    }
+
+    /// 处理一个请求消息
+    async fn handle(&mut self, req: Command) {
         let res_tx = self.response_tx.clone();
-        // 处理请求消息
-        while let Some(req) = self.request_rx.recv().await {
-            info!("msg req: {:?}", req);
+        logging::set_seq_id(req.seq_id());
+        info!("msg req: {:?}", req);
+
+        match req {
+            Command::Submit(submit) => {
+                let received = Instant::now();
+                let sp_id = self.account.get().map(String::as_str).unwrap_or_default();
+                let mut ctx = SubmitContext::new(sp_id, self.peer, submit);
+                let (pipeline, stats, metrics) = (self.pipeline.clone(), self.stats.clone(), self.metrics.clone());
+                self.submits.spawn(logging::inherit(async move {
+                    logging::set_seq_id(ctx.submit.seq_id);
+                    let result = pipeline.process(&mut ctx).await;
+                    stats.submits.fetch_add(1, Ordering::Relaxed);
+                    if result != 0 {
+                        stats.rejected.fetch_add(1, Ordering::Relaxed);
+                    }
 
-            match req {
-                Command::Submit(ref submit) => {
                     // 投递响应
-                    _ = res_tx.send(req.apply().unwrap()).await;
-                    // 投递状态报告 待定
-                    let mut report = Cmpp3DeliverReqPkt::new();
-                    report.msg_id = submit.msg_id;
-                    report.seq_id = submit.seq_id;
-                    report.dest_id = submit.dest_terminal_id[0].clone();
-                    _ = res_tx.send(Command::DeliverReq(report)).await;
+                    let mut res = ctx.submit.apply().unwrap();
+                    res.msg_id = ctx.msg_id;
+                    res.result = result;
+                    _ = res_tx.send(Command::SubmitRsp(res)).await;
+                    metrics.observe_latency(received.elapsed());
+                    metrics.inc(Counter::Submits, &[("account", &ctx.sp_id), ("result", &result.to_string())]);
+                    (ctx, result)
+                }));
+            }
+            // 待投递的 CMPP_DELIVER
+            Command::DeliverReq(mut deliver) => {
+                self.seq_id = self.seq_id.wrapping_add(1);
+                deliver.seq_id = self.seq_id;
+                self.track(&deliver);
+                _ = res_tx.send(Command::DeliverReq(deliver)).await;
+            }
+            Command::DeliverRes(ref res) => {
+                if self.pending_reports.remove(&res.msg_id).is_some() {
+                    let sp_id = self.account.get().map(String::as_str).unwrap_or_default();
+                    self.metrics.inc(Counter::DeliversAcked, &[("account", sp_id)]);
                 }
-                _ => {}
+                self.stats.in_flight.store(self.pending_reports.len(), Ordering::Relaxed);
             }
+            _ => {}
         }
     }
 
-}
\ No newline at end of file
+    /// CMPP_SUBMIT 回复后经会话表下发本网关的状态报告, 与上游的状态报告一样经过投递拦截
+    fn submitted(&self, done: Result<(SubmitContext, u32), JoinError>) {
+        let (ctx, result) = match done {
+            Ok(done) => done,
+            Err(e) => {
+                error!("submit task failed: {}", e);
+                return;
+            }
+        };
+        if result != 0 {
+            return;
+        }
+        // 被黑名单去掉的接收号码不再发送, 下发失败报告
+        if let Some(blocked) = ctx.attrs.get(ATTR_BLOCKED) {
+            let mut blocked_ctx = ctx.clone();
+            blocked_ctx.submit.dest_terminal_id = blocked.split(',').map(String::from).collect();
+            self.sessions.report(&blocked_ctx, STAT_BLOCKED);
+        }
+        self.sessions.delivered(&ctx);
+    }
+
+    /// 记录下发的 CMPP_DELIVER, 等待 SP 确认
+    fn track(&mut self, deliver: &Cmpp3DeliverReqPkt) {
+        let sp_id = self.account.get().map(String::as_str).unwrap_or_default();
+        let timeout = Duration::from_secs(self.config.borrow().deliver_timeout);
+        let pending = PendingDeliver { deliver: deliver.clone(), resend_at: Some(Instant::now() + timeout), retries: 0 };
+        self.pending_reports.insert(deliver.msg_id, pending);
+        self.metrics.inc(Counter::DeliversSent, &[("account", sp_id)]);
+        self.stats.delivers.fetch_add(1, Ordering::Relaxed);
+        self.stats.in_flight.store(self.pending_reports.len(), Ordering::Relaxed);
+    }
+
+    /// 重发超时未确认的 CMPP_DELIVER, 达到重发次数后不再重发, 会话结束时一并交还
+    async fn retransmit(&mut self) {
+        let (timeout, max_retries) = {
+            let cfg = self.config.borrow();
+            (Duration::from_secs(cfg.deliver_timeout), cfg.deliver_retries)
+        };
+        let sp_id = self.account.get().map(String::as_str).unwrap_or_default();
+        let now = Instant::now();
+        let mut resend = Vec::new();
+        for pending in self.pending_reports.values_mut() {
+            if pending.resend_at.is_none_or(|at| at > now) {
+                continue;
+            }
+            if pending.retries >= max_retries {
+                warn!("deliver not acknowledged after {} retries, msg_id: {}", pending.retries, pending.deliver.msg_id);
+                pending.resend_at = None;
+                continue;
+            }
+            pending.retries += 1;
+            pending.resend_at = Some(now + timeout);
+            resend.push(pending.deliver.clone());
+        }
+
+        for deliver in resend {
+            self.metrics.inc(Counter::Retransmissions, &[("account", sp_id)]);
+            _ = self.response_tx.send(Command::DeliverReq(deliver)).await;
+        }
+    }
+
+}
@@ -0,0 +1,661 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use chrono::Local;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::io;
+use tokio::net::TcpListener;
+
+use crate::server::billing::Billing;
+use crate::server::config::{Config, ConfigHandle};
+use crate::server::metrics::{render_gauge, Metrics};
+use crate::server::message::{MessageStatus, RecipientStatus, SendRequest, StatusStore};
+use crate::server::mo::{MoError, MoMessage, MoService};
+use crate::server::msgid::MsgIdGen;
+use crate::server::pipeline::{Pipeline, SubmitContext, ATTR_HELD, ATTR_MSG_ID};
+use crate::server::review::Reviews;
+use crate::server::schedule::Scheduler;
+use crate::server::session::Sessions;
+use crate::server::upstream::Dispatcher;
+use crate::server::webhook::Webhooks;
+use crate::server::Result;
+
+// 请求体的最大字节数
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// HTTP 接口使用的服务
+#[derive(Clone)]
+pub(crate) struct ApiState {
+    pub config: ConfigHandle,
+    pub mo: Arc<MoService>,
+    pub billing: Arc<Billing>,
+    pub pipeline: Arc<Pipeline>,
+    pub statuses: Arc<StatusStore>,
+    pub ids: Arc<MsgIdGen>,
+    pub sessions: Arc<Sessions>,
+    pub reviews: Arc<Reviews>,
+    pub scheduler: Arc<Scheduler>,
+    pub webhooks: Arc<Webhooks>,
+    pub dispatcher: Option<Arc<Dispatcher>>,
+    pub metrics: Arc<Metrics>,
+}
+
+/// HTTP 接口
+///
+/// - `POST /mo`: 注入上行短信, 请求体为 JSON 格式的 `MoMessage`
+/// - `POST /messages`: 提交短信, 请求体为 JSON 格式的 `SendRequest`, 返回各条的 Msg_Id
+/// - `GET /messages/{msg_id}`: 查询接口提交的短信的状态
+/// - `GET /metrics`: Prometheus 文本格式的运行指标
+///
+/// 管理接口:
+///
+/// - `GET /admin/sessions`: 在线会话
+/// - `DELETE /admin/sessions/{id}`: 发送 CMPP_TERMINATE 断开会话
+/// - `GET /admin/accounts`: 账号及其在线会话数、暂存的 CMPP_DELIVER 数
+/// - `POST /admin/accounts/{sp_id}/{enable,disable,pause,resume}`: 启用、禁用账号, 暂停、恢复投递
+/// - `POST /admin/accounts/{sp_id}/balance`: 为预付费账号充值, 请求体为 `{"amount": 1000}`
+/// - `GET /admin/queues`: 各队列的长度
+/// - `POST /admin/reload`: 重新读取配置文件, 与 SIGHUP 相同
+///
+/// 配置了 `http.token` 或账号的 `http_token` 后需要认证: 提交短信只接受账号的 token, 其他接口使用
+/// `http.token`; 管理接口只接受 `http.admin_token`, 未配置时不开放。
+#[derive(Clone)]
+pub struct HttpApi {
+    listener: Arc<TcpListener>,
+}
+
+impl HttpApi {
+    pub async fn bind(addr: &str) -> io::Result<HttpApi> {
+        let listener = TcpListener::bind(addr).await.map_err(|e| {
+            io::Error::new(e.kind(), format!("bind http {} failed: {}", addr, e))
+        })?;
+        Ok(HttpApi { listener: Arc::new(listener) })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub(crate) async fn run(&self, state: ApiState) -> Result<()> {
+        info!("start http api, addr: {}", self.listener.local_addr()?);
+        loop {
+            let (socket, peer) = self.listener.accept().await?;
+            let state = state.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req| {
+                    let state = state.clone();
+                    async move { Ok::<_, Infallible>(handle(&state, peer, req).await) }
+                });
+                if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(socket), service).await {
+                    warn!("http connection error, peer: {}, {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+/// 请求方
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Caller {
+    /// 未配置任何 token, 不需要认证
+    Open,
+    /// 使用 `http.token` 或 `http.admin_token`
+    Operator,
+    /// 使用账号的 `http_token`
+    Account(String),
+}
+
+/// 按 `Authorization` 请求头确定非管理接口的请求方, 认证失败时返回 None
+fn authenticate(cfg: &Config, auth: Option<&str>) -> Option<Caller> {
+    let token = cfg.http.as_ref().and_then(|h| h.token.as_deref());
+    let accounts: Vec<(&str, &str)> = cfg.accounts.iter()
+        .filter_map(|a| Some((a.sp_id.as_str(), a.http_token.as_deref()?)))
+        .collect();
+    if token.is_none() && accounts.is_empty() {
+        return Some(Caller::Open);
+    }
+    let auth = auth?.strip_prefix("Bearer ")?;
+    if token.is_some_and(|token| token_eq(token, auth)) {
+        return Some(Caller::Operator);
+    }
+    accounts.iter().find(|(_, token)| token_eq(token, auth)).map(|(sp_id, _)| Caller::Account(sp_id.to_string()))
+}
+
+/// 比较摘要, 耗时与 token 内容无关
+fn token_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (Sha256::digest(a.as_bytes()), Sha256::digest(b.as_bytes()));
+    a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn handle(state: &ApiState, peer: SocketAddr, req: Request<Incoming>) -> Response<Full<Bytes>> {
+    let admin = req.uri().path().starts_with("/admin/");
+    let auth = req.headers().get(AUTHORIZATION).and_then(|v| v.to_str().ok());
+    let caller = {
+        let cfg = state.config.current();
+        if admin {
+            match cfg.http.as_ref().and_then(|h| h.admin_token.as_deref()) {
+                Some(token) if auth.and_then(|a| a.strip_prefix("Bearer ")).is_some_and(|a| token_eq(token, a)) => {
+                    Some(Caller::Operator)
+                }
+                Some(_) => None,
+                None => return error(StatusCode::FORBIDDEN, "admin api disabled"),
+            }
+        } else {
+            authenticate(&cfg, auth)
+        }
+    };
+    let caller = match caller {
+        Some(caller) => caller,
+        None => {
+            warn!("http unauthorized, peer: {}, {} {}", peer, req.method(), req.uri().path());
+            return error(StatusCode::UNAUTHORIZED, "unauthorized");
+        }
+    };
+    // 账号 token 只能提交和查询本账号的短信
+    if matches!(caller, Caller::Account(_)) && !req.uri().path().starts_with("/messages") {
+        return error(StatusCode::FORBIDDEN, "forbidden");
+    }
+
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/mo") => {
+            let mo: MoMessage = match read_json(req).await {
+                Ok(mo) => mo,
+                Err(rsp) => return rsp,
+            };
+            match state.mo.inject(&mo) {
+                Ok(receipt) => {
+                    info!("mo injected via http, peer: {}, sp_id: {}, msg_id: {}", peer, receipt.sp_id, receipt.msg_id);
+                    reply(StatusCode::OK, json!(receipt))
+                }
+                Err(e @ MoError::UnknownDest(_)) => error(StatusCode::NOT_FOUND, &e.to_string()),
+                Err(e) => error(StatusCode::BAD_REQUEST, &e.to_string()),
+            }
+        }
+        (_, "/mo") => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        (&Method::POST, "/messages") => {
+            let send: SendRequest = match read_json(req).await {
+                Ok(send) => send,
+                Err(rsp) => return rsp,
+            };
+            match caller {
+                Caller::Open => {}
+                Caller::Account(ref sp_id) if *sp_id == send.sp_id => {}
+                Caller::Account(_) => return error(StatusCode::FORBIDDEN, "sp_id does not match token"),
+                Caller::Operator => return error(StatusCode::FORBIDDEN, "account token required"),
+            }
+            send_messages(state, peer, send).await
+        }
+        (&Method::GET, path) if path.starts_with("/messages/") => {
+            let status = path["/messages/".len()..].parse().ok()
+                .and_then(|id| state.statuses.get(id))
+                .filter(|status| !matches!(caller, Caller::Account(ref sp_id) if *sp_id != status.sp_id));
+            match status {
+                Some(status) => {
+                    let mut body = json!(status);
+                    body["state"] = json!(status.state());
+                    reply(StatusCode::OK, body)
+                }
+                None => error(StatusCode::NOT_FOUND, "message not found"),
+            }
+        }
+        (_, "/messages") => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        (&Method::GET, "/metrics") => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Full::new(Bytes::from(render_metrics(state))))
+            .unwrap(),
+        (method, path) if admin => {
+            let (method, path) = (method.clone(), path.to_string());
+            let segments: Vec<&str> = path.split('/').skip(2).collect();
+            let rsp = handle_admin(state, &method, &segments, req).await;
+            info!("http admin, peer: {}, {} {}, status: {}", peer, method, path, rsp.status());
+            rsp
+        }
+        _ => error(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// 管理接口, `segments` 为 `/admin/` 之后的路径
+async fn handle_admin(state: &ApiState, method: &Method, segments: &[&str], req: Request<Incoming>) -> Response<Full<Bytes>> {
+    match (method, segments) {
+        (&Method::GET, ["sessions"]) => reply(StatusCode::OK, json!(state.sessions.list())),
+        (&Method::DELETE, ["sessions", id]) => match id.parse() {
+            Ok(id) if state.sessions.kick(id) => reply(StatusCode::ACCEPTED, json!({ "kicked": id })),
+            _ => error(StatusCode::NOT_FOUND, "session not found"),
+        },
+        (&Method::GET, ["accounts"]) => {
+            let cfg = state.config.current();
+            let accounts: Vec<Value> = cfg.accounts.iter().map(|a| account_json(state, &a.sp_id, a.enabled)).collect();
+            reply(StatusCode::OK, json!(accounts))
+        }
+        (&Method::POST, ["accounts", sp_id, "balance"]) => {
+            let top_up: TopUp = match read_json(req).await {
+                Ok(top_up) => top_up,
+                Err(rsp) => return rsp,
+            };
+            match state.config.current().account(sp_id) {
+                Some(account) => match state.billing.top_up(sp_id, top_up.amount) {
+                    Some(_) => reply(StatusCode::OK, account_json(state, sp_id, account.enabled)),
+                    None => error(StatusCode::CONFLICT, "account is not prepaid"),
+                },
+                None => error(StatusCode::NOT_FOUND, "account not found"),
+            }
+        }
+        (&Method::POST, ["accounts", sp_id, action]) => {
+            let found = match *action {
+                "enable" => state.config.set_enabled(sp_id, true),
+                "disable" => state.config.set_enabled(sp_id, false),
+                "pause" | "resume" => {
+                    let found = state.config.current().account(sp_id).is_some();
+                    if found && *action == "pause" {
+                        state.sessions.pause(sp_id);
+                    } else if found {
+                        state.sessions.resume(sp_id);
+                    }
+                    found
+                }
+                _ => return error(StatusCode::NOT_FOUND, "not found"),
+            };
+            match state.config.current().account(sp_id) {
+                Some(account) if found => reply(StatusCode::OK, account_json(state, sp_id, account.enabled)),
+                _ => error(StatusCode::NOT_FOUND, "account not found"),
+            }
+        }
+        (&Method::GET, ["queues"]) => {
+            let upstreams: Vec<Value> = state.dispatcher.iter().flat_map(|d| {
+                d.upstreams().into_iter().map(|name| json!({
+                    "name": name,
+                    "online": d.online(&name),
+                    "queued": d.queued(&name),
+                }))
+            }).collect();
+            reply(StatusCode::OK, json!({
+                "delivers": state.sessions.queue_depths(),
+                "upstreams": upstreams,
+                "reviews": state.reviews.len(),
+                "scheduled": state.scheduler.len(),
+                "webhooks": state.webhooks.queued(),
+            }))
+        }
+        (&Method::POST, ["reload"]) => match state.config.reload() {
+            Ok(()) => reply(StatusCode::OK, json!({ "reloaded": true })),
+            Err(e) => {
+                warn!("reload config failed: {}", e);
+                error(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string())
+            }
+        },
+        (_, ["sessions" | "accounts" | "queues" | "reload", ..]) => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        _ => error(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// 计数器之外再输出会话、窗口和各队列的当前值
+fn render_metrics(state: &ApiState) -> String {
+    let mut out = state.metrics.render();
+
+    let mut sessions: BTreeMap<String, (f64, f64)> = BTreeMap::new();
+    for session in state.sessions.list() {
+        let entry = sessions.entry(session.sp_id).or_default();
+        entry.0 += 1.0;
+        entry.1 += session.window as f64;
+    }
+    let samples: Vec<_> = sessions.iter().map(|(sp_id, (n, _))| (vec![("account", sp_id.as_str())], *n)).collect();
+    render_gauge(&mut out, "cmpp_sessions", "Authenticated sessions", &samples);
+    let samples: Vec<_> = sessions.iter().map(|(sp_id, (_, w))| (vec![("account", sp_id.as_str())], *w)).collect();
+    render_gauge(&mut out, "cmpp_delivers_in_flight", "CMPP_DELIVER sent and not yet acknowledged", &samples);
+
+    let queued: BTreeMap<String, usize> = state.sessions.queue_depths().into_iter().collect();
+    let samples: Vec<_> = queued.iter().map(|(sp_id, n)| (vec![("account", sp_id.as_str())], *n as f64)).collect();
+    render_gauge(&mut out, "cmpp_deliver_queue_depth", "CMPP_DELIVER queued for offline or paused SPs", &samples);
+
+    if let Some(dispatcher) = &state.dispatcher {
+        let upstreams = dispatcher.upstreams();
+        let samples: Vec<_> = upstreams.iter()
+            .map(|name| (vec![("upstream", name.as_str())], dispatcher.online(name) as f64))
+            .collect();
+        render_gauge(&mut out, "cmpp_upstream_connections", "Logged in upstream connections", &samples);
+        let samples: Vec<_> = upstreams.iter()
+            .map(|name| (vec![("upstream", name.as_str())], dispatcher.queued(name) as f64))
+            .collect();
+        render_gauge(&mut out, "cmpp_upstream_queue_depth", "Submits waiting for an upstream window", &samples);
+    }
+
+    render_gauge(&mut out, "cmpp_review_queue_depth", "Submits held for review", &[(vec![], state.reviews.len() as f64)]);
+    render_gauge(&mut out, "cmpp_scheduled_submits", "Submits waiting for their At_Time", &[(vec![], state.scheduler.len() as f64)]);
+    render_gauge(&mut out, "cmpp_webhook_queue_depth", "Webhook events waiting to be sent", &[(vec![], state.webhooks.queued() as f64)]);
+    out
+}
+
+/// 充值请求
+#[derive(Deserialize)]
+struct TopUp {
+    amount: i64,
+}
+
+fn account_json(state: &ApiState, sp_id: &str, enabled: bool) -> Value {
+    json!({
+        "sp_id": sp_id,
+        "enabled": enabled,
+        "online": state.sessions.online(sp_id),
+        "queued": state.sessions.queued(sp_id),
+        "paused": state.sessions.is_paused(sp_id),
+        "balance": state.billing.balance(sp_id),
+        "usage": state.billing.usage(sp_id),
+    })
+}
+
+/// 将请求拆分为 CMPP_SUBMIT 后逐条经过流水线处理
+async fn send_messages(state: &ApiState, peer: SocketAddr, send: SendRequest) -> Response<Full<Bytes>> {
+    let submits = {
+        let cfg = state.config.current();
+        let account = match cfg.account(&send.sp_id) {
+            Some(account) if account.enabled => account,
+            _ => return error(StatusCode::FORBIDDEN, "unknown or disabled account"),
+        };
+        match send.to_submits(account) {
+            Ok(submits) => submits,
+            Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+        }
+    };
+
+    let mut messages = Vec::with_capacity(submits.len());
+    let mut failed = false;
+    for submit in submits {
+        let mut ctx = SubmitContext::new(&send.sp_id, peer, submit);
+        // 先分配 Msg_Id 并开始跟踪, 避免状态报告先于处理结果到达
+        ctx.msg_id = state.ids.next_id();
+        ctx.attrs.insert(ATTR_MSG_ID.to_string(), "http".to_string());
+        state.statuses.track(MessageStatus {
+            msg_id: ctx.msg_id,
+            sp_id: send.sp_id.clone(),
+            result: 0,
+            held: false,
+            submitted_at: Local::now().to_rfc3339(),
+            recipients: ctx.submit.dest_terminal_id.iter().map(|dest| RecipientStatus {
+                dest: dest.clone(),
+                stat: String::new(),
+                done_time: String::new(),
+            }).collect(),
+        });
+
+        let result = state.pipeline.process(&mut ctx).await;
+        let held = ctx.attrs.contains_key(ATTR_HELD);
+        state.statuses.set_result(ctx.msg_id, result, held);
+        failed |= result != 0;
+        messages.push(json!({ "msg_id": ctx.msg_id, "result": result, "held": held }));
+    }
+    info!("messages submitted via http, peer: {}, sp_id: {}, {:?}", peer, send.sp_id, messages);
+
+    let status = if failed { StatusCode::UNPROCESSABLE_ENTITY } else { StatusCode::OK };
+    reply(status, json!({ "messages": messages }))
+}
+
+async fn read_json<T: DeserializeOwned>(req: Request<Incoming>) -> std::result::Result<T, Response<Full<Bytes>>> {
+    let body = match Limited::new(req.into_body(), MAX_BODY_LEN).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return Err(error(StatusCode::BAD_REQUEST, &format!("read body failed: {}", e))),
+    };
+    serde_json::from_slice(&body).map_err(|e| error(StatusCode::BAD_REQUEST, &format!("invalid json: {}", e)))
+}
+
+fn reply(status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    let mut rsp = Response::new(Full::new(Bytes::from(body.to_string())));
+    *rsp.status_mut() = status;
+    rsp.headers_mut().insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    rsp
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    reply(status, json!({ "error": message }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use crate::server::cmd::deliver::{Cmpp3DeliverReqPkt, CmppReport};
+    use crate::server::cmd::Command;
+    use crate::server::billing::Billing;
+    use crate::server::config::{Config, ConfigHandle, HttpConfig};
+    use crate::server::config::Account;
+    use crate::server::http::{token_eq, ApiState, HttpApi};
+    use crate::server::message::StatusStore;
+    use crate::server::metrics::Counter;
+    use crate::server::mo::MoService;
+    use crate::server::msgid::MsgIdGen;
+    use crate::server::pipeline::Pipeline;
+    use crate::server::review::Reviews;
+    use crate::server::schedule::Scheduler;
+    use crate::server::session::Sessions;
+    use crate::server::webhook::Webhooks;
+
+    async fn request(addr: std::net::SocketAddr, req: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut rsp = String::new();
+        stream.read_to_string(&mut rsp).await.unwrap();
+        rsp
+    }
+
+    async fn start(cfg: Config, sessions: Arc<Sessions>) -> (std::net::SocketAddr, ApiState) {
+        let handle = ConfigHandle::new(cfg);
+        let statuses = Arc::new(StatusStore::default());
+        sessions.add_hook(statuses.clone());
+        let api = HttpApi::bind("127.0.0.1:0").await.unwrap();
+        let addr = api.local_addr().unwrap();
+        let ids = Arc::new(MsgIdGen::new(1));
+        let state = ApiState {
+            config: handle.clone(),
+            mo: Arc::new(MoService::new(handle.subscribe(), ids.clone(), sessions.clone(), None)),
+            billing: Billing::start(handle.subscribe()).unwrap(),
+            pipeline: Arc::new(Pipeline::new()),
+            statuses,
+            ids: ids.clone(),
+            sessions: sessions.clone(),
+            reviews: Arc::new(Reviews::new(ids.clone(), sessions.clone())),
+            scheduler: Scheduler::start(ids, sessions),
+            webhooks: Webhooks::start(handle.subscribe()),
+            dispatcher: None,
+            metrics: Arc::default(),
+        };
+        let api_state = state.clone();
+        tokio::spawn(async move { api.run(state).await });
+        (addr, api_state)
+    }
+
+    fn get(path: &str, token: &str) -> String {
+        format!("GET {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n", path, token)
+    }
+
+    fn post(path: &str, token: &str, body: &str) -> String {
+        format!("POST {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\
+                 Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", path, token, body.len(), body)
+    }
+
+    #[tokio::test]
+    async fn test_inject_mo() {
+        let mut cfg = Config::demo();
+        cfg.accounts[0].src_ids = vec!["1069".to_string()];
+        cfg.http = Some(HttpConfig { addr: "127.0.0.1:0".to_string(), token: Some("secret".to_string()), admin_token: None });
+        let sessions = Arc::new(Sessions::default());
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let _guard = sessions.register("900001", "127.0.0.1:5000".parse().unwrap(), tx);
+        let (addr, _) = start(cfg, sessions).await;
+
+        let body = r#"{"src_terminal_id": "13800138000", "dest_id": "106900011", "content": "TD"}"#;
+        let rsp = request(addr, &post("/mo", "secret", body)).await;
+        assert!(rsp.starts_with("HTTP/1.1 200"), "{}", rsp);
+        assert!(rsp.contains(r#""sp_id":"900001""#));
+        assert!(matches!(rx.try_recv(), Ok(Command::DeliverReq(d)) if d.msg_content == "TD" && d.dest_id == "106900011"));
+
+        let rsp = request(addr, &post("/mo", "wrong", body)).await;
+        assert!(rsp.starts_with("HTTP/1.1 401"), "{}", rsp);
+
+        let body = r#"{"src_terminal_id": "13800138000", "dest_id": "95555", "content": "hi"}"#;
+        let rsp = request(addr, &post("/mo", "secret", body)).await;
+        assert!(rsp.starts_with("HTTP/1.1 404"), "{}", rsp);
+
+        let rsp = request(addr, &post("/mo", "secret", "{")).await;
+        assert!(rsp.starts_with("HTTP/1.1 400"), "{}", rsp);
+
+        // 未配置 admin_token 时不开放管理接口
+        let rsp = request(addr, &get("/admin/sessions", "secret")).await;
+        assert!(rsp.starts_with("HTTP/1.1 403"), "{}", rsp);
+    }
+
+    #[tokio::test]
+    async fn test_send_messages() {
+        let mut cfg = Config::demo();
+        cfg.accounts[0].src_ids = vec!["1069".to_string()];
+        let sessions = Arc::new(Sessions::default());
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let _guard = sessions.register("900001", "127.0.0.1:5000".parse().unwrap(), tx);
+        let (addr, state) = start(cfg, sessions.clone()).await;
+        let statuses = state.statuses;
+
+        let body = r#"{"sp_id": "900001", "recipients": ["13800138000"], "content": "你好", "service_id": "svc"}"#;
+        let rsp = request(addr, &post("/messages", "", body)).await;
+        assert!(rsp.starts_with("HTTP/1.1 200"), "{}", rsp);
+        let json: serde_json::Value = serde_json::from_str(rsp.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        let msg_id = json["messages"][0]["msg_id"].as_u64().unwrap();
+        assert_eq!(statuses.get(msg_id).unwrap().state(), "pending");
+
+        // 状态报告更新到消息状态, 不再投递给 SP
+        let mut deliver = Cmpp3DeliverReqPkt::new();
+        deliver.report = Some(CmppReport { msg_id, stat: "DELIVRD".to_string(), dest_terminal_id: "13800138000".to_string(), ..Default::default() });
+        sessions.deliver("900001", deliver);
+        assert!(rx.try_recv().is_err());
+        let rsp = request(addr, &get(&format!("/messages/{}", msg_id), "")).await;
+        assert!(rsp.contains(r#""state":"delivered""#), "{}", rsp);
+
+        let body = r#"{"sp_id": "999999", "recipients": ["13800138000"], "content": "hi", "service_id": "svc"}"#;
+        let rsp = request(addr, &post("/messages", "", body)).await;
+        assert!(rsp.starts_with("HTTP/1.1 403"), "{}", rsp);
+        let rsp = request(addr, &get("/messages/1", "")).await;
+        assert!(rsp.starts_with("HTTP/1.1 404"), "{}", rsp);
+    }
+
+    #[tokio::test]
+    async fn test_admin() {
+        let http = HttpConfig { addr: "127.0.0.1:0".to_string(), token: Some("api".to_string()), admin_token: Some("admin".to_string()) };
+        let mut cfg = Config { http: Some(http), ..Config::demo() };
+        cfg.accounts[0].balance = Some(5);
+        let sessions = Arc::new(Sessions::default());
+        let (tx, _rx) = tokio::sync::mpsc::channel(8);
+        let guard = sessions.register("900001", "127.0.0.1:5000".parse().unwrap(), tx);
+        let (addr, state) = start(cfg, sessions.clone()).await;
+
+        // 管理接口只接受 admin_token
+        let rsp = request(addr, &get("/admin/sessions", "api")).await;
+        assert!(rsp.starts_with("HTTP/1.1 401"), "{}", rsp);
+        let rsp = request(addr, &get("/admin/sessions", "admin")).await;
+        assert!(rsp.contains(r#""sp_id":"900001""#) && rsp.contains(r#""peer":"127.0.0.1:5000""#), "{}", rsp);
+
+        let id = sessions.list()[0].id;
+        let kick = guard.kicked();
+        let rsp = request(addr, &format!("DELETE /admin/sessions/{} HTTP/1.1\r\nHost: localhost\r\n\
+                                           Authorization: Bearer admin\r\nConnection: close\r\n\r\n", id)).await;
+        assert!(rsp.starts_with("HTTP/1.1 202"), "{}", rsp);
+        tokio::time::timeout(std::time::Duration::from_secs(1), kick.notified()).await.unwrap();
+
+        let rsp = request(addr, &post("/admin/accounts/900001/disable", "admin", "")).await;
+        assert!(rsp.contains(r#""enabled":false"#), "{}", rsp);
+        assert!(!state.config.current().accounts[0].enabled);
+        let rsp = request(addr, &post("/admin/accounts/900001/pause", "admin", "")).await;
+        assert!(rsp.contains(r#""paused":true"#), "{}", rsp);
+        assert!(sessions.is_paused("900001"));
+        let rsp = request(addr, &post("/admin/accounts/999999/enable", "admin", "")).await;
+        assert!(rsp.starts_with("HTTP/1.1 404"), "{}", rsp);
+        let rsp = request(addr, &post("/admin/accounts/900001/balance", "admin", r#"{"amount": 10}"#)).await;
+        assert!(rsp.contains(r#""balance":15"#), "{}", rsp);
+
+        // 未从文件加载的配置不能重新加载
+        let rsp = request(addr, &post("/admin/reload", "admin", "")).await;
+        assert!(rsp.starts_with("HTTP/1.1 422"), "{}", rsp);
+        let path = std::env::temp_dir().join(format!("cmpp-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, "[[accounts]]\nsp_id = \"900002\"\npassword = \"1\"\n").unwrap();
+        let mut cfg = (*state.config.current()).clone();
+        cfg.path = Some(path.clone());
+        state.config.update(cfg);
+        let rsp = request(addr, &post("/admin/reload", "admin", "")).await;
+        assert!(rsp.starts_with("HTTP/1.1 200"), "{}", rsp);
+        assert!(state.config.current().account("900002").is_some());
+        let _ = std::fs::remove_file(path);
+
+        let rsp = request(addr, &get("/admin/queues", "admin")).await;
+        assert!(rsp.contains(r#""reviews":0"#) && rsp.contains(r#""scheduled":0"#) && rsp.contains(r#""webhooks":0"#), "{}", rsp);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let sessions = Arc::new(Sessions::default());
+        let (tx, _rx) = tokio::sync::mpsc::channel(8);
+        let _guard = sessions.register("900001", "127.0.0.1:5000".parse().unwrap(), tx);
+        let (addr, state) = start(Config::demo(), sessions).await;
+        state.metrics.inc(Counter::Submits, &[("account", "900001"), ("result", "0")]);
+
+        let rsp = request(addr, &get("/metrics", "")).await;
+        assert!(rsp.starts_with("HTTP/1.1 200"), "{}", rsp);
+        assert!(rsp.contains("content-type: text/plain; version=0.0.4"), "{}", rsp);
+        assert!(rsp.contains("cmpp_submits_total{account=\"900001\",result=\"0\"} 1\n"), "{}", rsp);
+        assert!(rsp.contains("cmpp_sessions{account=\"900001\"} 1\n"), "{}", rsp);
+        assert!(rsp.contains("cmpp_review_queue_depth 0\n"), "{}", rsp);
+        assert!(rsp.contains("cmpp_scheduled_submits 0\n"), "{}", rsp);
+    }
+    #[tokio::test]
+    async fn test_account_token() {
+        assert!(token_eq("secret", "secret"));
+        assert!(!token_eq("secret", "secreT"));
+        assert!(!token_eq("secret", "secret1"));
+
+        let mut cfg = Config::demo();
+        cfg.http = Some(HttpConfig { addr: "127.0.0.1:0".to_string(), token: Some("ops".to_string()), admin_token: None });
+        cfg.accounts[0].src_ids = vec!["1069".to_string()];
+        cfg.accounts[0].http_token = Some("sp1".to_string());
+        let mut account = Account::new("900002", "888888");
+        account.http_token = Some("sp2".to_string());
+        cfg.accounts.push(account);
+        let (addr, _) = start(cfg, Arc::new(Sessions::default())).await;
+
+        // 提交只接受 sp_id 所属账号的 token
+        let body = r#"{"sp_id": "900001", "recipients": ["13800138000"], "content": "hi", "service_id": "svc"}"#;
+        let rsp = request(addr, &post("/messages", "ops", body)).await;
+        assert!(rsp.starts_with("HTTP/1.1 403"), "{}", rsp);
+        let rsp = request(addr, &post("/messages", "sp2", body)).await;
+        assert!(rsp.starts_with("HTTP/1.1 403"), "{}", rsp);
+        let rsp = request(addr, &post("/messages", "wrong", body)).await;
+        assert!(rsp.starts_with("HTTP/1.1 401"), "{}", rsp);
+        let rsp = request(addr, &post("/messages", "sp1", body)).await;
+        assert!(rsp.starts_with("HTTP/1.1 200"), "{}", rsp);
+        let json: serde_json::Value = serde_json::from_str(rsp.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        let path = format!("/messages/{}", json["messages"][0]["msg_id"].as_u64().unwrap());
+
+        // 账号 token 只能查询本账号的消息, 不能访问其他接口
+        let rsp = request(addr, &get(&path, "sp2")).await;
+        assert!(rsp.starts_with("HTTP/1.1 404"), "{}", rsp);
+        let rsp = request(addr, &get(&path, "sp1")).await;
+        assert!(rsp.starts_with("HTTP/1.1 200"), "{}", rsp);
+        let rsp = request(addr, &get(&path, "ops")).await;
+        assert!(rsp.starts_with("HTTP/1.1 200"), "{}", rsp);
+        let rsp = request(addr, &get("/metrics", "sp1")).await;
+        assert!(rsp.starts_with("HTTP/1.1 403"), "{}", rsp);
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use aho_corasick::AhoCorasick;
+use async_trait::async_trait;
+use chrono::Local;
+
+use crate::server::cmd::ERRNO_SUBMIT_KEYWORD;
+use crate::server::config::{Config, ConfigRx, KeywordAction};
+use crate::server::pipeline::{Flow, Phase, SubmitContext, SubmitStage};
+use crate::server::review::Reviews;
+use crate::server::Result;
+
+// 长短信分段最多等待的时间, 超过后不再与之后的分段拼接
+const SEGMENT_TTL: Duration = Duration::from_secs(300);
+// 最多暂存的长短信数
+const MAX_SEGMENTED: usize = 10_000;
+
+/// 全角转半角并转为小写
+pub fn normalize(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\u{3000}' => ' ',
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            _ => c,
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// 禁止词和允许词组成的词典
+struct Dictionary {
+    matcher: Option<AhoCorasick>,
+    // 模式对应的原词, 以及是否为允许词
+    words: Vec<(String, bool)>,
+}
+
+impl Dictionary {
+    fn new<'a>(deny: impl Iterator<Item = &'a String>, allow: impl Iterator<Item = &'a String>) -> Result<Dictionary> {
+        let words: Vec<(String, bool)> = deny.map(|w| (w.clone(), false))
+            .chain(allow.map(|w| (w.clone(), true)))
+            .filter(|(w, _)| !w.trim().is_empty())
+            .collect();
+        if words.is_empty() {
+            return Ok(Dictionary { matcher: None, words });
+        }
+        let matcher = AhoCorasick::new(words.iter().map(|(w, _)| normalize(w.trim())))?;
+        Ok(Dictionary { matcher: Some(matcher), words })
+    }
+
+    /// 命中的禁止词, 完全位于允许词之内的不计
+    fn find(&self, text: &str) -> Vec<String> {
+        let matcher = match self.matcher {
+            Some(ref matcher) => matcher,
+            None => return vec![],
+        };
+        let text = normalize(text);
+        let mut allowed = Vec::new();
+        let mut denied = Vec::new();
+        for m in matcher.find_overlapping_iter(&text) {
+            match self.words[m.pattern()] {
+                (_, true) => allowed.push(m.range()),
+                (ref word, false) => denied.push((m.range(), word)),
+            }
+        }
+
+        let mut words: Vec<String> = Vec::new();
+        for (range, word) in denied {
+            let exempt = allowed.iter().any(|a| a.start <= range.start && range.end <= a.end);
+            if !exempt && !words.contains(word) {
+                words.push(word.clone());
+            }
+        }
+        words
+    }
+}
+
+/// 按配置构建的敏感词引擎, 配置了禁止词或允许词的账号使用独立的词典
+pub struct KeywordEngine {
+    global: Dictionary,
+    per_sp: HashMap<String, Dictionary>,
+}
+
+impl KeywordEngine {
+    pub fn new(cfg: &Config) -> Result<KeywordEngine> {
+        let mut deny = cfg.keywords.words.clone();
+        if let Some(ref path) = cfg.keywords.file {
+            deny.extend(load_words(path)?);
+        }
+
+        let mut per_sp = HashMap::new();
+        for account in &cfg.accounts {
+            if account.deny_words.is_empty() && account.allow_words.is_empty() {
+                continue;
+            }
+            let dict = Dictionary::new(deny.iter().chain(&account.deny_words), account.allow_words.iter())?;
+            per_sp.insert(account.sp_id.clone(), dict);
+        }
+
+        Ok(KeywordEngine {
+            global: Dictionary::new(deny.iter(), [].iter())?,
+            per_sp,
+        })
+    }
+
+    /// 未配置任何词
+    pub fn is_empty(&self) -> bool {
+        self.global.words.is_empty() && self.per_sp.is_empty()
+    }
+
+    /// 内容命中的禁止词
+    pub fn check(&self, sp_id: &str, text: &str) -> Vec<String> {
+        self.per_sp.get(sp_id).unwrap_or(&self.global).find(text)
+    }
+}
+
+/// 读取词文件, 每行一个词, `#` 开头为注释
+fn load_words<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path.as_ref())?;
+    Ok(content.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+// 同一条长短信: (SP, 源号码, 接收号码, 参考号, 总条数)
+type SegmentKey = (String, String, Vec<String>, u16, u8);
+
+// 已收到的长短信分段内容, 按序号存放
+struct Segments {
+    received_at: Instant,
+    texts: Vec<Option<String>>,
+}
+
+/// 敏感词过滤环节, 注册在 `Phase::Filtering`
+///
+/// 命中时按配置拒绝提交或转人工审核, 每次命中都写入审核记录。
+/// 长短信的分段与已收到的相邻分段拼接后检查, 以发现跨分段的禁止词。
+pub struct KeywordFilter {
+    engine: RwLock<Arc<KeywordEngine>>,
+    config: ConfigRx,
+    reviews: Arc<Reviews>,
+    // 审核记录文件, 路径变化时重新打开
+    audit: Mutex<Option<(PathBuf, File)>>,
+    segments: Mutex<HashMap<SegmentKey, Segments>>,
+}
+
+impl KeywordFilter {
+    /// 加载词典, 并在配置变化时重新加载, 需在 tokio 运行时中调用
+    pub fn start(mut config: ConfigRx, reviews: Arc<Reviews>) -> Result<Arc<KeywordFilter>> {
+        let engine = KeywordEngine::new(&config.borrow_and_update())?;
+        let filter = Arc::new(KeywordFilter {
+            engine: RwLock::new(Arc::new(engine)),
+            config: config.clone(),
+            reviews,
+            audit: Mutex::new(None),
+            segments: Mutex::new(HashMap::new()),
+        });
+
+        let weak = Arc::downgrade(&filter);
+        tokio::spawn(async move {
+            while config.changed().await.is_ok() {
+                let filter = match weak.upgrade() {
+                    Some(filter) => filter,
+                    None => return,
+                };
+                let res = KeywordEngine::new(&config.borrow_and_update());
+                match res {
+                    Ok(engine) => *filter.engine.write().unwrap() = Arc::new(engine),
+                    Err(e) => log::error!("reload keywords failed, keep old keywords: {}", e),
+                }
+            }
+        });
+        Ok(filter)
+    }
+
+    /// 内容命中的禁止词
+    pub fn check(&self, sp_id: &str, text: &str) -> Vec<String> {
+        self.engine.read().unwrap().check(sp_id, text)
+    }
+
+    // 待检查的内容: 长短信为本分段及已收到的相邻分段拼接后的内容
+    fn text(&self, ctx: &SubmitContext) -> String {
+        let submit = &ctx.submit;
+        let segment = match submit.segment() {
+            Some(segment) if segment.total > 1 && (1..=segment.total).contains(&segment.number) => segment,
+            _ => return submit.msg_content.clone(),
+        };
+        let key = (ctx.sp_id.clone(), submit.src_id.clone(), submit.dest_terminal_id.clone(), segment.reference, segment.total);
+
+        let mut segments = self.segments.lock().unwrap();
+        if segments.len() >= MAX_SEGMENTED {
+            segments.retain(|_, s| s.received_at.elapsed() < SEGMENT_TTL);
+        }
+        if !segments.contains_key(&key) && segments.len() >= MAX_SEGMENTED {
+            return submit.msg_content.clone();
+        }
+        let entry = segments.entry(key.clone())
+            .and_modify(|s| if s.received_at.elapsed() >= SEGMENT_TTL { s.texts.fill(None) })
+            .or_insert_with(|| Segments { received_at: Instant::now(), texts: vec![None; segment.total as usize] });
+        entry.received_at = Instant::now();
+        let index = segment.number as usize - 1;
+        entry.texts[index] = Some(submit.msg_content.clone());
+
+        // 向前后延伸到缺失的分段为止
+        let texts = &entry.texts;
+        let start = texts[..index].iter().rposition(Option::is_none).map_or(0, |i| i + 1);
+        let end = texts[index..].iter().position(Option::is_none).map_or(texts.len(), |i| index + i);
+        let text = texts[start..end].iter().flatten().map(String::as_str).collect();
+        if texts.iter().all(Option::is_some) {
+            segments.remove(&key);
+        }
+        text
+    }
+
+    fn audit(&self, ctx: &SubmitContext, words: &[String], action: &str, path: Option<&Path>) {
+        log::warn!("keyword matched, sp_id: {}, src_id: {}, words: {:?}, action: {}",
+            ctx.sp_id, ctx.submit.src_id, words, action);
+        let path = match path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let record = serde_json::json!({
+            "time": Local::now().to_rfc3339(),
+            "sp_id": ctx.sp_id,
+            "peer": ctx.peer.to_string(),
+            "msg_id": ctx.msg_id,
+            "src_id": ctx.submit.src_id,
+            "dest_terminal_id": ctx.submit.dest_terminal_id,
+            "words": words,
+            "action": action,
+            "content": ctx.submit.msg_content,
+        });
+        let mut audit = self.audit.lock().unwrap();
+        if audit.as_ref().is_none_or(|(p, _)| p != path) {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => *audit = Some((path.to_path_buf(), file)),
+                Err(e) => {
+                    log::error!("open keyword audit log {} failed: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+        if let Some((_, ref mut file)) = *audit {
+            if let Err(e) = writeln!(file, "{}", record) {
+                log::error!("write keyword audit log failed: {}", e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SubmitStage for KeywordFilter {
+    fn name(&self) -> &str {
+        "keyword"
+    }
+
+    async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+        // 短信写卡和二进制消息不检查
+        if matches!(ctx.submit.msg_fmt, 3 | 4) {
+            return Flow::Continue;
+        }
+        let words = self.check(&ctx.sp_id, &self.text(ctx));
+        if words.is_empty() {
+            return Flow::Continue;
+        }
+
+        let cfg = self.config.borrow().keywords.clone();
+        let result = cfg.result.unwrap_or(ERRNO_SUBMIT_KEYWORD);
+        let flow = match cfg.action {
+            KeywordAction::Review if self.reviews.hold(ctx, Phase::Filtering, &format!("keyword: {}", words.join(","))) => {
+                Flow::Hold
+            }
+            _ => Flow::Reject(result),
+        };
+        let action = match flow {
+            Flow::Hold => "review".to_string(),
+            _ => format!("reject:{}", result),
+        };
+        self.audit(ctx, &words, &action, cfg.audit_log.as_deref());
+        flow
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::server::cmd::submit::Cmpp3SubmitReqPkt;
+    use crate::server::cmd::ERRNO_SUBMIT_KEYWORD;
+    use crate::server::config::{Config, ConfigHandle};
+    use crate::server::keyword::{normalize, KeywordEngine, KeywordFilter};
+    use crate::server::msgid::MsgIdGen;
+    use crate::server::pipeline::{Flow, SubmitContext, SubmitStage};
+    use crate::server::review::Reviews;
+    use crate::server::session::Sessions;
+    use crate::util::str::utf8_to_ucs2;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("ＡＢＣ　ｄｅｆ１２３！"), "abc def123!");
+        assert_eq!(normalize("发票ABC"), "发票abc");
+    }
+
+    #[test]
+    fn test_check() {
+        let mut cfg = Config::demo();
+        cfg.keywords.words = vec!["发票".to_string(), "VPN".to_string()];
+        cfg.accounts[0].deny_words = vec!["贷款".to_string()];
+        cfg.accounts[0].allow_words = vec!["电子发票".to_string()];
+        let engine = KeywordEngine::new(&cfg).unwrap();
+
+        assert_eq!(engine.check("900002", "代开发票, 低价ｖｐｎ"), vec!["发票", "VPN"]);
+        assert!(engine.check("900002", "您好").is_empty());
+        assert!(engine.check("900002", "无息贷款").is_empty());
+        // 账号的禁止词和允许词
+        assert_eq!(engine.check("900001", "无息贷款"), vec!["贷款"]);
+        assert!(engine.check("900001", "您的电子发票已开具").is_empty());
+        assert_eq!(engine.check("900001", "电子发票, 代开发票"), vec!["发票"]);
+    }
+    // 经过编解码的 CMPP_SUBMIT
+    fn new_ctx(tp_udhi: u8, msg_fmt: u8, msg_bytes: Vec<u8>) -> SubmitContext {
+        let mut submit = Cmpp3SubmitReqPkt::default();
+        submit.tp_udhi = tp_udhi;
+        submit.msg_fmt = msg_fmt;
+        submit.src_id = "10690001".to_string();
+        submit.dest_terminal_id = vec!["13800138000".to_string()];
+        submit.set_msg_bytes(msg_bytes);
+        let mut frame = submit.pack().unwrap();
+        let submit = Cmpp3SubmitReqPkt::parse_frame(1, &mut frame[12..]).unwrap();
+        SubmitContext::new("900001", "127.0.0.1:5000".parse().unwrap(), submit)
+    }
+
+    // UCS2 编码的长短信分段
+    fn segment(reference: u8, number: u8, text: &str) -> SubmitContext {
+        let mut msg_bytes = vec![5, 0, 3, reference, 2, number];
+        msg_bytes.extend(utf8_to_ucs2(text));
+        new_ctx(1, 8, msg_bytes)
+    }
+
+    #[tokio::test]
+    async fn test_filter_content() {
+        let mut cfg = Config::demo();
+        cfg.keywords.words = vec!["发票".to_string(), "vpn".to_string()];
+        let handle = ConfigHandle::new(cfg);
+        let reviews = Arc::new(Reviews::new(Arc::new(MsgIdGen::new(1)), Arc::new(Sessions::default())));
+        let filter = KeywordFilter::start(handle.subscribe(), reviews).unwrap();
+        let reject = Flow::Reject(ERRNO_SUBMIT_KEYWORD);
+
+        // GBK 编码
+        let gbk = encoding_rs::GBK.encode("代开发票").0.into_owned();
+        assert_eq!(filter.process(&mut new_ctx(0, 15, gbk)).await, reject);
+
+        // UDH 不计入内容
+        let mut msg_bytes = vec![5, 0, 3, b'v', b'p', b'n'];
+        msg_bytes.extend(b"hello");
+        let mut ctx = new_ctx(1, 0, msg_bytes);
+        assert_eq!(ctx.submit.msg_content, "hello");
+        assert_eq!(filter.process(&mut ctx).await, Flow::Continue);
+
+        // 跨分段的禁止词, 分段可能乱序到达
+        assert_eq!(filter.process(&mut segment(1, 1, "代开发")).await, Flow::Continue);
+        assert_eq!(filter.process(&mut segment(1, 2, "票")).await, reject);
+        assert_eq!(filter.process(&mut segment(2, 2, "票")).await, Flow::Continue);
+        assert_eq!(filter.process(&mut segment(2, 1, "代开发")).await, reject);
+        // 不同批次的分段不拼接
+        assert_eq!(filter.process(&mut segment(3, 1, "代开发")).await, Flow::Continue);
+        assert_eq!(filter.process(&mut segment(4, 2, "票")).await, Flow::Continue);
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::Instant;
+
+/// 令牌桶限流, rate 为每秒允许的请求数, 0 表示不限流
+pub(crate) struct RateLimiter {
+    rate: usize,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: usize) -> RateLimiter {
+        RateLimiter {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub(crate) fn rate(&self) -> usize {
+        self.rate
+    }
+
+    /// 调整速率, 已积累的令牌不超过新的上限
+    pub(crate) fn set_rate(&mut self, rate: usize) {
+        self.refill();
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    pub(crate) fn try_acquire(&mut self) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true;
+        }
+        false
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::server::limit::RateLimiter;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2);
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        limiter.set_rate(0);
+        assert!(limiter.try_acquire());
+    }
+}
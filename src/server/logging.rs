@@ -0,0 +1,161 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use chrono::Local;
+use env_logger::Builder;
+use log::Record;
+use serde_json::{json, Map, Value};
+
+use crate::server::config::{LogConfig, LogFormat};
+
+// 会话编号, 进程内递增
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+
+tokio::task_local! {
+    static CONTEXT: LogContext;
+}
+
+/// 会话的日志上下文, 会话和处理器任务各持有一份, 共享会话信息
+pub(crate) struct LogContext {
+    session: Arc<SessionLog>,
+    // 当前处理的请求的 Sequence_Id
+    seq_id: Cell<Option<u32>>,
+}
+
+/// 会话信息, 出现在该会话的每条日志中
+pub(crate) struct SessionLog {
+    id: u64,
+    peer: SocketAddr,
+    account: OnceLock<String>,
+}
+
+impl SessionLog {
+    pub(crate) fn new(peer: SocketAddr) -> Arc<SessionLog> {
+        Arc::new(SessionLog {
+            id: NEXT_SESSION.fetch_add(1, Ordering::Relaxed),
+            peer,
+            account: OnceLock::new(),
+        })
+    }
+
+    /// 认证成功后记录账号
+    pub(crate) fn set_account(&self, account: &str) {
+        let _ = self.account.set(account.to_string());
+    }
+
+    /// 在该会话的上下文中运行 `fut`, 期间的日志带上会话信息
+    pub(crate) async fn scope<F: Future>(self: &Arc<Self>, fut: F) -> F::Output {
+        let ctx = LogContext { session: self.clone(), seq_id: Cell::new(None) };
+        CONTEXT.scope(ctx, fut).await
+    }
+}
+
+/// 在当前会话的上下文中运行 `fut`, 用于会话派生的任务, 不在会话上下文中时直接运行
+pub(crate) async fn inherit<F: Future>(fut: F) -> F::Output {
+    match CONTEXT.try_with(|ctx| ctx.session.clone()) {
+        Ok(session) => session.scope(fut).await,
+        Err(_) => fut.await,
+    }
+}
+
+/// 记录当前处理的请求的 Sequence_Id, 不在会话上下文中时忽略
+pub(crate) fn set_seq_id(seq_id: u32) {
+    let _ = CONTEXT.try_with(|ctx| ctx.seq_id.set(Some(seq_id)));
+}
+
+/// 按配置初始化日志, 只能调用一次
+pub fn init_logger(cfg: &LogConfig) {
+    let mut builder = Builder::new();
+    builder.parse_filters(&cfg.level);
+    match cfg.format {
+        LogFormat::Text => builder.format(|buf, record| writeln!(buf, "{}", format_text(record))),
+        LogFormat::Json => builder.format(|buf, record| writeln!(buf, "{}", format_json(record))),
+    };
+    builder.init();
+}
+
+fn format_text(record: &Record) -> String {
+    let mut line = format!("{} [{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), record.level(), record.target());
+    let _ = CONTEXT.try_with(|ctx| {
+        line.push_str(&format!(" [session={} peer={}", ctx.session.id, ctx.session.peer));
+        if let Some(account) = ctx.session.account.get() {
+            line.push_str(&format!(" account={}", account));
+        }
+        if let Some(seq_id) = ctx.seq_id.get() {
+            line.push_str(&format!(" seq_id={}", seq_id));
+        }
+        line.push(']');
+    });
+    line.push_str(&format!(" {}", record.args()));
+    line
+}
+
+fn format_json(record: &Record) -> Value {
+    let mut fields = Map::new();
+    fields.insert("ts".to_string(), json!(Local::now().to_rfc3339()));
+    fields.insert("level".to_string(), json!(record.level().as_str()));
+    fields.insert("target".to_string(), json!(record.target()));
+    let _ = CONTEXT.try_with(|ctx| {
+        fields.insert("session".to_string(), json!(ctx.session.id));
+        fields.insert("peer".to_string(), json!(ctx.session.peer.to_string()));
+        if let Some(account) = ctx.session.account.get() {
+            fields.insert("account".to_string(), json!(account));
+        }
+        if let Some(seq_id) = ctx.seq_id.get() {
+            fields.insert("seq_id".to_string(), json!(seq_id));
+        }
+    });
+    fields.insert("msg".to_string(), json!(record.args().to_string()));
+    Value::Object(fields)
+}
+
+/// 日志中代替敏感字段的内容
+pub(crate) fn redact(bytes: usize) -> String {
+    format!("<redacted {} bytes>", bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use log::{Level, Record};
+
+    use crate::server::cmd::connect::CmppConnReqPkt;
+    use crate::server::cmd::submit::Cmpp3SubmitReqPkt;
+    use crate::server::logging::{format_json, format_text, set_seq_id, SessionLog};
+
+    #[tokio::test]
+    async fn test_context() {
+        let args = format_args!("submit received");
+        let record = Record::builder().args(args).level(Level::Info).target("cmpp").build();
+        assert!(format_text(&record).ends_with("[INFO] cmpp submit received"));
+        assert!(format_json(&record).get("session").is_none());
+
+        let session = SessionLog::new("127.0.0.1:5000".parse().unwrap());
+        session.set_account("900001");
+        let (text, json) = session.scope(async {
+            set_seq_id(7);
+            (format_text(&record), format_json(&record))
+        }).await;
+        assert!(text.contains(" peer=127.0.0.1:5000 account=900001 seq_id=7] submit received"), "{}", text);
+        assert_eq!(json["account"], "900001");
+        assert_eq!(json["seq_id"], 7);
+        assert_eq!(json["msg"], "submit received");
+    }
+
+    #[test]
+    fn test_redact() {
+        let req = CmppConnReqPkt::login("900001", "888888", 0x30, 1019120000, 1);
+        let out = format!("{:?}", req);
+        assert!(out.contains("src_addr: \"900001\"") && !out.contains("888888"), "{}", out);
+        assert!(out.contains("auth_src: \"<redacted 16 bytes>\""), "{}", out);
+
+        let mut submit = Cmpp3SubmitReqPkt::default();
+        submit.set_msg_bytes("验证码 1234".as_bytes().to_vec());
+        let out = format!("{:?}", submit);
+        assert!(!out.contains("1234"), "{}", out);
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::server::cmd::deliver::Cmpp3DeliverReqPkt;
+use crate::server::cmd::submit::{encode_content, Cmpp3SubmitReqPkt};
+use crate::server::config::Account;
+use crate::server::session::DeliverHook;
+use crate::server::Result;
+
+// 单条短消息的最大长度, ASCII 编码为 160 字节, 其余编码为 140 字节
+const MAX_MSG_LEN: usize = 140;
+const MAX_ASCII_MSG_LEN: usize = 160;
+// 长短信 UDH: 05 00 03 批次号 总条数 序号
+const UDH_LEN: usize = 6;
+// 最多跟踪的消息数, 超出或过期时丢弃最早的
+const MAX_TRACKED: usize = 100_000;
+const TRACK_TTL: Duration = Duration::from_secs(72 * 3600);
+
+/// 通过接口提交的短信
+#[derive(Clone, Debug, Deserialize)]
+pub struct SendRequest {
+    pub sp_id: String,
+    /// 接收号码
+    pub recipients: Vec<String>,
+    pub content: String,
+    /// 编码, 0 为 ASCII, 8 为 UCS2
+    #[serde(default = "default_msg_fmt")]
+    pub msg_fmt: u8,
+    pub service_id: String,
+    /// 主叫号码, 未指定时使用账号的第一个 `src_ids`
+    #[serde(default)]
+    pub src_id: Option<String>,
+    /// 定时发送时间, 格式同 CMPP_SUBMIT 的 At_Time
+    #[serde(default)]
+    pub at_time: String,
+}
+
+fn default_msg_fmt() -> u8 {
+    8
+}
+
+impl SendRequest {
+    /// 转换为 CMPP_SUBMIT, 超长内容按 UDH 拆分为多条
+    pub fn to_submits(&self, account: &Account) -> Result<Vec<Cmpp3SubmitReqPkt>> {
+        if self.recipients.is_empty() {
+            return Err("no recipients".into());
+        }
+        let content = encode_content(self.msg_fmt, &self.content)?;
+        let segments = split(self.msg_fmt, content)?;
+        let src_id = self.src_id.clone().or_else(|| account.src_ids.first().cloned()).unwrap_or_default();
+
+        let total = segments.len() as u8;
+        Ok(segments.into_iter().enumerate().map(|(i, (udhi, msg_bytes))| {
+            let mut submit = Cmpp3SubmitReqPkt::default();
+            submit.pk_total = total;
+            submit.pk_number = i as u8 + 1;
+            submit.registered_delivery = 1;
+            submit.service_id = self.service_id.clone();
+            submit.tp_udhi = udhi;
+            submit.msg_fmt = self.msg_fmt;
+            submit.msg_src = self.sp_id.clone();
+            submit.fee_type = "01".to_string();
+            submit.at_time = self.at_time.clone();
+            submit.src_id = src_id.clone();
+            submit.dest_usr_tl = self.recipients.len() as u8;
+            submit.dest_terminal_id = self.recipients.clone();
+            submit.set_msg_bytes(msg_bytes);
+            submit
+        }).collect())
+    }
+}
+
+/// 超长内容拆分为带 UDH 的多条, 返回 (TP_udhi, 内容)
+fn split(msg_fmt: u8, content: Vec<u8>) -> Result<Vec<(u8, Vec<u8>)>> {
+    let max_len = if msg_fmt == 0 { MAX_ASCII_MSG_LEN } else { MAX_MSG_LEN };
+    if content.len() <= max_len {
+        return Ok(vec![(0, content)]);
+    }
+
+    let mut chunks: Vec<&[u8]> = Vec::new();
+    let mut rest = content.as_slice();
+    while !rest.is_empty() {
+        let mut len = rest.len().min(max_len - UDH_LEN);
+        if msg_fmt == 8 {
+            len -= len % 2;
+            // 不拆开 UTF-16 代理对
+            if len < rest.len() && (0xD8..0xDC).contains(&rest[len - 2]) {
+                len -= 2;
+            }
+        }
+        chunks.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    if chunks.len() > u8::MAX as usize {
+        return Err(format!("content too long: {} segments", chunks.len()).into());
+    }
+
+    let reference: u8 = rand::random();
+    let total = chunks.len() as u8;
+    Ok(chunks.into_iter().enumerate().map(|(i, chunk)| {
+        let mut msg_bytes = vec![5, 0, 3, reference, total, i as u8 + 1];
+        msg_bytes.extend_from_slice(chunk);
+        (1, msg_bytes)
+    }).collect())
+}
+
+/// 一个接收号码的状态
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RecipientStatus {
+    pub dest: String,
+    /// 状态报告的 Stat, 未收到时为空
+    pub stat: String,
+    pub done_time: String,
+}
+
+/// 接口提交的消息状态
+#[derive(Clone, Debug, Serialize)]
+pub struct MessageStatus {
+    pub msg_id: u64,
+    pub sp_id: String,
+    /// CMPP_SUBMIT_RESP 的 result
+    pub result: u32,
+    /// 等待人工审核
+    pub held: bool,
+    /// 提交时间, RFC 3339 格式
+    pub submitted_at: String,
+    pub recipients: Vec<RecipientStatus>,
+}
+
+impl MessageStatus {
+    /// 汇总状态: rejected, held, pending, delivered, failed, partial
+    pub fn state(&self) -> &'static str {
+        if self.result != 0 {
+            return "rejected";
+        }
+        if self.held {
+            return "held";
+        }
+        if self.recipients.iter().any(|r| r.stat.is_empty()) {
+            return "pending";
+        }
+        match self.recipients.iter().filter(|r| r.stat == "DELIVRD").count() {
+            n if n == self.recipients.len() => "delivered",
+            0 => "failed",
+            _ => "partial",
+        }
+    }
+}
+
+/// 跟踪接口提交的消息, 拦截其状态报告
+///
+/// 状态报告更新到对应的接收号码后不再投递给 SP 的 CMPP 会话。
+#[derive(Default)]
+pub struct StatusStore {
+    inner: Mutex<StatusInner>,
+}
+
+#[derive(Default)]
+struct StatusInner {
+    messages: HashMap<u64, MessageStatus>,
+    // 按提交顺序, 用于淘汰
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl StatusStore {
+    pub fn track(&self, status: MessageStatus) {
+        let mut inner = self.inner.lock().unwrap();
+        while let Some(&(msg_id, at)) = inner.order.front() {
+            if inner.order.len() < MAX_TRACKED && at.elapsed() < TRACK_TTL {
+                break;
+            }
+            inner.order.pop_front();
+            inner.messages.remove(&msg_id);
+        }
+        inner.order.push_back((status.msg_id, Instant::now()));
+        inner.messages.insert(status.msg_id, status);
+    }
+
+    /// 记录流水线的处理结果
+    pub fn set_result(&self, msg_id: u64, result: u32, held: bool) {
+        if let Some(status) = self.inner.lock().unwrap().messages.get_mut(&msg_id) {
+            status.result = result;
+            status.held = held;
+        }
+    }
+
+    pub fn get(&self, msg_id: u64) -> Option<MessageStatus> {
+        self.inner.lock().unwrap().messages.get(&msg_id).cloned()
+    }
+}
+
+impl DeliverHook for StatusStore {
+    fn intercept(&self, _sp_id: &str, deliver: Cmpp3DeliverReqPkt) -> Option<Cmpp3DeliverReqPkt> {
+        let report = match deliver.report {
+            Some(ref report) => report,
+            None => return Some(deliver),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let status = match inner.messages.get_mut(&report.msg_id) {
+            Some(status) => status,
+            None => return Some(deliver),
+        };
+
+        // 审核后的状态报告说明已不再等待审核
+        status.held = false;
+        match status.recipients.iter_mut().find(|r| r.dest == report.dest_terminal_id) {
+            Some(recipient) => {
+                recipient.stat = report.stat.clone();
+                recipient.done_time = report.done_time.clone();
+            }
+            None => log::warn!("report for unknown recipient {}, msg_id: {}", report.dest_terminal_id, report.msg_id),
+        }
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::Local;
+
+    use crate::server::cmd::deliver::{Cmpp3DeliverReqPkt, CmppReport};
+    use crate::server::config::Config;
+    use crate::server::message::{MessageStatus, RecipientStatus, SendRequest, StatusStore};
+    use crate::server::session::DeliverHook;
+
+    #[test]
+    fn test_to_submits() {
+        let mut cfg = Config::demo();
+        cfg.accounts[0].src_ids = vec!["10690001".to_string()];
+        let mut req = SendRequest {
+            sp_id: "900001".to_string(),
+            recipients: vec!["13800138000".to_string()],
+            content: "你好".to_string(),
+            msg_fmt: 8,
+            service_id: "svc".to_string(),
+            src_id: None,
+            at_time: String::new(),
+        };
+        let submits = req.to_submits(&cfg.accounts[0]).unwrap();
+        assert_eq!(submits.len(), 1);
+        assert_eq!((submits[0].pk_total, submits[0].pk_number), (1, 1));
+        assert_eq!(submits[0].src_id, "10690001");
+        assert_eq!(submits[0].msg_content, "你好");
+
+        // 100 个汉字拆分为 67 + 33
+        req.content = "好".repeat(100);
+        let submits = req.to_submits(&cfg.accounts[0]).unwrap();
+        assert_eq!(submits.len(), 2);
+        assert_eq!(submits[0].tp_udhi, 1);
+        assert_eq!((submits[0].pk_total, submits[0].pk_number), (2, 1));
+        assert_eq!((submits[1].pk_total, submits[1].pk_number), (2, 2));
+        assert_eq!(submits[0].msg_bytes.len(), 140);
+        assert_eq!(&submits[1].msg_bytes[3..6], &[submits[0].msg_bytes[3], 2, 2]);
+        assert_eq!(submits[1].msg_content, "好".repeat(33));
+    }
+
+    #[test]
+    fn test_status() {
+        let store = StatusStore::default();
+        store.track(MessageStatus {
+            msg_id: 1,
+            sp_id: "900001".to_string(),
+            result: 0,
+            held: false,
+            submitted_at: Local::now().to_rfc3339(),
+            recipients: vec![RecipientStatus { dest: "13800138000".to_string(), stat: String::new(), done_time: String::new() }],
+        });
+        assert_eq!(store.get(1).unwrap().state(), "pending");
+
+        let mut deliver = Cmpp3DeliverReqPkt::new();
+        deliver.report = Some(CmppReport { msg_id: 1, stat: "DELIVRD".to_string(), dest_terminal_id: "13800138000".to_string(), ..Default::default() });
+        assert!(store.intercept("900001", deliver.clone()).is_none());
+        assert_eq!(store.get(1).unwrap().state(), "delivered");
+
+        deliver.report.as_mut().unwrap().msg_id = 2;
+        assert!(store.intercept("900001", deliver).is_some());
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// CMPP_SUBMIT 处理耗时的分桶上限, 单位秒
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// 计数器
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Counter {
+    /// 接受的连接, 标签 listener
+    ConnectionsAccepted,
+    /// 接入保护拒绝的连接, 标签 reason
+    ConnectionsRejected,
+    /// 认证成功的连接, 标签 account
+    ConnectionsAuthenticated,
+    /// 认证失败, 标签 reason
+    AuthFailures,
+    /// 回复的 CMPP_SUBMIT_RESP, 标签 account 和 result
+    Submits,
+    /// 下发的 CMPP_DELIVER, 标签 account
+    DeliversSent,
+    /// 收到的 CMPP_DELIVER_RESP, 标签 account
+    DeliversAcked,
+    /// 等待 CMPP_DELIVER_RESP 超时后重发的 CMPP_DELIVER, 标签 account
+    Retransmissions,
+    /// 无法解析的消息
+    DecodeErrors,
+    /// 会话请求队列已满, 暂停读取, 标签 account
+    WindowSaturation,
+}
+
+impl Counter {
+    fn name(self) -> &'static str {
+        match self {
+            Counter::ConnectionsAccepted => "cmpp_connections_accepted_total",
+            Counter::ConnectionsRejected => "cmpp_connections_rejected_total",
+            Counter::ConnectionsAuthenticated => "cmpp_connections_authenticated_total",
+            Counter::AuthFailures => "cmpp_auth_failures_total",
+            Counter::Submits => "cmpp_submits_total",
+            Counter::DeliversSent => "cmpp_delivers_sent_total",
+            Counter::DeliversAcked => "cmpp_delivers_acked_total",
+            Counter::Retransmissions => "cmpp_deliver_retransmissions_total",
+            Counter::DecodeErrors => "cmpp_decode_errors_total",
+            Counter::WindowSaturation => "cmpp_window_saturation_total",
+        }
+    }
+
+    fn help(self) -> &'static str {
+        match self {
+            Counter::ConnectionsAccepted => "Connections accepted",
+            Counter::ConnectionsRejected => "Connections rejected before CMPP_CONNECT",
+            Counter::ConnectionsAuthenticated => "Connections authenticated",
+            Counter::AuthFailures => "CMPP_CONNECT failures by reason",
+            Counter::Submits => "CMPP_SUBMIT responses by account and result",
+            Counter::DeliversSent => "CMPP_DELIVER sent to SPs",
+            Counter::DeliversAcked => "CMPP_DELIVER_RESP received from SPs",
+            Counter::Retransmissions => "CMPP_DELIVER resent after CMPP_DELIVER_RESP timed out",
+            Counter::DecodeErrors => "Frames or bodies that could not be decoded",
+            Counter::WindowSaturation => "Times a session stopped reading because its request queue was full",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// 运行指标, 以 Prometheus 文本格式输出
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<(Counter, String), u64>>,
+    latency: Mutex<Histogram>,
+}
+
+impl Metrics {
+    pub fn inc(&self, counter: Counter, labels: &[(&str, &str)]) {
+        *self.counters.lock().unwrap().entry((counter, format_labels(labels))).or_default() += 1;
+    }
+
+    /// 计数器的当前值
+    pub fn get(&self, counter: Counter, labels: &[(&str, &str)]) -> u64 {
+        self.counters.lock().unwrap().get(&(counter, format_labels(labels))).copied().unwrap_or(0)
+    }
+
+    /// 记录收到 CMPP_SUBMIT 到回复 CMPP_SUBMIT_RESP 的耗时
+    pub fn observe_latency(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let mut latency = self.latency.lock().unwrap();
+        for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *le {
+                latency.buckets[i] += 1;
+            }
+        }
+        latency.sum += secs;
+        latency.count += 1;
+    }
+
+    /// 输出计数器和直方图
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = self.counters.lock().unwrap();
+        let mut last = None;
+        for ((counter, labels), value) in counters.iter() {
+            if last != Some(*counter) {
+                let _ = writeln!(out, "# HELP {} {}", counter.name(), counter.help());
+                let _ = writeln!(out, "# TYPE {} counter", counter.name());
+                last = Some(*counter);
+            }
+            let _ = writeln!(out, "{}{} {}", counter.name(), braced(labels), value);
+        }
+        drop(counters);
+
+        let name = "cmpp_submit_duration_seconds";
+        let latency = self.latency.lock().unwrap();
+        let _ = writeln!(out, "# HELP {} Time from CMPP_SUBMIT received to CMPP_SUBMIT_RESP sent", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (le, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, latency.count);
+        let _ = writeln!(out, "{}_sum {}", name, latency.sum);
+        let _ = writeln!(out, "{}_count {}", name, latency.count);
+        out
+    }
+}
+
+/// 输出一组 gauge, 样本为 (标签, 值)
+pub fn render_gauge(out: &mut String, name: &str, help: &str, samples: &[(Vec<(&str, &str)>, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, braced(&format_labels(labels)), value);
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    labels.iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::server::metrics::{render_gauge, Counter, Metrics};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.inc(Counter::Submits, &[("account", "900001"), ("result", "0")]);
+        metrics.inc(Counter::Submits, &[("account", "900001"), ("result", "0")]);
+        metrics.inc(Counter::AuthFailures, &[("reason", "a\"b")]);
+        metrics.observe_latency(Duration::from_millis(20));
+        assert_eq!(metrics.get(Counter::Submits, &[("account", "900001"), ("result", "0")]), 2);
+
+        let mut out = metrics.render();
+        assert!(out.contains("# TYPE cmpp_submits_total counter\ncmpp_submits_total{account=\"900001\",result=\"0\"} 2\n"));
+        assert!(out.contains("cmpp_auth_failures_total{reason=\"a\\\"b\"} 1\n"));
+        assert!(out.contains("cmpp_submit_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(out.contains("cmpp_submit_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(out.contains("cmpp_submit_duration_seconds_count 1\n"));
+
+        render_gauge(&mut out, "cmpp_sessions", "Online sessions", &[(vec![("account", "900001")], 1.0)]);
+        assert!(out.ends_with("# TYPE cmpp_sessions gauge\ncmpp_sessions{account=\"900001\"} 1\n"));
+    }
+}
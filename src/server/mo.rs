@@ -0,0 +1,215 @@
+use std::fmt;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::server::blacklist::Blacklist;
+use crate::server::cmd::deliver::Cmpp3DeliverReqPkt;
+use crate::server::cmd::submit::encode_content;
+use crate::server::config::{Config, ConfigRx};
+use crate::server::msgid::MsgIdGen;
+use crate::server::session::Sessions;
+
+// 单条上行的最大长度, ASCII 编码为 160 字节, 其余编码为 140 字节
+const MAX_MSG_LEN: usize = 140;
+const MAX_ASCII_MSG_LEN: usize = 160;
+
+/// 一条上行短信
+#[derive(Clone, Debug, Deserialize)]
+pub struct MoMessage {
+    /// 用户号码
+    pub src_terminal_id: String,
+    /// 接入号, 按 SP 的 `src_ids` 最长前缀确定所属 SP
+    pub dest_id: String,
+    pub content: String,
+    /// 编码, 0 为 ASCII, 8 为 UCS2
+    #[serde(default = "default_msg_fmt")]
+    pub msg_fmt: u8,
+    /// 业务代码, 接入号无法确定 SP 时按 `service_ids` 匹配
+    #[serde(default)]
+    pub service_id: String,
+    #[serde(default)]
+    pub link_id: String,
+}
+
+fn default_msg_fmt() -> u8 {
+    8
+}
+
+/// 上行投递结果
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MoReceipt {
+    pub sp_id: String,
+    pub msg_id: u64,
+    /// 内容为退订指令, 号码已加入 SP 的退订名单
+    pub unsubscribed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MoError {
+    /// 接入号和业务代码都不属于任何 SP
+    UnknownDest(String),
+    /// 内容无法按 Msg_Fmt 编码或超长
+    InvalidContent(String),
+    /// 号码等字段含非 ASCII 字符或超过 CMPP 字段长度
+    InvalidField(&'static str),
+}
+
+impl fmt::Display for MoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoError::UnknownDest(dest_id) => write!(f, "no sp for dest_id: {}", dest_id),
+            MoError::InvalidContent(e) => write!(f, "invalid content: {}", e),
+            MoError::InvalidField(name) => write!(f, "invalid {}", name),
+        }
+    }
+}
+
+impl std::error::Error for MoError {}
+
+/// 上行短信入口, 确定所属 SP 后以 CMPP_DELIVER 投递
+///
+/// 上游网关转来的上行和通过接口注入的上行都经由这里, 退订指令同时加入 SP 的退订名单。
+pub struct MoService {
+    config: ConfigRx,
+    sessions: Arc<Sessions>,
+    blacklist: Option<Arc<Blacklist>>,
+    ids: Arc<MsgIdGen>,
+}
+
+impl MoService {
+    pub fn new(config: ConfigRx, ids: Arc<MsgIdGen>, sessions: Arc<Sessions>, blacklist: Option<Arc<Blacklist>>) -> MoService {
+        MoService { config, sessions, blacklist, ids }
+    }
+
+    /// 注入一条上行
+    pub fn inject(&self, mo: &MoMessage) -> std::result::Result<MoReceipt, MoError> {
+        for (name, value, max_len) in [
+            ("dest_id", &mo.dest_id, 21),
+            ("service_id", &mo.service_id, 10),
+            ("src_terminal_id", &mo.src_terminal_id, 32),
+            ("link_id", &mo.link_id, 20),
+        ] {
+            if !value.is_ascii() || value.len() > max_len {
+                return Err(MoError::InvalidField(name));
+            }
+        }
+        let content = encode_content(mo.msg_fmt, &mo.content).map_err(|e| MoError::InvalidContent(e.to_string()))?;
+        let max_len = if mo.msg_fmt == 0 { MAX_ASCII_MSG_LEN } else { MAX_MSG_LEN };
+        if content.len() > max_len {
+            return Err(MoError::InvalidContent(format!("content too long: {} bytes", content.len())));
+        }
+
+        let mut deliver = Cmpp3DeliverReqPkt::new();
+        deliver.dest_id = mo.dest_id.clone();
+        deliver.service_id = mo.service_id.clone();
+        deliver.msg_fmt = mo.msg_fmt;
+        deliver.src_terminal_id = mo.src_terminal_id.clone();
+        deliver.msg_content = mo.content.clone();
+        deliver.msg_bytes = content;
+        deliver.link_id = mo.link_id.clone();
+        self.deliver(deliver)
+    }
+
+    /// 转发上游网关下发的上行
+    pub(crate) fn on_upstream(&self, upstream: &str, deliver: Cmpp3DeliverReqPkt) {
+        let (src, dest_id) = (deliver.src_terminal_id.clone(), deliver.dest_id.clone());
+        match self.deliver(deliver) {
+            Ok(receipt) => log::info!("mo from upstream {}, src: {}, dest_id: {}, sp_id: {}, msg_id: {}",
+                upstream, src, dest_id, receipt.sp_id, receipt.msg_id),
+            Err(e) => log::warn!("mo from upstream {} dropped, src: {}, {}", upstream, src, e),
+        }
+    }
+
+    fn deliver(&self, mut deliver: Cmpp3DeliverReqPkt) -> std::result::Result<MoReceipt, MoError> {
+        let sp_id = owner(&self.config.borrow(), &deliver.dest_id, &deliver.service_id)
+            .ok_or_else(|| MoError::UnknownDest(deliver.dest_id.clone()))?;
+        let unsubscribed = self.blacklist.as_ref()
+            .is_some_and(|b| b.on_mo(&sp_id, &deliver.src_terminal_id, &deliver.msg_content));
+
+        deliver.msg_id = self.ids.next_id();
+        deliver.register_delivery = 0;
+        deliver.report = None;
+        let receipt = MoReceipt { sp_id, msg_id: deliver.msg_id, unsubscribed };
+        self.sessions.deliver(&receipt.sp_id, deliver);
+        Ok(receipt)
+    }
+}
+
+/// 上行所属的 SP: 接入号按 `src_ids` 最长前缀匹配, 无匹配时按业务代码匹配
+fn owner(cfg: &Config, dest_id: &str, service_id: &str) -> Option<String> {
+    if let Some(account) = cfg.owner_of(dest_id) {
+        return Some(account.sp_id.clone());
+    }
+    if service_id.is_empty() {
+        return None;
+    }
+    cfg.accounts.iter().find(|a| a.service_ids.iter().any(|s| s == service_id)).map(|a| a.sp_id.clone())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::server::cmd::Command;
+    use crate::server::config::{Config, ConfigHandle};
+    use crate::server::mo::{MoError, MoMessage, MoService};
+    use crate::server::msgid::MsgIdGen;
+    use crate::server::session::Sessions;
+
+    fn mo(dest_id: &str, content: &str, msg_fmt: u8) -> MoMessage {
+        MoMessage {
+            src_terminal_id: "13800138000".to_string(),
+            dest_id: dest_id.to_string(),
+            content: content.to_string(),
+            msg_fmt,
+            service_id: String::new(),
+            link_id: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inject() {
+        let mut cfg = Config::demo();
+        cfg.accounts[0].src_ids = vec!["1069".to_string()];
+        cfg.accounts[0].service_ids = vec!["svc".to_string()];
+        let handle = ConfigHandle::new(cfg);
+        let sessions = Arc::new(Sessions::default());
+        let mo_service = MoService::new(handle.subscribe(), Arc::new(MsgIdGen::new(1)), sessions.clone(), None);
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let _guard = sessions.register("900001", "127.0.0.1:5000".parse().unwrap(), tx);
+
+        let receipt = mo_service.inject(&mo("10690001123", "你好", 8)).unwrap();
+        assert_eq!(receipt.sp_id, "900001");
+        match rx.try_recv() {
+            Ok(Command::DeliverReq(d)) => {
+                assert_eq!(d.msg_id, receipt.msg_id);
+                assert_eq!(d.register_delivery, 0);
+                assert_eq!(d.msg_bytes, vec![0x4f, 0x60, 0x59, 0x7d]);
+            }
+            other => panic!("unexpected: {:?}", other),
+        }
+
+        // 按业务代码匹配
+        let mut msg = mo("95555", "hi", 0);
+        msg.service_id = "svc".to_string();
+        assert_eq!(mo_service.inject(&msg).unwrap().sp_id, "900001");
+
+        assert!(matches!(mo_service.inject(&mo("95555", "hi", 0)), Err(MoError::UnknownDest(_))));
+        assert!(matches!(mo_service.inject(&mo("1069", "你好", 0)), Err(MoError::InvalidContent(_))));
+
+        // 超长或含非 ASCII 字符的字段在编码前拒绝
+        assert_eq!(mo_service.inject(&mo("1069000000000000000001", "hi", 0)), Err(MoError::InvalidField("dest_id")));
+        let mut msg = mo("10690001", "hi", 0);
+        msg.src_terminal_id = "号码".to_string();
+        assert_eq!(mo_service.inject(&msg), Err(MoError::InvalidField("src_terminal_id")));
+        let mut msg = mo("10690001", "hi", 0);
+        msg.service_id = "s".repeat(11);
+        assert_eq!(mo_service.inject(&msg), Err(MoError::InvalidField("service_id")));
+        let mut msg = mo("10690001", "hi", 0);
+        msg.link_id = "l".repeat(21);
+        assert_eq!(mo_service.inject(&msg), Err(MoError::InvalidField("link_id")));
+    }
+}
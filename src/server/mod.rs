@@ -1,15 +1,68 @@
+#[allow(clippy::module_inception)]
 pub mod server;
 mod config;
 
+mod acl;
 mod conn;
+mod limit;
+mod proxy;
+mod shutdown;
+mod store;
+mod tls;
+mod transport;
 mod error;
 mod codec;
 pub mod cmd;
 mod handler;
+mod pipeline;
+mod validate;
+mod msgid;
+mod session;
+mod upstream;
+mod router;
+mod keyword;
+mod review;
+mod blacklist;
+mod mo;
+mod message;
+mod metrics;
+mod logging;
+mod cdr;
+mod billing;
+mod schedule;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "http")]
+mod webhook;
 
-pub use self::config::{Config};
+pub use self::acl::{ip_allowed, Cidr};
+pub use self::config::{Account, BillingConfig, BlacklistAction, BlacklistConfig, CdrConfig, CdrFormat, CdrRotate, Config,
+                       ConfigHandle, ConfigRx, HttpConfig, KeywordAction, KeywordConfig, ListenerConfig, LogConfig,
+                       LogFormat, TlsConfig, UnknownCommandPolicy, UpstreamConfig, WebhookConfig, WebhookTarget};
 pub use self::error::IoError;
-pub use self::conn::{Conn};
+pub use self::pipeline::{Flow, Phase, Pipeline, RouteDecision, SubmitContext, SubmitStage, ATTR_HELD, ATTR_MSG_ID};
+pub use self::router::{Channel, RouteConfig, RouteFile, RouteMatch, RouteTable, Router};
+pub use self::blacklist::{Blacklist, ATTR_BLOCKED, STAT_BLOCKED};
+#[cfg(feature = "http")]
+pub use self::http::HttpApi;
+#[cfg(feature = "http")]
+pub use self::webhook::Webhooks;
+pub use self::logging::init_logger;
+pub use self::billing::{Billing, Charge, ChargedParty, Fee, FeeType, Usage, ATTR_CHARGE};
+pub use self::cdr::{Cdr, CdrRecord};
+pub use self::metrics::{render_gauge, Counter, Metrics};
+pub use self::message::{MessageStatus, RecipientStatus, SendRequest, StatusStore};
+pub use self::mo::{MoError, MoMessage, MoReceipt, MoService};
+pub use self::keyword::{KeywordEngine, KeywordFilter};
+pub use self::review::{HeldSubmit, Reviews};
+pub use self::schedule::{parse_time, Scheduler};
+pub use self::validate::{validate_submit, SubmitValidator};
+pub use self::msgid::MsgIdGen;
+pub use self::session::{DeliverHook, SessionInfo, Sessions};
+pub use self::upstream::{Dispatcher, ATTR_UPSTREAM};
+pub use self::conn::{Conn, PeerInfo, SessionState};
+pub use self::transport::{BoxedTransport, Transport};
+pub use self::store::load_reports;
 pub use self::codec::{CmppMessage, CmppDecoder, CmppEncoder};
 
 
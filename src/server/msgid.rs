@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use chrono::{Datelike, Local, Timelike};
+
+/// 按 CMPP 3.0 规范生成 Msg_Id
+///
+/// 64 位依次为: 月(4) 日(5) 时(5) 分(6) 秒(6), 网关代码(22), 序列号(16)。
+#[derive(Debug)]
+pub struct MsgIdGen {
+    ismg_id: u32,
+    seq: AtomicU32,
+}
+
+impl MsgIdGen {
+    pub fn new(ismg_id: u32) -> MsgIdGen {
+        MsgIdGen {
+            ismg_id: ismg_id & 0x3F_FFFF,
+            seq: AtomicU32::new(0),
+        }
+    }
+
+    pub fn next_id(&self) -> u64 {
+        let now = Local::now();
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) & 0xFFFF;
+        (now.month() as u64) << 60
+            | (now.day() as u64) << 55
+            | (now.hour() as u64) << 50
+            | (now.minute() as u64) << 44
+            | (now.second() as u64) << 38
+            | (self.ismg_id as u64) << 16
+            | seq as u64
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::server::msgid::MsgIdGen;
+
+    #[test]
+    fn test_next_id() {
+        let ids = MsgIdGen::new(0x12345);
+        let first = ids.next_id();
+        let second = ids.next_id();
+        assert_eq!((first >> 16) & 0x3F_FFFF, 0x12345);
+        assert_eq!(second & 0xFFFF, (first & 0xFFFF) + 1);
+        assert!((1..=12).contains(&(first >> 60)));
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::server::cmd::submit::Cmpp3SubmitReqPkt;
+
+/// 处理阶段, 同一阶段内按注册顺序执行
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Phase {
+    Validation,
+    Filtering,
+    Billing,
+    Routing,
+    Persistence,
+    Dispatch,
+}
+
+/// 阶段处理结果
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    /// 继续执行后续阶段
+    Continue,
+    /// 中止处理, 以给定的 result 回复 CMPP_SUBMIT_RESP
+    Reject(u32),
+    /// 暂停处理, 以成功回复 CMPP_SUBMIT_RESP, 之后由 `Pipeline::resume` 继续
+    Hold,
+}
+
+/// 被暂停处理的消息带有此属性, 值为暂停原因, 未设置时为暂停的环节名称
+pub const ATTR_HELD: &str = "held";
+
+/// 已由网关分配回复给 SP 的 Msg_Id, 后续环节沿用 `SubmitContext::msg_id`
+pub const ATTR_MSG_ID: &str = "msg_id";
+
+/// 一条 CMPP_SUBMIT 在流水线中的上下文
+#[derive(Debug, Clone)]
+pub struct SubmitContext {
+    /// 提交消息的账号
+    pub sp_id: String,
+    pub peer: SocketAddr,
+    pub submit: Cmpp3SubmitReqPkt,
+    /// 回复给 SP 的 Msg_Id, 默认沿用请求中的值
+    pub msg_id: u64,
+    /// 路由环节按接收号码分组选择的上游, 为空时使用默认上游
+    pub routes: Vec<RouteDecision>,
+    /// 阶段之间传递的附加信息
+    pub attrs: HashMap<String, String>,
+    // 正在执行的环节在流水线中的位置, 被暂停的消息从下一个环节继续
+    stage: usize,
+}
+
+/// 一组接收号码的路由结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteDecision {
+    /// 匹配的路由名称
+    pub route: Option<String>,
+    pub dests: Vec<String>,
+    /// 依次尝试的上游网关
+    pub upstreams: Vec<String>,
+}
+
+impl SubmitContext {
+    pub fn new(sp_id: &str, peer: SocketAddr, submit: Cmpp3SubmitReqPkt) -> SubmitContext {
+        SubmitContext {
+            sp_id: sp_id.to_string(),
+            peer,
+            msg_id: submit.msg_id,
+            submit,
+            routes: vec![],
+            attrs: HashMap::new(),
+            stage: 0,
+        }
+    }
+}
+
+/// 流水线中的一个处理环节
+#[async_trait]
+pub trait SubmitStage: Send + Sync {
+    /// 用于日志的名称
+    fn name(&self) -> &str;
+
+    async fn process(&self, ctx: &mut SubmitContext) -> Flow;
+
+    /// 已通过本环节的消息最终未发送, 如被之后的环节拒绝, `stat` 为记录的最终状态
+    ///
+    /// 用于撤销本环节的副作用, 如退还预付费、写入话单。本环节未执行时也可能被调用, 须自行判断。
+    async fn cancel(&self, _ctx: &mut SubmitContext, _stat: &str) {}
+}
+
+/// CMPP_SUBMIT 处理流水线
+///
+/// 按 `Phase` 的顺序执行已注册的环节, 任一环节返回 `Flow::Reject` 时中止, 并按相反顺序通知
+/// 之前的环节, 最终状态为 `RJ:` 加四位的 result。
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    stages: Vec<(Phase, Arc<dyn SubmitStage>)>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// 注册一个环节
+    pub fn stage<S: SubmitStage + 'static>(mut self, phase: Phase, stage: S) -> Pipeline {
+        self.add(phase, Arc::new(stage));
+        self
+    }
+
+    pub fn add(&mut self, phase: Phase, stage: Arc<dyn SubmitStage>) {
+        // 插入到同阶段已注册环节之后
+        let pos = self.stages.partition_point(|(p, _)| *p <= phase);
+        self.stages.insert(pos, (phase, stage));
+    }
+
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// 执行流水线, 返回 CMPP_SUBMIT_RESP 的 result, 0 表示成功
+    pub async fn process(&self, ctx: &mut SubmitContext) -> u32 {
+        self.run(ctx, 0).await
+    }
+
+    /// 从暂停消息的环节之后继续执行, `ctx` 须为该环节暂停时保存的上下文
+    pub async fn resume(&self, ctx: &mut SubmitContext) -> u32 {
+        self.run(ctx, ctx.stage + 1).await
+    }
+
+    /// 被暂停的消息最终未发送时, 按相反顺序通知 `upto` 及之前阶段的环节, 如定时消息过期、审核拒绝
+    pub async fn cancel(&self, ctx: &mut SubmitContext, upto: Phase, stat: &str) {
+        let end = self.stages.partition_point(|(p, _)| *p <= upto);
+        for (_, stage) in self.stages[..end].iter().rev() {
+            stage.cancel(ctx, stat).await;
+        }
+    }
+
+    async fn run(&self, ctx: &mut SubmitContext, start: usize) -> u32 {
+        for (i, (phase, stage)) in self.stages.iter().enumerate().skip(start) {
+            ctx.stage = i;
+            match stage.process(ctx).await {
+                Flow::Continue => {}
+                Flow::Reject(result) => {
+                    log::info!("submit rejected by {:?}/{}, sp_id: {}, msg_id: {}, result: {}",
+                        phase, stage.name(), ctx.sp_id, ctx.msg_id, result);
+                    // 暂停前执行过的环节也需要通知
+                    let stat = format!("RJ:{:04}", result);
+                    for (_, stage) in self.stages[..i].iter().rev() {
+                        stage.cancel(ctx, &stat).await;
+                    }
+                    return result;
+                }
+                Flow::Hold => {
+                    log::info!("submit held by {:?}/{}, sp_id: {}, msg_id: {}",
+                        phase, stage.name(), ctx.sp_id, ctx.msg_id);
+                    ctx.attrs.entry(ATTR_HELD.to_string()).or_insert_with(|| stage.name().to_string());
+                    return 0;
+                }
+            }
+        }
+        0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use crate::server::cmd::submit::Cmpp3SubmitReqPkt;
+    use crate::server::pipeline::{Flow, Phase, Pipeline, SubmitContext, SubmitStage, ATTR_HELD};
+
+    struct Mark(&'static str, Option<u32>);
+
+    #[async_trait]
+    impl SubmitStage for Mark {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+            let trace = ctx.attrs.entry("trace".to_string()).or_default();
+            trace.push_str(self.0);
+            match self.1 {
+                Some(result) => Flow::Reject(result),
+                None => Flow::Continue,
+            }
+        }
+
+        async fn cancel(&self, ctx: &mut SubmitContext, stat: &str) {
+            let trace = ctx.attrs.entry("cancel".to_string()).or_insert_with(|| stat.to_string());
+            trace.push_str(self.0);
+        }
+    }
+
+    struct Pause;
+
+    #[async_trait]
+    impl SubmitStage for Pause {
+        fn name(&self) -> &str {
+            "pause"
+        }
+
+        async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+            ctx.attrs.entry("trace".to_string()).or_default().push('p');
+            Flow::Hold
+        }
+    }
+
+    fn new_ctx() -> SubmitContext {
+        SubmitContext::new("900001", "127.0.0.1:5000".parse().unwrap(), Cmpp3SubmitReqPkt::default())
+    }
+
+    #[tokio::test]
+    async fn test_pipeline() {
+        let pipeline = Pipeline::new()
+            .stage(Phase::Dispatch, Mark("d", None))
+            .stage(Phase::Validation, Mark("v", None))
+            .stage(Phase::Filtering, Mark("f1", None))
+            .stage(Phase::Filtering, Mark("f2", None));
+        let mut ctx = new_ctx();
+        assert_eq!(pipeline.process(&mut ctx).await, 0);
+        assert_eq!(ctx.attrs["trace"], "vf1f2d");
+
+        let pipeline = pipeline.stage(Phase::Billing, Mark("b", Some(9)));
+        let mut ctx = new_ctx();
+        assert_eq!(pipeline.process(&mut ctx).await, 9);
+        assert_eq!(ctx.attrs["trace"], "vf1f2b");
+        assert_eq!(ctx.attrs["cancel"], "RJ:0009f2f1v");
+
+        // 从暂停的环节之后继续, 同阶段之后的环节也会执行
+        let pipeline = Pipeline::new()
+            .stage(Phase::Validation, Mark("v", None))
+            .stage(Phase::Filtering, Mark("f1", None))
+            .stage(Phase::Filtering, Pause)
+            .stage(Phase::Filtering, Mark("f2", None))
+            .stage(Phase::Billing, Mark("b", Some(9)));
+        let mut ctx = new_ctx();
+        assert_eq!(pipeline.process(&mut ctx).await, 0);
+        assert_eq!(ctx.attrs["trace"], "vf1p");
+        assert_eq!(ctx.attrs[ATTR_HELD], "pause");
+        assert_eq!(pipeline.resume(&mut ctx).await, 9);
+        assert_eq!(ctx.attrs["trace"], "vf1pf2b");
+        assert_eq!(ctx.attrs["cancel"], "RJ:0009f2f1v");
+
+        let mut ctx = new_ctx();
+        pipeline.cancel(&mut ctx, Phase::Filtering, "EXPIRED").await;
+        assert_eq!(ctx.attrs["cancel"], "EXPIREDf2f1v");
+    }
+}
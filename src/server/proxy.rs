@@ -0,0 +1,125 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::server::Result;
+
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+const V1_PREFIX: &[u8] = b"PROXY ";
+// v1 头部最大长度, 包含结尾的 CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// 读取 HAProxy PROXY protocol v1/v2 头部, 返回真实的客户端地址
+///
+/// 头部声明为 LOCAL/UNKNOWN 时返回 None, 调用方应继续使用 socket 的对端地址。
+/// 只读取头部本身, 不会多读后续的 CMPP 数据。
+pub(crate) async fn read_proxy_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // v1 最短为 "PROXY UNKNOWN\r\n", v2 签名为 12 字节, 先读 12 字节判断版本
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head).await?;
+
+    if head == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+    if head.starts_with(V1_PREFIX) {
+        return read_v1(stream, &head).await;
+    }
+    Err("missing PROXY protocol header".into())
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, head: &[u8]) -> Result<Option<SocketAddr>> {
+    let mut line = head.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err("PROXY v1 header too long".into());
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse()?;
+            let port: u16 = src_port.parse()?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(format!("invalid PROXY v1 header: {}", line).into()),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let ver_cmd = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    if ver_cmd >> 4 != 2 {
+        return Err(format!("unsupported PROXY version: {}", ver_cmd >> 4).into());
+    }
+    // LOCAL 命令为负载均衡器自身的健康检查
+    if ver_cmd & 0x0F == 0 {
+        return Ok(None);
+    }
+
+    match family {
+        // TCP over IPv4
+        0x11 if len >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // TCP over IPv6
+        0x21 if len >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        _ => Ok(None),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use crate::server::proxy::{read_proxy_header, V2_SIGNATURE};
+
+    #[tokio::test]
+    async fn test_proxy_v1() {
+        let mut data: &[u8] = b"PROXY TCP4 10.1.2.3 10.0.0.1 40000 7890\r\n\x00\x00\x00\x27";
+        let addr = read_proxy_header(&mut data).await.unwrap();
+        assert_eq!(addr, Some("10.1.2.3:40000".parse().unwrap()));
+        // 头部之后的数据保持不变
+        assert_eq!(data.read_u32().await.unwrap(), 0x27);
+
+        let mut data: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut data).await.unwrap(), None);
+
+        let mut data: &[u8] = b"\x00\x00\x00\x27\x00\x00\x00\x01\x00\x00\x00\x01";
+        assert!(read_proxy_header(&mut data).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C]);
+        header.extend_from_slice(&[192, 168, 1, 9, 10, 0, 0, 1]);
+        header.extend_from_slice(&50000u16.to_be_bytes());
+        header.extend_from_slice(&7890u16.to_be_bytes());
+
+        let mut data = header.as_slice();
+        let addr = read_proxy_header(&mut data).await.unwrap();
+        assert_eq!(addr, Some("192.168.1.9:50000".parse().unwrap()));
+        assert!(data.is_empty());
+
+        // LOCAL
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(read_proxy_header(&mut header.as_slice()).await.unwrap(), None);
+    }
+}
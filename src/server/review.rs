@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use chrono::{DateTime, Local};
+
+use crate::server::msgid::MsgIdGen;
+use crate::server::pipeline::{Phase, Pipeline, SubmitContext, ATTR_HELD, ATTR_MSG_ID};
+use crate::server::session::Sessions;
+
+// 最多暂存等待审核的消息数
+const MAX_HELD: usize = 10_000;
+// 审核拒绝的状态报告
+const STAT_REJECTD: &str = "REJECTD";
+
+/// 等待人工审核的消息
+#[derive(Clone, Debug)]
+pub struct HeldSubmit {
+    pub ctx: SubmitContext,
+    /// 暂停的阶段, 审核拒绝时通知此阶段及之前的环节
+    pub phase: Phase,
+    /// 暂停原因, 如命中的敏感词
+    pub reason: String,
+    pub held_at: DateTime<Local>,
+}
+
+/// 人工审核队列
+///
+/// 处理环节调用 `hold` 后返回 `Flow::Hold`, SP 收到成功响应; 审核通过后从暂停的环节之后
+/// 继续执行流水线, 未转发上游时下发 DELIVRD; 拒绝时按 SP 的要求下发 REJECTD 状态报告。
+pub struct Reviews {
+    ids: Arc<MsgIdGen>,
+    held: Mutex<BTreeMap<u64, HeldSubmit>>,
+    // Server 运行时设置, 流水线持有本队列, 这里只保留弱引用
+    pipeline: Mutex<Weak<Pipeline>>,
+    sessions: Arc<Sessions>,
+}
+
+impl Reviews {
+    pub fn new(ids: Arc<MsgIdGen>, sessions: Arc<Sessions>) -> Reviews {
+        Reviews {
+            ids,
+            held: Mutex::new(BTreeMap::new()),
+            pipeline: Mutex::new(Weak::new()),
+            sessions,
+        }
+    }
+
+    /// 设置审核通过后继续执行的流水线
+    pub fn attach(&self, pipeline: &Arc<Pipeline>) {
+        *self.pipeline.lock().unwrap() = Arc::downgrade(pipeline);
+    }
+
+    /// 暂存消息并分配回复给 SP 的 Msg_Id (已分配时沿用), 队列已满时返回 false
+    pub fn hold(&self, ctx: &mut SubmitContext, phase: Phase, reason: &str) -> bool {
+        let mut held = self.held.lock().unwrap();
+        if held.len() >= MAX_HELD {
+            log::warn!("too many held submits, sp_id: {}", ctx.sp_id);
+            return false;
+        }
+        if !ctx.attrs.contains_key(ATTR_MSG_ID) {
+            ctx.msg_id = self.ids.next_id();
+            ctx.attrs.insert(ATTR_MSG_ID.to_string(), "review".to_string());
+        }
+        ctx.attrs.insert(ATTR_HELD.to_string(), reason.to_string());
+        held.insert(ctx.msg_id, HeldSubmit {
+            ctx: ctx.clone(),
+            phase,
+            reason: reason.to_string(),
+            held_at: Local::now(),
+        });
+        true
+    }
+
+    /// 等待审核的消息, 按 Msg_Id 排序
+    pub fn list(&self) -> Vec<HeldSubmit> {
+        self.held.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held.lock().unwrap().is_empty()
+    }
+
+    /// 审核通过, 继续执行流水线, 返回处理结果; 消息不存在或流水线未运行时返回 None
+    pub async fn approve(&self, msg_id: u64) -> Option<u32> {
+        let pipeline = self.pipeline.lock().unwrap().upgrade()?;
+        let mut held = self.held.lock().unwrap().remove(&msg_id)?;
+        held.ctx.attrs.remove(ATTR_HELD);
+        let result = pipeline.resume(&mut held.ctx).await;
+        log::info!("held submit approved, sp_id: {}, msg_id: {}, result: {}", held.ctx.sp_id, msg_id, result);
+        if result != 0 {
+            self.sessions.report(&held.ctx, &format!("RV:{:04}", result));
+        } else {
+            self.sessions.delivered(&held.ctx);
+        }
+        Some(result)
+    }
+
+    /// 审核拒绝, 通知暂停前执行过的环节 (如退还扣费), 消息不存在时返回 false
+    pub async fn reject(&self, msg_id: u64) -> bool {
+        let mut held = match self.held.lock().unwrap().remove(&msg_id) {
+            Some(held) => held,
+            None => return false,
+        };
+        log::info!("held submit rejected, sp_id: {}, msg_id: {}", held.ctx.sp_id, msg_id);
+        let pipeline = self.pipeline.lock().unwrap().upgrade();
+        if let Some(pipeline) = pipeline {
+            pipeline.cancel(&mut held.ctx, held.phase, STAT_REJECTD).await;
+        }
+        self.sessions.report(&held.ctx, STAT_REJECTD);
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+
+    use crate::server::cmd::submit::Cmpp3SubmitReqPkt;
+    use crate::server::pipeline::{Flow, Phase, Pipeline, SubmitContext, SubmitStage, ATTR_HELD};
+    use crate::server::msgid::MsgIdGen;
+    use crate::server::review::Reviews;
+    use crate::server::session::Sessions;
+
+    struct Hold(Arc<Reviews>);
+
+    #[async_trait]
+    impl SubmitStage for Hold {
+        fn name(&self) -> &str {
+            "hold"
+        }
+
+        async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+            self.0.hold(ctx, Phase::Filtering, "test");
+            Flow::Hold
+        }
+    }
+
+    struct Dispatch;
+
+    #[async_trait]
+    impl SubmitStage for Dispatch {
+        fn name(&self) -> &str {
+            "dispatch"
+        }
+
+        async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+            ctx.attrs.insert("dispatched".to_string(), String::new());
+            Flow::Continue
+        }
+    }
+
+    #[tokio::test]
+    async fn test_review() {
+        let sessions = Arc::new(Sessions::default());
+        let reviews = Arc::new(Reviews::new(Arc::new(MsgIdGen::new(1)), sessions.clone()));
+        let pipeline = Arc::new(Pipeline::new()
+            .stage(Phase::Filtering, Hold(reviews.clone()))
+            .stage(Phase::Dispatch, Dispatch));
+        reviews.attach(&pipeline);
+
+        let mut submit = Cmpp3SubmitReqPkt::default();
+        submit.registered_delivery = 1;
+        submit.dest_terminal_id = vec!["13800138000".to_string()];
+        let peer = "127.0.0.1:5000".parse().unwrap();
+
+        let mut ctx = SubmitContext::new("900001", peer, submit.clone());
+        assert_eq!(pipeline.process(&mut ctx).await, 0);
+        assert_eq!(ctx.attrs[ATTR_HELD], "test");
+        assert!(!ctx.attrs.contains_key("dispatched"));
+        assert_eq!(reviews.len(), 1);
+
+        assert_eq!(reviews.approve(ctx.msg_id).await, Some(0));
+        assert_eq!(reviews.approve(ctx.msg_id).await, None);
+        assert!(reviews.is_empty());
+        // 未转发上游时由本网关下发 DELIVRD
+        assert_eq!(sessions.queued("900001"), 1);
+
+        let mut ctx = SubmitContext::new("900001", peer, submit);
+        pipeline.process(&mut ctx).await;
+        assert!(reviews.reject(ctx.msg_id).await);
+        assert_eq!(sessions.queued("900001"), 2);
+    }
+}
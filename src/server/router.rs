@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::server::config::ConfigRx;
+use crate::server::pipeline::{Flow, RouteDecision, SubmitContext, SubmitStage};
+use crate::server::Result;
+
+/// 路由文件
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RouteFile {
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+}
+
+/// 一条路由, 按号段前缀匹配接收号码
+#[derive(Clone, Debug, Deserialize)]
+pub struct RouteConfig {
+    pub name: String,
+    /// 号段前缀, 支持 "134-139" 形式的范围, "" 匹配所有号码
+    pub prefixes: Vec<String>,
+    /// 只对该 SP 生效, 优先于全局路由
+    #[serde(default)]
+    pub sp_id: Option<String>,
+    /// 按权重分配的上游通道
+    #[serde(default)]
+    pub channels: Vec<Channel>,
+    /// 所有通道不可用时依次尝试的上游
+    #[serde(default)]
+    pub failover: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Channel {
+    pub upstream: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// 号码的路由结果
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteMatch {
+    pub route: String,
+    pub prefix: String,
+    pub sp_id: Option<String>,
+}
+
+/// 加载后的路由表
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    routes: Vec<RouteConfig>,
+    // 前缀 -> 路由下标
+    global: HashMap<String, usize>,
+    per_sp: HashMap<(String, String), usize>,
+}
+
+impl RouteTable {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<RouteTable> {
+        let content = std::fs::read_to_string(path.as_ref())?;
+        let file: RouteFile = toml::from_str(&content)?;
+        RouteTable::new(file.routes)
+    }
+
+    pub fn new(routes: Vec<RouteConfig>) -> Result<RouteTable> {
+        let mut table = RouteTable::default();
+        for (idx, route) in routes.iter().enumerate() {
+            if route.channels.is_empty() && route.failover.is_empty() {
+                return Err(format!("route {} has no upstream", route.name).into());
+            }
+            for prefix in &route.prefixes {
+                for prefix in expand_prefix(prefix)? {
+                    let dup = match route.sp_id {
+                        Some(ref sp_id) => table.per_sp.insert((sp_id.clone(), prefix.clone()), idx),
+                        None => table.global.insert(prefix.clone(), idx),
+                    };
+                    if let Some(other) = dup {
+                        return Err(format!("prefix {} in both {} and {}", prefix, routes[other].name, route.name).into());
+                    }
+                }
+            }
+        }
+        table.routes = routes;
+        Ok(table)
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    fn lookup<'a>(&'a self, sp_id: Option<&str>, msisdn: &'a str) -> Option<(&'a RouteConfig, &'a str)> {
+        let number = normalize(msisdn);
+        if let Some(sp_id) = sp_id {
+            for len in (0..=number.len()).rev() {
+                if let Some(idx) = self.per_sp.get(&(sp_id.to_string(), number[..len].to_string())) {
+                    return Some((&self.routes[*idx], &number[..len]));
+                }
+            }
+        }
+        for len in (0..=number.len()).rev() {
+            if let Some(idx) = self.global.get(&number[..len]) {
+                return Some((&self.routes[*idx], &number[..len]));
+            }
+        }
+        None
+    }
+
+    /// 号码匹配的路由
+    pub fn which(&self, sp_id: Option<&str>, msisdn: &str) -> Option<RouteMatch> {
+        self.lookup(sp_id, msisdn).map(|(route, prefix)| RouteMatch {
+            route: route.name.clone(),
+            prefix: prefix.to_string(),
+            sp_id: route.sp_id.clone(),
+        })
+    }
+
+    /// 号码依次尝试的上游: 按权重随机排列的通道, 然后是故障转移列表
+    pub fn upstreams(&self, sp_id: Option<&str>, msisdn: &str) -> Vec<String> {
+        let route = match self.lookup(sp_id, msisdn) {
+            Some((route, _)) => route,
+            None => return vec![],
+        };
+
+        let mut rng = rand::thread_rng();
+        let mut channels: Vec<&Channel> = route.channels.iter().filter(|c| c.weight > 0).collect();
+        let mut upstreams = Vec::with_capacity(channels.len() + route.failover.len());
+        while !channels.is_empty() {
+            let total: u32 = channels.iter().map(|c| c.weight).sum();
+            let mut pick = rng.gen_range(0..total);
+            let idx = channels.iter().position(|c| {
+                if pick < c.weight {
+                    return true;
+                }
+                pick -= c.weight;
+                false
+            }).unwrap_or(0);
+            upstreams.push(channels.remove(idx).upstream.clone());
+        }
+        for upstream in &route.failover {
+            if !upstreams.contains(upstream) {
+                upstreams.push(upstream.clone());
+            }
+        }
+        upstreams
+    }
+}
+
+/// 去掉国家码 86 / +86
+pub(crate) fn normalize(msisdn: &str) -> &str {
+    let number = msisdn.strip_prefix('+').unwrap_or(msisdn);
+    match number.strip_prefix("86") {
+        Some(rest) if rest.len() == 11 => rest,
+        _ => number,
+    }
+}
+
+/// 展开 "134-139" 形式的号段范围
+fn expand_prefix(prefix: &str) -> Result<Vec<String>> {
+    let (start, end) = match prefix.split_once('-') {
+        Some(range) => range,
+        None => return Ok(vec![prefix.to_string()]),
+    };
+    if start.len() != end.len() {
+        return Err(format!("invalid prefix range: {}", prefix).into());
+    }
+    let (from, to): (u64, u64) = (start.parse()?, end.parse()?);
+    if from > to || to - from > 10_000 {
+        return Err(format!("invalid prefix range: {}", prefix).into());
+    }
+    Ok((from..=to).map(|n| format!("{:0width$}", n, width = start.len())).collect())
+}
+
+/// 路由环节, 为每个接收号码选择上游网关, 注册在 `Phase::Routing`
+///
+/// 配置中 `routes` 指定的文件在配置热加载时重新读取。
+pub struct Router {
+    table: RwLock<Arc<RouteTable>>,
+}
+
+impl Router {
+    /// 加载路由文件, 并在配置变化时重新加载, 需在 tokio 运行时中调用
+    pub fn start(mut config: ConfigRx) -> Result<Arc<Router>> {
+        let table = match config.borrow_and_update().routes {
+            Some(ref path) => RouteTable::from_file(path)?,
+            None => RouteTable::default(),
+        };
+        let router = Arc::new(Router { table: RwLock::new(Arc::new(table)) });
+
+        let weak = Arc::downgrade(&router);
+        tokio::spawn(async move {
+            while config.changed().await.is_ok() {
+                let router = match weak.upgrade() {
+                    Some(router) => router,
+                    None => return,
+                };
+                let path = config.borrow_and_update().routes.clone();
+                let res = match path {
+                    Some(ref path) => RouteTable::from_file(path),
+                    None => Ok(RouteTable::default()),
+                };
+                match res {
+                    Ok(table) => {
+                        log::info!("routes reloaded, {} routes", table.len());
+                        router.set_table(table);
+                    }
+                    Err(e) => log::error!("reload routes failed, keep old routes: {}", e),
+                }
+            }
+        });
+        Ok(router)
+    }
+
+    pub fn with_table(table: RouteTable) -> Router {
+        Router { table: RwLock::new(Arc::new(table)) }
+    }
+
+    pub fn set_table(&self, table: RouteTable) {
+        *self.table.write().unwrap() = Arc::new(table);
+    }
+
+    pub fn table(&self) -> Arc<RouteTable> {
+        self.table.read().unwrap().clone()
+    }
+
+    /// 查询号码会走的路由
+    pub fn which(&self, sp_id: Option<&str>, msisdn: &str) -> Option<RouteMatch> {
+        self.table().which(sp_id, msisdn)
+    }
+}
+
+#[async_trait]
+impl SubmitStage for Router {
+    fn name(&self) -> &str {
+        "router"
+    }
+
+    async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+        let table = self.table();
+        if table.is_empty() {
+            return Flow::Continue;
+        }
+
+        // 同一路由的号码合并为一组
+        let mut routes: Vec<RouteDecision> = Vec::new();
+        for dest in &ctx.submit.dest_terminal_id {
+            let upstreams = table.upstreams(Some(&ctx.sp_id), dest);
+            let route = table.which(Some(&ctx.sp_id), dest).map(|m| m.route);
+            match routes.iter_mut().find(|r| r.route == route) {
+                Some(decision) => decision.dests.push(dest.clone()),
+                None => routes.push(RouteDecision { route, dests: vec![dest.clone()], upstreams }),
+            }
+        }
+        ctx.routes = routes;
+        Flow::Continue
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::server::router::{RouteFile, RouteTable};
+
+    const ROUTES: &str = r#"
+[[routes]]
+name = "cmcc"
+prefixes = ["134-139", "150-152", "188"]
+channels = [{ upstream = "cmcc-a", weight = 3 }, { upstream = "cmcc-b" }]
+failover = ["backup"]
+
+[[routes]]
+name = "cmcc-1380"
+prefixes = ["1380"]
+channels = [{ upstream = "cmcc-c" }]
+
+[[routes]]
+name = "vip"
+prefixes = ["13"]
+sp_id = "900001"
+channels = [{ upstream = "vip" }]
+
+[[routes]]
+name = "default"
+prefixes = [""]
+failover = ["backup"]
+"#;
+
+    fn table() -> RouteTable {
+        let file: RouteFile = toml::from_str(ROUTES).unwrap();
+        RouteTable::new(file.routes).unwrap()
+    }
+
+    #[test]
+    fn test_which() {
+        let table = table();
+        let m = table.which(None, "13912345678").unwrap();
+        assert_eq!((m.route.as_str(), m.prefix.as_str()), ("cmcc", "139"));
+        assert_eq!(table.which(None, "+8613801380000").unwrap().route, "cmcc-1380");
+        assert_eq!(table.which(None, "18812345678").unwrap().route, "cmcc");
+        assert_eq!(table.which(None, "17712345678").unwrap().route, "default");
+        // SP 专属路由优先
+        assert_eq!(table.which(Some("900001"), "13912345678").unwrap().route, "vip");
+        assert_eq!(table.which(Some("900001"), "18812345678").unwrap().route, "cmcc");
+    }
+
+    #[test]
+    fn test_upstreams() {
+        let table = table();
+        let upstreams = table.upstreams(None, "13912345678");
+        assert_eq!(upstreams.len(), 3);
+        assert!(upstreams[..2].contains(&"cmcc-a".to_string()));
+        assert_eq!(upstreams[2], "backup");
+        assert_eq!(table.upstreams(None, "17712345678"), vec!["backup"]);
+
+        let file: RouteFile = toml::from_str(&format!("{}{}", ROUTES, "\n[[routes]]\nname = \"dup\"\nprefixes = [\"135\"]\nchannels = [{ upstream = \"x\" }]\n")).unwrap();
+        assert!(RouteTable::new(file.routes).is_err());
+    }
+}
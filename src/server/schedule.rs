@@ -0,0 +1,371 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset, Local, Months, NaiveDate, TimeDelta, TimeZone};
+use tokio::sync::Notify;
+
+use crate::server::cmd::{ERRNO_SUBMIT_FLOW_CONTROL, ERRNO_SUBMIT_STRUCTURE};
+use crate::server::msgid::MsgIdGen;
+use crate::server::pipeline::{Flow, Phase, Pipeline, SubmitContext, SubmitStage, ATTR_HELD, ATTR_MSG_ID};
+use crate::server::session::Sessions;
+use crate::server::Result;
+
+// 最多暂存等待定时发送的消息数
+const MAX_SCHEDULED: usize = 100_000;
+// 没有到期消息时检查的间隔
+const IDLE_WAIT: Duration = Duration::from_secs(60);
+// 超过 Valid_Time 的状态报告
+const STAT_EXPIRED: &str = "EXPIRED";
+// 停机时取消发送的状态报告
+const STAT_DELETED: &str = "DELETED";
+
+/// 解析 CMPP 时间格式, 空字符串返回 None
+///
+/// 绝对时间为 YYMMDDhhmmsstnnp, t 为十分之一秒, nn 为与 UTC 相差的刻钟数, p 为 `+` 或 `-`;
+/// 相对时间为 YYMMDDhhmmss000R, 表示从 `now` 起的时长。
+pub fn parse_time(time: &str, now: DateTime<Local>) -> Result<Option<DateTime<Local>>> {
+    let time = time.trim_end_matches('\0').trim();
+    if time.is_empty() {
+        return Ok(None);
+    }
+    let invalid = || format!("invalid time: {}", time);
+    if time.len() != 16 || !time.is_ascii() {
+        return Err(invalid().into());
+    }
+    let field = |start: usize, len: usize| time[start..start + len].parse::<u32>().map_err(|_| invalid());
+    let (yy, mm, dd, hh, mi, ss) = (field(0, 2)?, field(2, 2)?, field(4, 2)?, field(6, 2)?, field(8, 2)?, field(10, 2)?);
+    let (tenths, quarters) = (field(12, 1)?, field(13, 2)?);
+
+    let at = match &time[15..] {
+        "R" => now.checked_add_months(Months::new(yy * 12 + mm))
+            .and_then(|t| t.checked_add_signed(TimeDelta::try_days(dd as i64)?))
+            .map(|t| t + TimeDelta::seconds((hh * 3600 + mi * 60 + ss) as i64)),
+        sign @ ("+" | "-") if quarters <= 48 => {
+            let offset = (quarters * 15 * 60) as i32;
+            let offset = if sign == "+" { FixedOffset::east_opt(offset) } else { FixedOffset::west_opt(offset) };
+            NaiveDate::from_ymd_opt(2000 + yy as i32, mm, dd)
+                .and_then(|d| d.and_hms_milli_opt(hh, mi, ss, tenths * 100))
+                .zip(offset)
+                .and_then(|(t, offset)| offset.from_local_datetime(&t).single())
+                .map(|t| t.with_timezone(&Local))
+        }
+        _ => None,
+    };
+    at.map(Some).ok_or_else(|| invalid().into())
+}
+
+/// 定时发送的消息
+struct Scheduled {
+    ctx: SubmitContext,
+    // 超过后不再发送, 下发 EXPIRED 状态报告
+    valid_until: Option<DateTime<Local>>,
+}
+
+/// 定时发送, 注册在 `Phase::Persistence`
+///
+/// At_Time 晚于当前时间的消息回复成功后暂存, 到期后从 `Phase::Persistence` 之后继续执行流水线;
+/// 超过 Valid_Time 的消息不再发送, 按 SP 的要求下发 EXPIRED 状态报告。
+/// 暂存的消息不会保存, 停机时由 `cancel_all` 取消。
+pub struct Scheduler {
+    ids: Arc<MsgIdGen>,
+    // 按 (到期时间, Msg_Id) 排序, 到期时间为 At_Time 和 Valid_Time 中较早的一个
+    scheduled: Mutex<BTreeMap<(DateTime<Local>, u64), Scheduled>>,
+    wake: Arc<Notify>,
+    // Server 运行时设置, 流水线持有本环节, 这里只保留弱引用
+    pipeline: Mutex<Weak<Pipeline>>,
+    sessions: Arc<Sessions>,
+}
+
+impl Scheduler {
+    /// 创建并在到期时发送暂存的消息, 需在 tokio 运行时中调用
+    pub fn start(ids: Arc<MsgIdGen>, sessions: Arc<Sessions>) -> Arc<Scheduler> {
+        let scheduler = Arc::new(Scheduler {
+            ids,
+            scheduled: Mutex::new(BTreeMap::new()),
+            wake: Arc::new(Notify::new()),
+            pipeline: Mutex::new(Weak::new()),
+            sessions,
+        });
+        tokio::spawn(Self::run(Arc::downgrade(&scheduler)));
+        scheduler
+    }
+
+    /// 设置到期后继续执行的流水线
+    pub fn attach(&self, pipeline: &Arc<Pipeline>) {
+        *self.pipeline.lock().unwrap() = Arc::downgrade(pipeline);
+    }
+
+    /// 等待定时发送的消息数
+    pub fn len(&self) -> usize {
+        self.scheduled.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scheduled.lock().unwrap().is_empty()
+    }
+
+    /// 停机时取消所有等待发送的消息, 返回取消的消息数
+    ///
+    /// 通知 `pipeline` 中之前的环节 (如退还扣费、写入话单), 并按 SP 的要求下发 DELETED 状态报告,
+    /// 状态报告暂存在会话表中, 随其他未投递的状态报告一起保存。
+    pub async fn cancel_all(&self, pipeline: &Pipeline) -> usize {
+        let scheduled = std::mem::take(&mut *self.scheduled.lock().unwrap());
+        let count = scheduled.len();
+        for (_, mut item) in scheduled {
+            let ctx = &mut item.ctx;
+            log::info!("scheduled submit deleted, sp_id: {}, msg_id: {}", ctx.sp_id, ctx.msg_id);
+            pipeline.cancel(ctx, Phase::Persistence, STAT_DELETED).await;
+            self.sessions.report(ctx, STAT_DELETED);
+        }
+        count
+    }
+
+    async fn run(scheduler: Weak<Scheduler>) {
+        loop {
+            let scheduler = match scheduler.upgrade() {
+                Some(scheduler) => scheduler,
+                None => return,
+            };
+            for item in scheduler.take_due(Local::now()) {
+                scheduler.release(item).await;
+            }
+
+            let wait = scheduler.scheduled.lock().unwrap().keys().next()
+                .map(|(at, _)| (*at - Local::now()).to_std().unwrap_or_default().min(IDLE_WAIT))
+                .unwrap_or(IDLE_WAIT);
+            let wake = scheduler.wake.clone();
+            let notified = wake.notified();
+            drop(scheduler);
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = notified => {}
+            }
+        }
+    }
+
+    fn take_due(&self, now: DateTime<Local>) -> Vec<Scheduled> {
+        let mut scheduled = self.scheduled.lock().unwrap();
+        let mut due = vec![];
+        while let Some(entry) = scheduled.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            due.push(entry.remove());
+        }
+        due
+    }
+
+    /// 到期的消息: 超过有效期时下发 EXPIRED, 否则继续执行流水线, 未转发上游时下发 DELIVRD
+    async fn release(&self, mut item: Scheduled) {
+        let ctx = &mut item.ctx;
+        if item.valid_until.is_some_and(|valid| valid <= Local::now()) {
+            log::info!("scheduled submit expired, sp_id: {}, msg_id: {}", ctx.sp_id, ctx.msg_id);
+            self.expire(ctx).await;
+            return;
+        }
+        let pipeline = match self.pipeline.lock().unwrap().upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        ctx.attrs.remove(ATTR_HELD);
+        let result = pipeline.resume(ctx).await;
+        log::info!("scheduled submit released, sp_id: {}, msg_id: {}, result: {}", ctx.sp_id, ctx.msg_id, result);
+        if result != 0 {
+            self.sessions.report(ctx, &format!("SC:{:04}", result));
+        } else {
+            self.sessions.delivered(ctx);
+        }
+    }
+
+    // 过期不再发送: 通知之前的环节 (如退还扣费、写入话单), 并下发 EXPIRED 状态报告
+    async fn expire(&self, ctx: &mut SubmitContext) {
+        let pipeline = self.pipeline.lock().unwrap().upgrade();
+        if let Some(pipeline) = pipeline {
+            pipeline.cancel(ctx, Phase::Persistence, STAT_EXPIRED).await;
+        }
+        self.sessions.report(ctx, STAT_EXPIRED);
+    }
+
+    // 分配回复给 SP 的 Msg_Id (已分配时沿用), 并标记为暂停
+    fn hold(&self, ctx: &mut SubmitContext, reason: &str) {
+        if !ctx.attrs.contains_key(ATTR_MSG_ID) {
+            ctx.msg_id = self.ids.next_id();
+            ctx.attrs.insert(ATTR_MSG_ID.to_string(), "schedule".to_string());
+        }
+        ctx.attrs.insert(ATTR_HELD.to_string(), reason.to_string());
+    }
+}
+
+#[async_trait]
+impl SubmitStage for Scheduler {
+    fn name(&self) -> &str {
+        "schedule"
+    }
+
+    async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+        let now = Local::now();
+        let (at, valid_until) = match (parse_time(&ctx.submit.at_time, now), parse_time(&ctx.submit.valid_time, now)) {
+            (Ok(at), Ok(valid_until)) => (at, valid_until),
+            (Err(e), _) | (_, Err(e)) => {
+                log::warn!("invalid schedule, sp_id: {}, {}", ctx.sp_id, e);
+                return Flow::Reject(ERRNO_SUBMIT_STRUCTURE);
+            }
+        };
+
+        if valid_until.is_some_and(|valid| valid <= now) {
+            self.hold(ctx, STAT_EXPIRED);
+            log::info!("submit expired, sp_id: {}, msg_id: {}", ctx.sp_id, ctx.msg_id);
+            self.expire(ctx).await;
+            return Flow::Hold;
+        }
+        let at = match at {
+            Some(at) if at > now => at,
+            _ => return Flow::Continue,
+        };
+
+        let mut scheduled = self.scheduled.lock().unwrap();
+        if scheduled.len() >= MAX_SCHEDULED {
+            log::warn!("too many scheduled submits, sp_id: {}", ctx.sp_id);
+            return Flow::Reject(ERRNO_SUBMIT_FLOW_CONTROL);
+        }
+        self.hold(ctx, "schedule");
+        let due = valid_until.map_or(at, |valid| valid.min(at));
+        scheduled.insert((due, ctx.msg_id), Scheduled { ctx: ctx.clone(), valid_until });
+        log::info!("submit scheduled at {}, sp_id: {}, msg_id: {}", at.to_rfc3339(), ctx.sp_id, ctx.msg_id);
+        self.wake.notify_one();
+        Flow::Hold
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use chrono::{Local, TimeDelta, TimeZone, Utc};
+
+    use crate::server::cmd::Command;
+    use crate::server::cmd::submit::Cmpp3SubmitReqPkt;
+    use crate::server::pipeline::{Flow, Phase, Pipeline, SubmitContext, SubmitStage, ATTR_HELD};
+    use crate::server::msgid::MsgIdGen;
+    use crate::server::schedule::{parse_time, Scheduler};
+    use crate::server::session::Sessions;
+
+    #[test]
+    fn test_parse_time() {
+        let now = Local::now();
+        assert_eq!(parse_time("", now).unwrap(), None);
+
+        let at = parse_time("240102130405532+", now).unwrap().unwrap();
+        assert_eq!(at.with_timezone(&Utc), Utc.with_ymd_and_hms(2024, 1, 2, 5, 4, 5).unwrap() + TimeDelta::milliseconds(500));
+        let at = parse_time("240102130405004-", now).unwrap().unwrap();
+        assert_eq!(at.with_timezone(&Utc), Utc.with_ymd_and_hms(2024, 1, 2, 14, 4, 5).unwrap());
+
+        let at = parse_time("000001020304000R", now).unwrap().unwrap();
+        assert_eq!(at - now, TimeDelta::seconds(86400 + 2 * 3600 + 3 * 60 + 4));
+
+        assert!(parse_time("2401021304", now).is_err());
+        assert!(parse_time("241302130405032+", now).is_err());
+        assert!(parse_time("240102130405049+", now).is_err());
+        assert!(parse_time("240102130405032x", now).is_err());
+    }
+
+    struct Dispatch;
+
+    #[async_trait]
+    impl SubmitStage for Dispatch {
+        fn name(&self) -> &str {
+            "dispatch"
+        }
+
+        async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+            ctx.attrs.insert("dispatched".to_string(), String::new());
+            if ctx.submit.dest_terminal_id[0] == "13800138001" {
+                return Flow::Continue;
+            }
+            Flow::Reject(9)
+        }
+    }
+
+    // 记录收到的撤销通知
+    struct Charge;
+
+    #[async_trait]
+    impl SubmitStage for Charge {
+        fn name(&self) -> &str {
+            "charge"
+        }
+
+        async fn process(&self, _ctx: &mut SubmitContext) -> Flow {
+            Flow::Continue
+        }
+
+        async fn cancel(&self, ctx: &mut SubmitContext, stat: &str) {
+            ctx.attrs.insert("cancel".to_string(), stat.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_schedule() {
+        let sessions = Arc::new(Sessions::default());
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let _guard = sessions.register("900001", "127.0.0.1:5000".parse().unwrap(), tx);
+        let scheduler = Scheduler::start(Arc::new(MsgIdGen::new(1)), sessions);
+        let mut pipeline = Pipeline::new().stage(Phase::Billing, Charge).stage(Phase::Dispatch, Dispatch);
+        pipeline.add(Phase::Persistence, scheduler.clone());
+        let pipeline = Arc::new(pipeline);
+        scheduler.attach(&pipeline);
+
+        let new_ctx = |at_time: &str, valid_time: &str| {
+            let mut submit = Cmpp3SubmitReqPkt::default();
+            submit.registered_delivery = 1;
+            submit.dest_terminal_id = vec!["13800138000".to_string()];
+            submit.at_time = at_time.to_string();
+            submit.valid_time = valid_time.to_string();
+            SubmitContext::new("900001", "127.0.0.1:5000".parse().unwrap(), submit)
+        };
+
+        // 已过有效期
+        let mut ctx = new_ctx("", "240102130405032+");
+        assert_eq!(pipeline.process(&mut ctx).await, 0);
+        assert_eq!(ctx.attrs[ATTR_HELD], "EXPIRED");
+        assert_eq!(ctx.attrs["cancel"], "EXPIRED");
+        assert!(matches!(rx.try_recv(), Ok(Command::DeliverReq(d)) if d.report.as_ref().unwrap().stat == "EXPIRED"));
+
+        // 未指定 At_Time 时立即发送
+        let mut ctx = new_ctx("", "000001000000000R");
+        assert_eq!(pipeline.process(&mut ctx).await, 9);
+        assert_eq!(ctx.attrs["cancel"], "RJ:0009");
+
+        // 到期后继续执行, 发送失败时下发状态报告
+        let mut ctx = new_ctx("000000000001000R", "");
+        assert_eq!(pipeline.process(&mut ctx).await, 0);
+        assert!(!ctx.attrs.contains_key("dispatched"));
+        assert_eq!(scheduler.len(), 1);
+        let deliver = tokio::time::timeout(Duration::from_secs(3), rx.recv()).await.unwrap();
+        assert!(matches!(deliver, Some(Command::DeliverReq(d)) if d.report.as_ref().unwrap().stat == "SC:0009"
+            && d.report.as_ref().unwrap().msg_id == ctx.msg_id));
+        assert!(scheduler.is_empty());
+
+        // 到期后发送成功, 未转发上游时下发 DELIVRD
+        let mut ctx = new_ctx("000000000001000R", "");
+        ctx.submit.dest_terminal_id = vec!["13800138001".to_string()];
+        assert_eq!(pipeline.process(&mut ctx).await, 0);
+        let deliver = tokio::time::timeout(Duration::from_secs(3), rx.recv()).await.unwrap();
+        assert!(matches!(deliver, Some(Command::DeliverReq(d)) if d.report.as_ref().unwrap().stat == "DELIVRD"
+            && d.report.as_ref().unwrap().msg_id == ctx.msg_id));
+        assert!(scheduler.is_empty());
+
+        // 停机时取消未到期的消息
+        let mut ctx = new_ctx("000001000000000R", "");
+        assert_eq!(pipeline.process(&mut ctx).await, 0);
+        assert_eq!(scheduler.cancel_all(&pipeline).await, 1);
+        assert!(scheduler.is_empty());
+        assert!(matches!(rx.try_recv(), Ok(Command::DeliverReq(d)) if d.report.as_ref().unwrap().stat == "DELETED"
+            && d.report.as_ref().unwrap().msg_id == ctx.msg_id));
+    }
+}
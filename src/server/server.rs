@@ -1,27 +1,425 @@
+use std::collections::HashMap;
 use std::net::{SocketAddr};
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 use crate::server::Result;
-use log::{error, info};
+use log::{error, info, warn};
 use tokio::{io, time};
 use tokio::net::{TcpListener, TcpStream};
-use super::{Config, Conn};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use super::{BoxedTransport, Config, ConfigHandle, ConfigRx, Conn, PeerInfo, Phase, Pipeline, SubmitStage};
+use super::{Billing, Blacklist, Cdr, Counter, Dispatcher, KeywordFilter, Metrics, MoService, MsgIdGen, Reviews, Router, Scheduler, Sessions, StatusStore,
+            SubmitValidator};
+#[cfg(feature = "http")]
+use super::Webhooks;
+#[cfg(feature = "http")]
+use super::http::{ApiState, HttpApi};
+use super::{proxy, tls};
+use super::acl::AcceptGuard;
+use super::cmd::deliver::Cmpp3DeliverReqPkt;
+use super::store::{load_reports, save_reports};
+
+// TLS 握手超时时间
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// 读取 PROXY protocol 头部的超时时间
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
 
 
 pub struct Server {
-    cfg: Config,
-    listener: TcpListener,
+    cfg: ConfigHandle,
+    listeners: Vec<BoundListener>,
+    // 所有端口共享的接入保护
+    guard: Arc<AcceptGuard>,
+    // CMPP_SUBMIT 处理流水线
+    pipeline: Pipeline,
+    // 已认证的会话
+    sessions: Arc<Sessions>,
+    // 号段路由
+    router: Arc<Router>,
+    // 所有组件共享的 Msg_Id 生成器
+    #[cfg(feature = "http")]
+    ids: Arc<MsgIdGen>,
+    // 人工审核队列
+    reviews: Arc<Reviews>,
+    // 定时发送的消息
+    scheduler: Arc<Scheduler>,
+    // 接收号码黑白名单
+    blacklist: Arc<Blacklist>,
+    // 上行短信入口
+    mo: Arc<MoService>,
+    // 计费和预付费余额
+    billing: Arc<Billing>,
+    // 接口提交的消息状态
+    statuses: Arc<StatusStore>,
+    // 运行指标
+    metrics: Arc<Metrics>,
+    // 上游网关, 未配置时为空
+    dispatcher: Option<Arc<Dispatcher>>,
+    #[cfg(feature = "http")]
+    webhooks: Arc<Webhooks>,
+    #[cfg(feature = "http")]
+    http: Option<HttpApi>,
+
+    /// Broadcasts a shutdown signal to all active connections.
+    ///
+    /// When `shutdown` is called, the sender is dropped and every `Conn`
+    /// starts draining: pending responses are flushed, CMPP_TERMINATE is sent
+    /// and unacknowledged reports are handed back to `sessions`, which are
+    /// saved to `report_store` once all sessions have ended.
+    notify_shutdown: broadcast::Sender<()>,
+
+    /// Used as part of the graceful shutdown process to wait for client
+    /// connections to complete processing.
+    ///
+    /// Every spawned connection task holds a clone of the sender. Once all
+    /// of them are dropped, `recv()` on the receiver returns `None`.
+    shutdown_complete_tx: mpsc::Sender<()>,
+    shutdown_complete_rx: mpsc::Receiver<()>,
+}
+
+/// A bound listening socket and its TLS acceptor.
+struct BoundListener {
+    addr: String,
+    listener: Arc<TcpListener>,
+    tls: Option<TlsAcceptor>,
+}
+
+/// Accept loop of a single listening socket.
+struct Listener {
+    addr: String,
+    listener: Arc<TcpListener>,
+    tls: Option<TlsAcceptor>,
+    // 连接建立后先读取 PROXY protocol 头部
+    proxy_protocol: bool,
+    cfg: ConfigHandle,
+    // 用于感知配置热加载, 重新加载证书
+    config: ConfigRx,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+    // 当前连接数
+    connections: Arc<AtomicUsize>,
+    guard: Arc<AcceptGuard>,
+    pipeline: Arc<Pipeline>,
+    sessions: Arc<Sessions>,
+    metrics: Arc<Metrics>,
 }
 
 impl Server {
+    /// 绑定配置中的所有监听端口, 任一端口绑定失败时返回错误
     pub async fn new(cfg: Config) -> io::Result<Server> {
-        let addr = SocketAddr::from_str(&cfg.addr).unwrap();
-        let listener = TcpListener::bind(addr).await.unwrap();
-        let svr = Server { cfg, listener };
+        let mut listeners = Vec::new();
+        for listener in cfg.listeners() {
+            let addr = SocketAddr::from_str(&listener.addr).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("invalid listen addr {}: {}", listener.addr, e))
+            })?;
+            let socket = TcpListener::bind(addr).await.map_err(|e| {
+                io::Error::new(e.kind(), format!("bind {} failed: {}", listener.addr, e))
+            })?;
+            let tls = match listener.tls {
+                Some(ref tls) => Some(tls::build_acceptor(tls).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("load tls for {} failed: {}", listener.addr, e))
+                })?),
+                None => None,
+            };
+            listeners.push(BoundListener { addr: listener.addr, listener: Arc::new(socket), tls });
+        }
+
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
+        let cfg = ConfigHandle::new(cfg);
+        // 同一 ISMG_Id 下只能有一个序号, 否则各组件生成的 Msg_Id 会重复
+        let ids = Arc::new(MsgIdGen::new(cfg.current().ismg_id));
+        let sessions = Arc::new(Sessions::new(ids.clone()));
+        if let Some(ref path) = cfg.current().report_store {
+            restore_reports(path, &sessions);
+        }
+        let router = Router::start(cfg.subscribe()).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("load routes failed: {}", e))
+        })?;
+        let reviews = Arc::new(Reviews::new(ids.clone(), sessions.clone()));
+        let keywords = KeywordFilter::start(cfg.subscribe(), reviews.clone()).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("load keywords failed: {}", e))
+        })?;
+        let blacklist = Blacklist::start(cfg.subscribe()).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("load blacklist failed: {}", e))
+        })?;
+        let billing = Billing::start(cfg.subscribe()).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("load balances failed: {}", e))
+        })?;
+        let mo = Arc::new(MoService::new(cfg.subscribe(), ids.clone(), sessions.clone(), Some(blacklist.clone())));
+        // 话单须在其他拦截之前看到状态报告
+        let cdr = Cdr::start(cfg.subscribe(), ids.clone());
+        sessions.add_hook(cdr.clone());
+        let statuses = Arc::new(StatusStore::default());
+        sessions.add_hook(statuses.clone());
+        #[cfg(feature = "http")]
+        let webhooks = Webhooks::start(cfg.subscribe());
+        #[cfg(feature = "http")]
+        sessions.add_hook(webhooks.clone());
+        #[cfg(not(feature = "http"))]
+        if cfg.current().accounts.iter().any(|a| a.webhook.is_some()) {
+            warn!("webhook requires the `http` feature, ignored");
+        }
+        let mut pipeline = Pipeline::new().stage(Phase::Validation, SubmitValidator::new(cfg.subscribe()));
+        pipeline.add(Phase::Filtering, blacklist.clone());
+        pipeline.add(Phase::Filtering, keywords);
+        pipeline.add(Phase::Billing, billing.clone());
+        pipeline.add(Phase::Routing, router.clone());
+        pipeline.add(Phase::Persistence, cdr);
+        let scheduler = Scheduler::start(ids.clone(), sessions.clone());
+        pipeline.add(Phase::Persistence, scheduler.clone());
+        let dispatcher = if cfg.current().upstreams.is_empty() {
+            None
+        } else {
+            Some(Dispatcher::start(&cfg.current(), ids.clone(), sessions.clone(), Some(mo.clone())))
+        };
+        if let Some(ref dispatcher) = dispatcher {
+            pipeline.add(Phase::Dispatch, dispatcher.clone());
+        }
+        #[cfg(feature = "http")]
+        let http = match cfg.current().http {
+            Some(ref http) => Some(HttpApi::bind(&http.addr).await?),
+            None => None,
+        };
+        if cfg.current().accounts.is_empty() {
+            warn!("no accounts configured, all logins will be rejected");
+        }
+        #[cfg(not(feature = "http"))]
+        if cfg.current().http.is_some() {
+            warn!("http api requires the `http` feature, ignored");
+        }
+        let svr = Server {
+            cfg,
+            listeners,
+            guard: Arc::new(AcceptGuard::default()),
+            pipeline,
+            sessions,
+            router,
+            #[cfg(feature = "http")]
+            ids,
+            reviews,
+            scheduler,
+            blacklist,
+            mo,
+            billing,
+            statuses,
+            metrics: Arc::default(),
+            dispatcher,
+            #[cfg(feature = "http")]
+            webhooks,
+            #[cfg(feature = "http")]
+            http,
+            notify_shutdown,
+            shutdown_complete_tx,
+            shutdown_complete_rx,
+        };
         Ok(svr)
     }
 
-    async fn accept(&mut self) -> Result<TcpStream> {
+    /// 运行中配置的句柄, 可用于热加载账号和限流配置
+    pub fn config_handle(&self) -> ConfigHandle {
+        self.cfg.clone()
+    }
+
+    /// 已认证的会话, 可用于向 SP 投递 CMPP_DELIVER
+    pub fn sessions(&self) -> Arc<Sessions> {
+        self.sessions.clone()
+    }
+
+    /// 号段路由, 可用于查询号码会走的路由
+    pub fn router(&self) -> Arc<Router> {
+        self.router.clone()
+    }
+
+    /// 人工审核队列, 可用于放行或拒绝被暂停的消息
+    pub fn reviews(&self) -> Arc<Reviews> {
+        self.reviews.clone()
+    }
+
+    /// 定时发送队列, 可用于查询等待发送的消息数
+    pub fn scheduler(&self) -> Arc<Scheduler> {
+        self.scheduler.clone()
+    }
+
+    /// 接收号码黑白名单, 可用于查询和维护退订名单
+    pub fn blacklist(&self) -> Arc<Blacklist> {
+        self.blacklist.clone()
+    }
+
+    /// 上行短信入口, 可用于向 SP 注入上行
+    pub fn mo(&self) -> Arc<MoService> {
+        self.mo.clone()
+    }
+
+    /// 计费, 可用于查询用量和为预付费账号充值
+    pub fn billing(&self) -> Arc<Billing> {
+        self.billing.clone()
+    }
+
+    /// 上游网关, 可用于查询连接数和提交队列长度, 未配置上游时为空
+    pub fn dispatcher(&self) -> Option<Arc<Dispatcher>> {
+        self.dispatcher.clone()
+    }
+
+    /// 运行指标, 可用 `Metrics::render` 输出 Prometheus 文本格式
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// 接口提交的消息状态, 可按 Msg_Id 查询
+    pub fn statuses(&self) -> Arc<StatusStore> {
+        self.statuses.clone()
+    }
+
+    /// 注册 CMPP_SUBMIT 的处理环节, 需在 `run` 之前调用
+    pub fn with_stage<S: SubmitStage + 'static>(mut self, phase: Phase, stage: S) -> Server {
+        self.pipeline.add(phase, Arc::new(stage));
+        self
+    }
+
+    /// 替换整个处理流水线, 默认的字段校验环节也会被替换
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Server {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// 在所有端口上接收连接, 任一端口持续 accept 失败时返回错误
+    pub async fn run(&mut self) -> Result<()> {
+        // Dropping the set (e.g. when `run` is cancelled) aborts every accept loop.
+        let mut accept_loops = JoinSet::new();
+        let pipeline = Arc::new(self.pipeline.clone());
+        self.reviews.attach(&pipeline);
+        self.scheduler.attach(&pipeline);
+        for bound in &self.listeners {
+            info!("start cmpp server, addr: {}, tls: {}", bound.addr, bound.tls.is_some());
+            let mut listener = Listener {
+                addr: bound.addr.clone(),
+                listener: bound.listener.clone(),
+                tls: bound.tls.clone(),
+                proxy_protocol: self.cfg.current().listener(&bound.addr).is_some_and(|l| l.proxy_protocol),
+                cfg: self.cfg.clone(),
+                config: self.cfg.subscribe(),
+                notify_shutdown: self.notify_shutdown.clone(),
+                shutdown_complete_tx: self.shutdown_complete_tx.clone(),
+                connections: Arc::new(AtomicUsize::new(0)),
+                guard: self.guard.clone(),
+                pipeline: pipeline.clone(),
+                sessions: self.sessions.clone(),
+                metrics: self.metrics.clone(),
+            };
+            accept_loops.spawn(async move { listener.run().await });
+        }
+        #[cfg(feature = "http")]
+        if let Some(ref http) = self.http {
+            let http = http.clone();
+            let state = ApiState {
+                config: self.cfg.clone(),
+                mo: self.mo.clone(),
+                billing: self.billing.clone(),
+                pipeline: pipeline.clone(),
+                statuses: self.statuses.clone(),
+                ids: self.ids.clone(),
+                sessions: self.sessions.clone(),
+                reviews: self.reviews.clone(),
+                scheduler: self.scheduler.clone(),
+                webhooks: self.webhooks.clone(),
+                dispatcher: self.dispatcher.clone(),
+                metrics: self.metrics.clone(),
+            };
+            accept_loops.spawn(async move { http.run(state).await });
+        }
+
+        while let Some(res) = accept_loops.join_next().await {
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => return Err(e),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// 通知所有会话停机并等待其退出, 最长等待 `shutdown_timeout` 秒
+    ///
+    /// 调用前需先停止 `run`, 不再接收新的连接。
+    pub async fn shutdown(self) {
+        let Server {
+            cfg,
+            billing,
+            sessions,
+            scheduler,
+            pipeline,
+            notify_shutdown,
+            shutdown_complete_tx,
+            mut shutdown_complete_rx,
+            ..
+        } = self;
+
+        let timeout = Duration::from_secs(cfg.current().shutdown_timeout);
+
+        // Dropping the sender notifies every `Conn`, and dropping our own
+        // completion sender leaves only the ones held by connection tasks.
+        drop(notify_shutdown);
+        drop(shutdown_complete_tx);
+
+        if time::timeout(timeout, shutdown_complete_rx.recv()).await.is_err() {
+            warn!("sessions still active after {}s, exit anyway", timeout.as_secs());
+        } else {
+            info!("all sessions closed");
+        }
+        // 定时消息不会保存, 取消后下发失败报告, 并在保存余额前退还扣费
+        let deleted = scheduler.cancel_all(&pipeline).await;
+        if deleted > 0 {
+            warn!("cancel {} scheduled submits", deleted);
+        }
+        billing.save();
+
+        // 会话结束时已交还未确认的状态报告
+        let reports = sessions.take_queued();
+        match cfg.current().report_store {
+            Some(ref path) => match save_reports(path, &reports) {
+                Ok(()) => info!("save {} undelivered reports to {}", reports.len(), path.display()),
+                Err(e) => error!("save {} undelivered reports failed: {}", reports.len(), e),
+            },
+            None if !reports.is_empty() => warn!("drop {} undelivered reports, report_store not configured", reports.len()),
+            None => {}
+        }
+    }
+}
+
+/// 读取上次停机时保存的状态报告, 放入会话表等待 SP 登录后补发
+fn restore_reports(path: &Path, sessions: &Sessions) {
+    if !path.exists() {
+        return;
+    }
+    let reports = match load_reports(path) {
+        Ok(reports) => reports,
+        Err(e) => {
+            error!("load undelivered reports from {} failed: {}", path.display(), e);
+            return;
+        }
+    };
+    info!("load {} undelivered reports from {}", reports.len(), path.display());
+    let mut by_sp: HashMap<String, Vec<Cmpp3DeliverReqPkt>> = HashMap::new();
+    for (sp_id, deliver) in reports {
+        if sp_id.is_empty() {
+            warn!("drop stored report without sp_id, msg_id: {}", deliver.msg_id);
+            continue;
+        }
+        by_sp.entry(sp_id).or_default().push(deliver);
+    }
+    for (sp_id, delivers) in by_sp {
+        sessions.requeue(&sp_id, delivers);
+    }
+}
+
+impl Listener {
+    async fn accept(&self) -> Result<TcpStream> {
         let mut backoff = 1;
 
         // Try to accept a few times
@@ -46,26 +444,150 @@ impl Server {
         }
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        info!("start cmpp server, addr: {}", &self.cfg.addr);
+    /// 配置热加载后重新读取证书, 失败时继续使用原证书
+    fn reload_tls(&mut self) {
+        if !self.config.has_changed().unwrap_or(false) {
+            return;
+        }
+
+        let cfg = self.config.borrow_and_update().clone();
+        match (cfg.listener(&self.addr).and_then(|l| l.tls), self.tls.is_some()) {
+            (Some(tls_cfg), true) => match tls::build_acceptor(&tls_cfg) {
+                Ok(acceptor) => {
+                    info!("tls certificates reloaded, listener: {}", self.addr);
+                    self.tls = Some(acceptor);
+                }
+                Err(e) => error!("reload tls for {} failed, keep old certificates: {}", self.addr, e),
+            },
+            (None, false) => {}
+            _ => warn!("tls mode change requires restart, listener: {}", self.addr),
+        }
+    }
 
+    /// 读取 PROXY protocol 头部, 用真实的客户端地址替换 `peer.addr`
+    async fn read_proxy(socket: &mut TcpStream, peer: &mut PeerInfo) -> Result<()> {
+        match time::timeout(PROXY_HEADER_TIMEOUT, proxy::read_proxy_header(socket)).await {
+            Ok(Ok(Some(addr))) => {
+                info!("proxied client: {}, via: {}", addr, peer.addr);
+                peer.addr = addr;
+                Ok(())
+            }
+            Ok(Ok(None)) => Ok(()),
+            Ok(Err(e)) => Err(format!("read PROXY header failed: {}", e).into()),
+            Err(_) => Err("read PROXY header timeout".into()),
+        }
+    }
+
+    /// 完成传输层握手, 返回会话使用的字节流
+    async fn handshake(acceptor: Option<TlsAcceptor>, socket: TcpStream, peer: &mut PeerInfo) -> Result<BoxedTransport> {
+        let acceptor = match acceptor {
+            Some(acceptor) => acceptor,
+            None => return Ok(Box::new(socket)),
+        };
+
+        let stream = match time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => return Err(format!("tls handshake failed: {}", e).into()),
+            Err(_) => return Err("tls handshake timeout".into()),
+        };
+        peer.cert = tls::peer_fingerprint(&stream);
+        Ok(Box::new(stream))
+    }
+
+    async fn run(&mut self) -> Result<()> {
         loop {
             let socket = self.accept().await?;
-            let client_addr = socket.peer_addr().unwrap().to_string();
-            info!("accept client: {}", client_addr.to_string());
+            // 对端在 accept 之后已重置连接时只丢弃这个连接
+            let peer_addr = match socket.peer_addr() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    warn!("get peer addr failed on {}: {}", self.addr, e);
+                    continue;
+                }
+            };
+            let client_addr = peer_addr.to_string();
+            self.reload_tls();
 
-            let mut conn = Conn::new();
+            let max_connections = self.cfg.current().listener(&self.addr).and_then(|l| l.max_connections);
+            let connections = self.connections.clone();
+            if max_connections.is_some_and(|max| connections.load(Ordering::SeqCst) >= max) {
+                warn!("too many connections on {}, reject client: {}", self.addr, client_addr);
+                self.metrics.inc(Counter::ConnectionsRejected, &[("reason", "max_connections")]);
+                continue;
+            }
+
+            // 未使用 PROXY protocol 时对端地址即客户端地址, 在 accept 阶段直接拒绝
+            let mut permit = None;
+            if !self.proxy_protocol {
+                match self.guard.admit(&self.cfg.current(), peer_addr.ip()) {
+                    Ok(p) => permit = Some(p),
+                    Err(e) => {
+                        warn!("reject client: {}, {}", client_addr, e);
+                        self.metrics.inc(Counter::ConnectionsRejected, &[("reason", "guard")]);
+                        continue;
+                    }
+                }
+            }
+
+            connections.fetch_add(1, Ordering::SeqCst);
+            let slot = ConnectionSlot(connections);
+            info!("accept client: {}, listener: {}", client_addr, self.addr);
+            self.metrics.inc(Counter::ConnectionsAccepted, &[("listener", &self.addr)]);
+
+            let mut peer = PeerInfo { listener: self.addr.clone(), addr: peer_addr, cert: None };
+            let config = self.cfg.subscribe();
+            let shutdown = self.notify_shutdown.subscribe();
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+            let acceptor = self.tls.clone();
+            let proxy_protocol = self.proxy_protocol;
+            let cfg = self.cfg.clone();
+            let guard = self.guard.clone();
+            let pipeline = self.pipeline.clone();
+            let sessions = self.sessions.clone();
+            let metrics = self.metrics.clone();
 
             tokio::spawn(async move {
-                match conn.run(socket).await {
+                let mut socket = socket;
+                let res = async {
+                    if proxy_protocol {
+                        Self::read_proxy(&mut socket, &mut peer).await?;
+                    }
+                    let permit = match permit {
+                        Some(permit) => permit,
+                        None => guard.admit(&cfg.current(), peer.addr.ip()).inspect_err(|_| {
+                            metrics.inc(Counter::ConnectionsRejected, &[("reason", "guard")]);
+                        })?,
+                    };
+                    let stream = Self::handshake(acceptor, socket, &mut peer).await?;
+                    Conn::new(config, peer.clone())
+                        .with_shutdown(shutdown)
+                        .with_permit(permit)
+                        .with_pipeline(pipeline)
+                        .with_sessions(sessions)
+                        .with_metrics(metrics)
+                        .run(stream).await
+                }.await;
+
+                match res {
                     Ok(()) => {
-                        info!("client disconnect, client addr: {}", client_addr)
+                        info!("client disconnect, client addr: {}", peer.addr)
                     }
                     Err(e) => {
-                        error!("exit loop: {:?}, addr: {}", e, client_addr.to_string())
+                        error!("exit loop: {:?}, addr: {}", e, peer.addr)
                     }
                 }
+                drop(slot);
+                drop(shutdown_complete);
             });
         }
     }
-}
\ No newline at end of file
+}
+
+/// 端口上的一个连接数, 会话任务结束 (包括 panic) 时释放
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
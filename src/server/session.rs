@@ -0,0 +1,413 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use chrono::Local;
+use serde::Serialize;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+
+use crate::server::cmd::Command;
+use crate::server::cmd::deliver::{Cmpp3DeliverReqPkt, CmppReport};
+use crate::server::msgid::MsgIdGen;
+use crate::server::pipeline::{SubmitContext, ATTR_HELD};
+use crate::server::upstream::ATTR_UPSTREAM;
+
+// 每个 SP 不在线时最多暂存的 CMPP_DELIVER 数
+const MAX_QUEUED: usize = 100_000;
+// 未转发上游的消息由本网关下发的状态
+const STAT_DELIVRD: &str = "DELIVRD";
+
+/// 已认证会话的注册表, 用于向 SP 投递状态报告
+///
+/// 同一 SP 有多个会话时轮流投递, SP 不在线、暂停投递或会话队列已满时暂存, 重新登录、恢复投递或
+/// 会话处理完队列后按原顺序补发。有暂存时新的 CMPP_DELIVER 排在暂存之后。
+pub struct Sessions {
+    inner: Mutex<Inner>,
+    hooks: RwLock<Vec<Arc<dyn DeliverHook>>>,
+    // 本网关生成的状态报告的 Msg_Id
+    ids: Arc<MsgIdGen>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions::new(Arc::new(MsgIdGen::new(1)))
+    }
+}
+
+/// 投递前的拦截, 如跟踪接口提交的消息状态
+pub trait DeliverHook: Send + Sync {
+    /// 返回 None 表示已处理, 不再投递给 SP 的会话
+    fn intercept(&self, sp_id: &str, deliver: Cmpp3DeliverReqPkt) -> Option<Cmpp3DeliverReqPkt>;
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    sessions: HashMap<u64, SessionEntry>,
+    queued: HashMap<String, VecDeque<Cmpp3DeliverReqPkt>>,
+    // 暂停投递的 SP
+    paused: HashSet<String>,
+    // 轮询投递的位置
+    cursor: usize,
+}
+
+struct SessionEntry {
+    sp_id: String,
+    peer: SocketAddr,
+    listener: String,
+    version: u8,
+    connected_at: String,
+    // 会话处理器的请求队列
+    inbox: Sender<Command>,
+    stats: Arc<SessionStats>,
+    kick: Arc<Notify>,
+}
+
+/// 会话的计数, 由会话和处理器更新
+#[derive(Debug, Default)]
+pub struct SessionStats {
+    /// 收到的 CMPP_SUBMIT 数
+    pub submits: AtomicU64,
+    /// 回复 result 非 0 的 CMPP_SUBMIT 数
+    pub rejected: AtomicU64,
+    /// 下发的 CMPP_DELIVER 数
+    pub delivers: AtomicU64,
+    /// 已下发未确认的 CMPP_DELIVER 数
+    pub in_flight: AtomicUsize,
+}
+
+/// 在线会话的信息
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub sp_id: String,
+    pub peer: SocketAddr,
+    pub listener: String,
+    pub version: u8,
+    /// 登录时间, RFC 3339 格式
+    pub connected_at: String,
+    /// 已下发未确认的 CMPP_DELIVER 数
+    pub window: usize,
+    /// 等待会话处理的请求数
+    pub inbox: usize,
+    pub submits: u64,
+    pub rejected: u64,
+    pub delivers: u64,
+}
+
+/// 会话结束时从注册表中移除
+pub(crate) struct SessionGuard {
+    id: u64,
+    sessions: Arc<Sessions>,
+    kick: Arc<Notify>,
+}
+
+impl SessionGuard {
+    /// 补充会话的接入端口、协议版本和计数
+    pub(crate) fn describe(&self, listener: &str, version: u8, stats: Arc<SessionStats>) {
+        if let Some(entry) = self.sessions.inner.lock().unwrap().sessions.get_mut(&self.id) {
+            entry.listener = listener.to_string();
+            entry.version = version;
+            entry.stats = stats;
+        }
+    }
+
+    /// 管理员要求断开会话时返回
+    pub(crate) fn kicked(&self) -> Arc<Notify> {
+        self.kick.clone()
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut inner = self.sessions.inner.lock().unwrap();
+        if let Some(entry) = inner.sessions.remove(&self.id) {
+            log::info!("session unregistered, sp_id: {}, peer: {}", entry.sp_id, entry.peer);
+        }
+    }
+}
+
+impl Sessions {
+    /// 本网关生成的状态报告使用 `ids` 分配 Msg_Id
+    pub fn new(ids: Arc<MsgIdGen>) -> Sessions {
+        Sessions { inner: Mutex::default(), hooks: RwLock::default(), ids }
+    }
+
+    pub(crate) fn register(self: &Arc<Self>, sp_id: &str, peer: SocketAddr, inbox: Sender<Command>) -> SessionGuard {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+
+        let kick = Arc::new(Notify::new());
+        inner.sessions.insert(id, SessionEntry {
+            sp_id: sp_id.to_string(),
+            peer,
+            listener: String::new(),
+            version: 0,
+            connected_at: Local::now().to_rfc3339(),
+            inbox,
+            stats: Arc::default(),
+            kick: kick.clone(),
+        });
+        // 补发暂存的 CMPP_DELIVER
+        inner.flush(sp_id);
+        SessionGuard { id, sessions: self.clone(), kick }
+    }
+
+    /// 注册投递前的拦截, 按注册顺序执行
+    pub fn add_hook(&self, hook: Arc<dyn DeliverHook>) {
+        self.hooks.write().unwrap().push(hook);
+    }
+
+    /// 向 SP 的任一在线会话投递, 无在线会话、暂停投递或队列已满时暂存
+    pub fn deliver(&self, sp_id: &str, mut deliver: Cmpp3DeliverReqPkt) {
+        for hook in self.hooks.read().unwrap().iter() {
+            deliver = match hook.intercept(sp_id, deliver) {
+                Some(deliver) => deliver,
+                None => return,
+            };
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let queued = inner.queued.entry(sp_id.to_string()).or_default();
+        if queued.len() >= MAX_QUEUED {
+            log::warn!("too many queued delivers for {}, drop msg_id: {}", sp_id, queued[0].msg_id);
+            queued.pop_front();
+        }
+        queued.push_back(deliver);
+        inner.flush(sp_id);
+    }
+
+    /// SP 要求状态报告时为每个接收号码下发本网关生成的状态报告
+    pub(crate) fn report(&self, ctx: &SubmitContext, stat: &str) {
+        if ctx.submit.registered_delivery != 1 {
+            return;
+        }
+        let now = Local::now().format("%y%m%d%H%M").to_string();
+        for dest in &ctx.submit.dest_terminal_id {
+            let mut deliver = Cmpp3DeliverReqPkt::new();
+            deliver.msg_id = self.ids.next_id();
+            deliver.dest_id = ctx.submit.src_id.clone();
+            deliver.service_id = ctx.submit.service_id.clone();
+            deliver.src_terminal_id = dest.clone();
+            deliver.register_delivery = 1;
+            deliver.report = Some(CmppReport {
+                msg_id: ctx.msg_id,
+                stat: stat.to_string(),
+                submit_time: now.clone(),
+                done_time: now.clone(),
+                dest_terminal_id: dest.clone(),
+                smsc_sequence: 0,
+            });
+            self.deliver(&ctx.sp_id, deliver);
+        }
+    }
+
+    /// 本网关处理完成的消息下发 DELIVRD; 已转发到上游网关的由上游返回状态报告, 被暂停的之后再处理
+    pub(crate) fn delivered(&self, ctx: &SubmitContext) {
+        if ctx.attrs.contains_key(ATTR_UPSTREAM) || ctx.attrs.contains_key(ATTR_HELD) {
+            return;
+        }
+        self.report(ctx, STAT_DELIVRD);
+    }
+
+    /// 交还会话结束时未确认的 CMPP_DELIVER, 排在暂存之前, 不再经过投递拦截
+    pub(crate) fn requeue(&self, sp_id: &str, delivers: Vec<Cmpp3DeliverReqPkt>) {
+        let mut inner = self.inner.lock().unwrap();
+        let queued = inner.queued.entry(sp_id.to_string()).or_default();
+        for deliver in delivers.into_iter().rev() {
+            queued.push_front(deliver);
+        }
+        inner.flush(sp_id);
+    }
+
+    /// 取出所有暂存的 CMPP_DELIVER, 用于停机时保存
+    pub(crate) fn take_queued(&self) -> Vec<(String, Cmpp3DeliverReqPkt)> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.queued.drain()
+            .flat_map(|(sp_id, queued)| queued.into_iter().map(move |deliver| (sp_id.clone(), deliver)))
+            .collect()
+    }
+
+    /// 会话处理完请求队列后调用, 补发因队列已满暂存的 CMPP_DELIVER
+    pub(crate) fn flush(&self, sp_id: &str) {
+        self.inner.lock().unwrap().flush(sp_id);
+    }
+
+    /// SP 的在线会话数
+    pub fn online(&self, sp_id: &str) -> usize {
+        self.inner.lock().unwrap().sessions.values().filter(|s| s.sp_id == sp_id).count()
+    }
+
+    /// SP 暂存待投递的 CMPP_DELIVER 数
+    pub fn queued(&self, sp_id: &str) -> usize {
+        self.inner.lock().unwrap().queued.get(sp_id).map_or(0, |q| q.len())
+    }
+
+    /// 各 SP 暂存待投递的 CMPP_DELIVER 数
+    pub fn queue_depths(&self) -> HashMap<String, usize> {
+        let inner = self.inner.lock().unwrap();
+        inner.queued.iter().filter(|(_, q)| !q.is_empty()).map(|(sp_id, q)| (sp_id.clone(), q.len())).collect()
+    }
+
+    /// 在线会话, 按登录顺序
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let inner = self.inner.lock().unwrap();
+        let mut sessions: Vec<SessionInfo> = inner.sessions.iter().map(|(id, s)| SessionInfo {
+            id: *id,
+            sp_id: s.sp_id.clone(),
+            peer: s.peer,
+            listener: s.listener.clone(),
+            version: s.version,
+            connected_at: s.connected_at.clone(),
+            window: s.stats.in_flight.load(Ordering::Relaxed),
+            inbox: s.inbox.max_capacity() - s.inbox.capacity(),
+            submits: s.stats.submits.load(Ordering::Relaxed),
+            rejected: s.stats.rejected.load(Ordering::Relaxed),
+            delivers: s.stats.delivers.load(Ordering::Relaxed),
+        }).collect();
+        sessions.sort_by_key(|s| s.id);
+        sessions
+    }
+
+    /// 要求会话发送 CMPP_TERMINATE 后断开, 会话不存在时返回 false
+    pub fn kick(&self, id: u64) -> bool {
+        match self.inner.lock().unwrap().sessions.get(&id) {
+            Some(entry) => {
+                log::info!("kick session, sp_id: {}, peer: {}", entry.sp_id, entry.peer);
+                entry.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 暂停向 SP 投递, 期间的 CMPP_DELIVER 暂存
+    pub fn pause(&self, sp_id: &str) {
+        log::info!("delivery paused, sp_id: {}", sp_id);
+        self.inner.lock().unwrap().paused.insert(sp_id.to_string());
+    }
+
+    /// 恢复向 SP 投递, 补发暂存的 CMPP_DELIVER
+    pub fn resume(&self, sp_id: &str) {
+        log::info!("delivery resumed, sp_id: {}", sp_id);
+        let mut inner = self.inner.lock().unwrap();
+        inner.paused.remove(sp_id);
+        inner.flush(sp_id);
+    }
+
+    pub fn is_paused(&self, sp_id: &str) -> bool {
+        self.inner.lock().unwrap().paused.contains(sp_id)
+    }
+}
+
+impl Inner {
+    /// 按顺序将 SP 暂存的 CMPP_DELIVER 轮流发送到其在线会话, 所有会话队列都满时停止
+    fn flush(&mut self, sp_id: &str) {
+        if self.paused.contains(sp_id) {
+            return;
+        }
+        let Some(queued) = self.queued.get_mut(sp_id).filter(|q| !q.is_empty()) else {
+            return;
+        };
+        let inboxes: Vec<&Sender<Command>> = self.sessions.values()
+            .filter(|s| s.sp_id == sp_id)
+            .map(|s| &s.inbox)
+            .collect();
+
+        'next: while let Some(deliver) = queued.pop_front() {
+            let mut cmd = Command::DeliverReq(deliver);
+            for _ in 0..inboxes.len() {
+                self.cursor = self.cursor.wrapping_add(1);
+                match inboxes[self.cursor % inboxes.len()].try_send(cmd) {
+                    Ok(()) => continue 'next,
+                    Err(TrySendError::Full(c) | TrySendError::Closed(c)) => cmd = c,
+                }
+            }
+            if let Command::DeliverReq(deliver) = cmd {
+                queued.push_front(deliver);
+            }
+            break;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::server::cmd::Command;
+    use crate::server::cmd::deliver::Cmpp3DeliverReqPkt;
+    use crate::server::session::Sessions;
+
+    #[test]
+    fn test_deliver() {
+        let sessions = Arc::new(Sessions::default());
+        let peer = "127.0.0.1:5000".parse().unwrap();
+
+        let mut deliver = Cmpp3DeliverReqPkt::new();
+        deliver.msg_id = 1;
+        sessions.deliver("900001", deliver);
+        assert_eq!(sessions.queued("900001"), 1);
+
+        // 登录后补发
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let guard = sessions.register("900001", peer, tx);
+        assert_eq!(sessions.queued("900001"), 0);
+        assert_eq!(sessions.online("900001"), 1);
+        assert!(matches!(rx.try_recv(), Ok(Command::DeliverReq(d)) if d.msg_id == 1));
+
+        let mut deliver = Cmpp3DeliverReqPkt::new();
+        deliver.msg_id = 2;
+        sessions.deliver("900001", deliver);
+        assert!(matches!(rx.try_recv(), Ok(Command::DeliverReq(d)) if d.msg_id == 2));
+
+        // 暂停期间暂存, 恢复后补发
+        sessions.pause("900001");
+        let mut deliver = Cmpp3DeliverReqPkt::new();
+        deliver.msg_id = 3;
+        sessions.deliver("900001", deliver);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(sessions.queued("900001"), 1);
+        sessions.resume("900001");
+        assert!(matches!(rx.try_recv(), Ok(Command::DeliverReq(d)) if d.msg_id == 3));
+
+        let list = sessions.list();
+        assert_eq!(list.len(), 1);
+        assert!(sessions.kick(list[0].id));
+        assert!(!sessions.kick(list[0].id + 1));
+
+        drop(guard);
+        assert_eq!(sessions.online("900001"), 0);
+    }
+
+    #[test]
+    fn test_deliver_order() {
+        let sessions = Arc::new(Sessions::default());
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let _guard = sessions.register("900001", "127.0.0.1:5000".parse().unwrap(), tx);
+
+        // 会话队列满时暂存, 之后的 CMPP_DELIVER 排在暂存之后
+        for msg_id in 1..=3 {
+            let mut deliver = Cmpp3DeliverReqPkt::new();
+            deliver.msg_id = msg_id;
+            sessions.deliver("900001", deliver);
+        }
+        assert_eq!(sessions.queued("900001"), 2);
+        assert!(matches!(rx.try_recv(), Ok(Command::DeliverReq(d)) if d.msg_id == 1));
+        let mut deliver = Cmpp3DeliverReqPkt::new();
+        deliver.msg_id = 4;
+        sessions.deliver("900001", deliver);
+
+        // 会话处理完队列后按原顺序补发
+        for msg_id in 2..=4 {
+            assert!(matches!(rx.try_recv(), Ok(Command::DeliverReq(d)) if d.msg_id == msg_id));
+            sessions.flush("900001");
+        }
+        assert_eq!(sessions.queued("900001"), 0);
+    }
+}
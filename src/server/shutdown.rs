@@ -0,0 +1,52 @@
+use tokio::sync::broadcast;
+
+/// Listens for the server shutdown signal.
+///
+/// Shutdown is signalled using a `broadcast::Receiver`. Only a single value is
+/// ever sent. Once a value has been sent via the broadcast channel, the session
+/// should start draining.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    /// `true` if the shutdown signal has been received
+    is_shutdown: bool,
+
+    /// The receive half of the channel used to listen for shutdown. `None`
+    /// for sessions that are never notified.
+    notify: Option<broadcast::Receiver<()>>,
+}
+
+impl Shutdown {
+    pub(crate) fn new(notify: broadcast::Receiver<()>) -> Shutdown {
+        Shutdown {
+            is_shutdown: false,
+            notify: Some(notify),
+        }
+    }
+
+    /// A listener that never receives the shutdown signal.
+    pub(crate) fn never() -> Shutdown {
+        Shutdown {
+            is_shutdown: false,
+            notify: None,
+        }
+    }
+
+    /// Receive the shutdown notice, waiting if necessary.
+    pub(crate) async fn recv(&mut self) {
+        // If the shutdown signal has already been received, then return
+        // immediately.
+        if self.is_shutdown {
+            return;
+        }
+
+        match self.notify {
+            // Cannot receive a "lag error" as only one value is ever sent.
+            Some(ref mut notify) => {
+                let _ = notify.recv().await;
+            }
+            None => std::future::pending::<()>().await,
+        }
+
+        self.is_shutdown = true;
+    }
+}
@@ -0,0 +1,74 @@
+use std::io::Write;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::server::cmd::deliver::Cmpp3DeliverReqPkt;
+use crate::server::Result;
+
+/// 保存的 CMPP_DELIVER 及所属的 SP
+#[derive(Serialize, Deserialize)]
+struct StoredReport {
+    #[serde(default)]
+    sp_id: String,
+    #[serde(flatten)]
+    deliver: Cmpp3DeliverReqPkt,
+}
+
+/// 保存未投递的 CMPP_DELIVER, 每行一个 json 对象, 覆盖原有内容
+pub(crate) fn save_reports<P: AsRef<Path>>(path: P, reports: &[(String, Cmpp3DeliverReqPkt)]) -> Result<()> {
+    let path = path.as_ref();
+    let mut lines = Vec::new();
+    for (sp_id, deliver) in reports {
+        serde_json::to_writer(&mut lines, &StoredReport { sp_id: sp_id.clone(), deliver: deliver.clone() })?;
+        lines.push(b'\n');
+    }
+
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(&lines)?;
+    file.flush()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// 读取已保存的 CMPP_DELIVER 及所属的 SP, 旧格式中没有 SP 的为空字符串
+pub fn load_reports<P: AsRef<Path>>(path: P) -> Result<Vec<(String, Cmpp3DeliverReqPkt)>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut reports = Vec::new();
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        let stored: StoredReport = serde_json::from_str(line)?;
+        reports.push((stored.sp_id, stored.deliver));
+    }
+    Ok(reports)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::server::cmd::deliver::Cmpp3DeliverReqPkt;
+    use crate::server::store::{load_reports, save_reports};
+
+    #[test]
+    fn test_save_reports() {
+        let path = std::env::temp_dir().join(format!("cmpp-reports-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut report = Cmpp3DeliverReqPkt::new();
+        report.msg_id = 42;
+        report.dest_id = "13800138000".to_string();
+        save_reports(&path, &[("900001".to_string(), report.clone()), ("900002".to_string(), report)]).unwrap();
+
+        let reports = load_reports(&path).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].0, "900001");
+        assert_eq!(reports[0].1.msg_id, 42);
+        assert_eq!(reports[1].0, "900002");
+        assert_eq!(reports[1].1.dest_id, "13800138000");
+
+        // 覆盖原有内容
+        save_reports(&path, &[]).unwrap();
+        assert!(load_reports(&path).unwrap().is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
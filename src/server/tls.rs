@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::server::config::TlsConfig;
+use crate::server::Result;
+
+/// 根据配置加载证书并创建 TLS acceptor, 配置了 `client_ca` 时要求客户端提供证书
+pub(crate) fn build_acceptor(cfg: &TlsConfig) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(&cfg.cert)?.collect::<std::result::Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(&cfg.key)?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+
+    let builder = match cfg.client_ca {
+        Some(ref ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca)? {
+                roots.add(cert?)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder.with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// 客户端证书的 SHA-256 指纹 (小写 hex), 未提供证书时返回 None
+pub(crate) fn peer_fingerprint<S>(stream: &TlsStream<S>) -> Option<String> {
+    let (_, session) = stream.get_ref();
+    let cert = session.peer_certificates()?.first()?;
+    Some(fingerprint(cert.as_ref()))
+}
+
+pub(crate) fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::server::tls::fingerprint;
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(fingerprint(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
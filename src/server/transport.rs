@@ -0,0 +1,9 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// 会话底层的字节流, TCP、TLS、Unix socket 以及内存中的 `tokio::io::duplex` 都可以作为传输层
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+/// 类型擦除的传输层, 便于在运行时选择不同的传输实现
+pub type BoxedTransport = Box<dyn Transport>;
@@ -0,0 +1,654 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use chrono::Local;
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, Notify, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+use tokio_util::codec::Decoder;
+
+use crate::server::cmd::active::CmppActiveTestReqPkt;
+use crate::server::cmd::connect::{Cmpp3ConnRspPkt, CmppConnReqPkt};
+use crate::server::cmd::deliver::{Cmpp3DeliverReqPkt, Cmpp3DeliverResPkt, CmppReport};
+use crate::server::cmd::submit::{Cmpp3SubmitReqPkt, Cmpp3SubmitRspPkt};
+use crate::server::cmd::terminate::CmppTerminateReqPkt;
+use crate::server::cmd::{CMPP_ACTIVE_TEST, CMPP_ACTIVE_TEST_RESP, CMPP_CONNECT_RESP, CMPP_DELIVER,
+                         CMPP_SUBMIT_RESP, CMPP_TERMINATE, CMPP_TERMINATE_RESP, ERRNO_SUBMIT_FLOW_CONTROL};
+use crate::server::config::{Config, UpstreamConfig};
+use crate::server::mo::MoService;
+use crate::server::msgid::MsgIdGen;
+use crate::server::pipeline::{Flow, SubmitContext, SubmitStage, ATTR_MSG_ID};
+use crate::server::session::Sessions;
+use crate::server::{CmppDecoder, CmppMessage, Result};
+
+/// 记录消息经由的上游网关, 多个以逗号分隔
+pub const ATTR_UPSTREAM: &str = "upstream";
+
+// 登录上游网关的超时时间
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+// 连续多少次链路检测未收到任何数据后重连
+const MAX_IDLE_TICKS: u32 = 3;
+// 等待状态报告的最长时间, 过期的对应关系会被清理
+const REPORT_TTL: Duration = Duration::from_secs(72 * 3600);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// 状态报告可能先于 CMPP_SUBMIT_RESP 被处理, 暂存等待对应关系的时间
+const EARLY_REPORT_TTL: Duration = Duration::from_secs(60);
+// Msg_Level 的取值范围 0-9
+const LEVELS: usize = 10;
+// 清理超时未响应的提交的间隔
+const PENDING_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Outbound 的状态, 连接写出前和提交方放弃时各自从 QUEUED 切换, 先切换的一方生效
+const QUEUED: u8 = 0;
+const WRITTEN: u8 = 1;
+const ABANDONED: u8 = 2;
+
+/// 待发往上游的提交
+struct Outbound {
+    submit: Cmpp3SubmitReqPkt,
+    reply: oneshot::Sender<Cmpp3SubmitRspPkt>,
+    state: Arc<AtomicU8>,
+}
+
+/// 一次提交的结果
+enum Submitted {
+    Resp(Cmpp3SubmitRspPkt),
+    // 超时前未写出, 不会再发送
+    Unsent,
+    // 已写出但未收到响应, 上游可能已经收下
+    NoResp,
+}
+
+/// 按 Msg_Level 分道的提交队列, 同一上游的所有连接共享
+///
+/// 各级别按 `level + 1` 的权重平滑加权轮询: 级别高的优先发送, 级别低的也能按比例得到发送机会。
+struct Lanes {
+    state: Mutex<LaneState>,
+    // 可排队的提交数, 出队时归还
+    space: Semaphore,
+    // 有提交入队时唤醒连接
+    ready: Notify,
+}
+
+#[derive(Default)]
+struct LaneState {
+    lanes: [VecDeque<Outbound>; LEVELS],
+    // 平滑加权轮询的当前权重, 队列为空时清零
+    current: [i64; LEVELS],
+}
+
+impl Lanes {
+    fn new(capacity: usize) -> Lanes {
+        Lanes { state: Mutex::default(), space: Semaphore::new(capacity), ready: Notify::new() }
+    }
+
+    /// 队列已满时等待空位
+    async fn push(&self, level: u8, out: Outbound) {
+        // 信号量不会关闭
+        self.space.acquire().await.unwrap().forget();
+        self.state.lock().unwrap().lanes[(level as usize).min(LEVELS - 1)].push_back(out);
+        self.ready.notify_one();
+    }
+
+    /// 队列为空时等待提交入队
+    async fn pop(&self) -> Outbound {
+        loop {
+            let ready = self.ready.notified();
+            if let Some(out) = self.try_pop() {
+                return out;
+            }
+            ready.await;
+        }
+    }
+
+    fn try_pop(&self) -> Option<Outbound> {
+        let mut state = self.state.lock().unwrap();
+        let LaneState { lanes, current } = &mut *state;
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for level in (0..LEVELS).filter(|&l| !lanes[l].is_empty()) {
+            current[level] += level as i64 + 1;
+            total += level as i64 + 1;
+            if best.is_none_or(|b| current[level] >= current[b]) {
+                best = Some(level);
+            }
+        }
+        let level = best?;
+        current[level] -= total;
+        let out = lanes[level].pop_front();
+        if lanes[level].is_empty() {
+            current[level] = 0;
+        }
+        // 还有排队的提交时唤醒其他连接
+        if lanes.iter().any(|l| !l.is_empty()) {
+            self.ready.notify_one();
+        }
+        self.space.add_permits(1);
+        out
+    }
+
+    fn len(&self) -> usize {
+        self.state.lock().unwrap().lanes.iter().map(VecDeque::len).sum()
+    }
+}
+
+/// 上游 Msg_Id 对应的原始提交
+struct Origin {
+    sp_id: String,
+    // 回复给 SP 的 Msg_Id
+    msg_id: u64,
+    src_id: String,
+    service_id: String,
+    // 尚未收到状态报告的号码数
+    remaining: usize,
+    created: Instant,
+}
+
+/// 维护 Msg_Id 的对应关系, 将上游的状态报告转回给 SP
+struct Relay {
+    ids: Arc<MsgIdGen>,
+    origins: Mutex<HashMap<u64, Origin>>,
+    // 尚未记录对应关系的状态报告
+    early: Mutex<HashMap<u64, Vec<(CmppReport, Instant)>>>,
+    last_sweep: Mutex<Instant>,
+    sessions: Arc<Sessions>,
+    mo: Option<Arc<MoService>>,
+}
+
+impl Relay {
+    fn record(&self, upstream_msg_id: u64, mut origin: Origin) {
+        let mut origins = self.origins.lock().unwrap();
+        let mut early = self.early.lock().unwrap();
+        let mut last_sweep = self.last_sweep.lock().unwrap();
+        if last_sweep.elapsed() >= SWEEP_INTERVAL {
+            origins.retain(|_, o| o.created.elapsed() < REPORT_TTL);
+            early.retain(|msg_id, reports| {
+                let fresh = reports.iter().any(|(_, at)| at.elapsed() < EARLY_REPORT_TTL);
+                if !fresh {
+                    warn!("drop {} reports for unknown msg_id: {}", reports.len(), msg_id);
+                }
+                fresh
+            });
+            *last_sweep = Instant::now();
+        }
+
+        for (report, _) in early.remove(&upstream_msg_id).unwrap_or_default() {
+            self.deliver_report(&origin, report);
+            origin.remaining = origin.remaining.saturating_sub(1);
+        }
+        if origin.remaining > 0 {
+            origins.insert(upstream_msg_id, origin);
+        }
+    }
+
+    fn on_report(&self, upstream: &str, report: &CmppReport) {
+        let mut origins = self.origins.lock().unwrap();
+        let origin = match origins.get_mut(&report.msg_id) {
+            Some(origin) => origin,
+            None => {
+                debug!("report from {} before submit resp, msg_id: {}", upstream, report.msg_id);
+                let mut early = self.early.lock().unwrap();
+                early.entry(report.msg_id).or_default().push((report.clone(), Instant::now()));
+                return;
+            }
+        };
+
+        self.deliver_report(origin, report.clone());
+        origin.remaining = origin.remaining.saturating_sub(1);
+        if origin.remaining == 0 {
+            origins.remove(&report.msg_id);
+        }
+    }
+
+    /// 以 SP 的 Msg_Id 投递状态报告
+    fn deliver_report(&self, origin: &Origin, report: CmppReport) {
+        let mut deliver = Cmpp3DeliverReqPkt::new();
+        deliver.msg_id = self.ids.next_id();
+        deliver.dest_id = origin.src_id.clone();
+        deliver.service_id = origin.service_id.clone();
+        deliver.src_terminal_id = report.dest_terminal_id.clone();
+        deliver.register_delivery = 1;
+        deliver.report = Some(CmppReport { msg_id: origin.msg_id, ..report });
+        self.sessions.deliver(&origin.sp_id, deliver);
+    }
+}
+
+/// 一个上游网关的连接池
+struct Pool {
+    name: String,
+    queue: Arc<Lanes>,
+    // 已登录的连接数
+    online: Arc<AtomicUsize>,
+}
+
+/// 将 CMPP_SUBMIT 转发到上游网关, 注册在 `Phase::Dispatch`
+///
+/// 每个上游网关维护一组客户端连接, 共享一个提交队列。提交成功后以本网关生成的
+/// Msg_Id 回复 SP, 上游的状态报告按 Msg_Id 对应关系转换后投递给原 SP。
+/// 按路由环节的分组依次尝试候选上游, 未经路由时使用第一个上游网关。
+pub struct Dispatcher {
+    pools: Vec<Pool>,
+    relay: Arc<Relay>,
+    submit_timeout: Duration,
+    // Dispatcher 释放时终止所有上游连接
+    _tasks: JoinSet<()>,
+}
+
+impl Dispatcher {
+    /// 连接配置中的所有上游网关, 需在 tokio 运行时中调用
+    ///
+    /// 上游转来的上行交给 `mo` 投递给 SP, 未配置时忽略。
+    pub fn start(cfg: &Config, ids: Arc<MsgIdGen>, sessions: Arc<Sessions>, mo: Option<Arc<MoService>>) -> Arc<Dispatcher> {
+        let relay = Arc::new(Relay {
+            ids,
+            origins: Mutex::new(HashMap::new()),
+            early: Mutex::new(HashMap::new()),
+            last_sweep: Mutex::new(Instant::now()),
+            sessions,
+            mo,
+        });
+
+        let mut tasks = JoinSet::new();
+        let mut pools = Vec::new();
+        for upstream in &cfg.upstreams {
+            let queue = Arc::new(Lanes::new(upstream.window.max(1) * upstream.connections.max(1)));
+            let online = Arc::new(AtomicUsize::new(0));
+            for _ in 0..upstream.connections.max(1) {
+                tasks.spawn(run_client(upstream.clone(), Duration::from_secs(cfg.submit_timeout), queue.clone(),
+                                       relay.clone(), online.clone()));
+            }
+            pools.push(Pool { name: upstream.name.clone(), queue, online });
+        }
+
+        Arc::new(Dispatcher {
+            pools,
+            relay,
+            submit_timeout: Duration::from_secs(cfg.submit_timeout),
+            _tasks: tasks,
+        })
+    }
+
+    /// 已配置的上游网关名称
+    pub fn upstreams(&self) -> Vec<String> {
+        self.pools.iter().map(|p| p.name.clone()).collect()
+    }
+
+    /// 上游网关已登录的连接数
+    pub fn online(&self, upstream: &str) -> usize {
+        self.pools.iter().find(|p| p.name == upstream).map_or(0, |p| p.online.load(Ordering::SeqCst))
+    }
+
+    /// 上游网关等待发送的 CMPP_SUBMIT 数
+    pub fn queued(&self, upstream: &str) -> usize {
+        self.pools.iter().find(|p| p.name == upstream).map_or(0, |p| p.queue.len())
+    }
+
+    async fn submit(&self, pool: &Pool, submit: Cmpp3SubmitReqPkt) -> Submitted {
+        let (reply, rx) = oneshot::channel();
+        let state = Arc::new(AtomicU8::new(QUEUED));
+        let out = Outbound { submit, reply, state: state.clone() };
+        let res = time::timeout(self.submit_timeout, async {
+            pool.queue.push(out.submit.msg_level, out).await;
+            rx.await.ok()
+        }).await;
+        if let Ok(Some(rsp)) = res {
+            return Submitted::Resp(rsp);
+        }
+        match state.compare_exchange(QUEUED, ABANDONED, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => Submitted::Unsent,
+            Err(_) => Submitted::NoResp,
+        }
+    }
+
+    /// 依次尝试候选上游, 已登录的优先, 返回成功的上游和响应, 失败时返回 result
+    ///
+    /// 只有未写出的提交才改投下一个上游, 已写出未响应的不再重试, 避免重复下发。
+    async fn dispatch(&self, upstreams: &[String], submit: &Cmpp3SubmitReqPkt) -> std::result::Result<(String, Cmpp3SubmitRspPkt), u32> {
+        let mut pools: Vec<&Pool> = upstreams.iter()
+            .filter_map(|name| {
+                let pool = self.pools.iter().find(|p| &p.name == name);
+                if pool.is_none() {
+                    warn!("unknown upstream: {}", name);
+                }
+                pool
+            })
+            .collect();
+        pools.sort_by_key(|p| p.online.load(Ordering::SeqCst) == 0);
+
+        for pool in pools {
+            match self.submit(pool, submit.clone()).await {
+                Submitted::Resp(rsp) if rsp.result == 0 => return Ok((pool.name.clone(), rsp)),
+                // 上游流量控制时尝试下一个, 其余错误直接返回
+                Submitted::Resp(rsp) if rsp.result != ERRNO_SUBMIT_FLOW_CONTROL => return Err(rsp.result),
+                Submitted::Resp(_) => warn!("submit to {} flow controlled, try next", pool.name),
+                Submitted::Unsent => warn!("submit to {} not sent before timeout, try next", pool.name),
+                Submitted::NoResp => {
+                    warn!("submit to {} sent but no resp before timeout, not retried", pool.name);
+                    return Err(ERRNO_SUBMIT_FLOW_CONTROL);
+                }
+            }
+        }
+        // 上游均不可用按流量控制错处理, SP 可稍后重试
+        Err(ERRNO_SUBMIT_FLOW_CONTROL)
+    }
+}
+
+#[async_trait]
+impl SubmitStage for Dispatcher {
+    fn name(&self) -> &str {
+        "dispatcher"
+    }
+
+    async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+        let groups: Vec<(Vec<String>, Vec<String>)> = if ctx.routes.is_empty() {
+            match self.pools.first() {
+                Some(pool) => vec![(ctx.submit.dest_terminal_id.clone(), vec![pool.name.clone()])],
+                None => return Flow::Continue,
+            }
+        } else {
+            ctx.routes.iter().map(|r| (r.dests.clone(), r.upstreams.clone())).collect()
+        };
+
+        // 审核后放行的消息和接口提交的消息已分配过 Msg_Id
+        let msg_id = if ctx.attrs.contains_key(ATTR_MSG_ID) { ctx.msg_id } else { self.relay.ids.next_id() };
+        let origin = |remaining: usize| Origin {
+            sp_id: ctx.sp_id.clone(),
+            msg_id,
+            src_id: ctx.submit.src_id.clone(),
+            service_id: ctx.submit.service_id.clone(),
+            remaining,
+            created: Instant::now(),
+        };
+
+        let mut used: Vec<String> = Vec::new();
+        let mut failed: Vec<(Vec<String>, u32)> = Vec::new();
+        for (dests, upstreams) in groups {
+            let mut submit = ctx.submit.clone();
+            submit.dest_usr_tl = dests.len() as u8;
+            submit.dest_terminal_id = dests.clone();
+            match self.dispatch(&upstreams, &submit).await {
+                Ok((name, rsp)) => {
+                    info!("submit dispatched, upstream: {}, sp_id: {}, msg_id: {} -> {}", name, ctx.sp_id, msg_id, rsp.msg_id);
+                    if submit.registered_delivery == 1 {
+                        self.relay.record(rsp.msg_id, origin(dests.len()));
+                    }
+                    if !used.contains(&name) {
+                        used.push(name);
+                    }
+                }
+                Err(result) => failed.push((dests, result)),
+            }
+        }
+
+        if used.is_empty() {
+            return Flow::Reject(failed.first().map_or(ERRNO_SUBMIT_FLOW_CONTROL, |f| f.1));
+        }
+        // 部分号码发送失败时以状态报告告知 SP, 黑名单去掉的号码由会话回复后统一报告
+        if ctx.submit.registered_delivery == 1 {
+            let now = Local::now().format("%y%m%d%H%M").to_string();
+            for (dests, result) in failed {
+                for dest in dests {
+                    self.relay.deliver_report(&origin(1), CmppReport {
+                        msg_id,
+                        stat: format!("GW:{:04}", result),
+                        submit_time: now.clone(),
+                        done_time: now.clone(),
+                        dest_terminal_id: dest,
+                        smsc_sequence: 0,
+                    });
+                }
+            }
+        }
+
+        ctx.msg_id = msg_id;
+        ctx.attrs.insert(ATTR_UPSTREAM.to_string(), used.join(","));
+        Flow::Continue
+    }
+}
+
+/// 维持一个上游连接, 断开后按退避时间重连
+async fn run_client(cfg: UpstreamConfig, submit_timeout: Duration, queue: Arc<Lanes>, relay: Arc<Relay>,
+                    online: Arc<AtomicUsize>) {
+    let mut backoff = 1;
+    loop {
+        match TcpStream::connect(&cfg.addr).await {
+            Ok(stream) => {
+                let mut client = Client::new(&cfg, submit_timeout);
+                let res = client.run(stream, &queue, &relay, &online).await;
+                if client.logged_in {
+                    online.fetch_sub(1, Ordering::SeqCst);
+                    backoff = 1;
+                }
+                match res {
+                    Ok(()) => info!("upstream {} disconnected", cfg.name),
+                    Err(e) => warn!("upstream {} error: {}", cfg.name, e),
+                }
+            }
+            Err(e) => warn!("connect upstream {} ({}) failed: {}", cfg.name, cfg.addr, e),
+        }
+
+        time::sleep(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(64);
+    }
+}
+
+/// 到上游网关的一个客户端连接
+struct Client<'a> {
+    cfg: &'a UpstreamConfig,
+    buf: BytesMut,
+    decoder: CmppDecoder,
+    seq_id: u32,
+    // 等待 CMPP_SUBMIT_RESP 的提交及发送时间
+    pending: HashMap<u32, (oneshot::Sender<Cmpp3SubmitRspPkt>, Instant)>,
+    // 提交方等待响应的时间, 超过两倍后不再等待, 释放窗口
+    submit_timeout: Duration,
+    logged_in: bool,
+}
+
+impl<'a> Client<'a> {
+    fn new(cfg: &'a UpstreamConfig, submit_timeout: Duration) -> Client<'a> {
+        Client {
+            cfg,
+            buf: BytesMut::with_capacity(2048),
+            decoder: CmppDecoder::default(),
+            seq_id: 0,
+            pending: HashMap::new(),
+            submit_timeout,
+            logged_in: false,
+        }
+    }
+
+    /// 清理超时未响应的提交
+    ///
+    /// 提交方超时放弃后上游可能仍在处理, 再等待同样长的时间才释放窗口, 期间迟到的响应仍可匹配。
+    fn expire_pending(&mut self) {
+        let before = self.pending.len();
+        let ttl = self.submit_timeout * 2;
+        self.pending.retain(|_, (_, sent)| sent.elapsed() < ttl);
+        if self.pending.len() < before {
+            warn!("{} submits to {} expired without resp", before - self.pending.len(), self.cfg.name);
+        }
+    }
+
+    fn next_seq(&mut self) -> u32 {
+        self.seq_id = self.seq_id.wrapping_add(1);
+        self.seq_id
+    }
+
+    async fn run(&mut self, stream: TcpStream, queue: &Lanes,
+                 relay: &Relay, online: &AtomicUsize) -> Result<()> {
+        let (mut reader, mut writer) = stream.into_split();
+        time::timeout(LOGIN_TIMEOUT, self.login(&mut reader, &mut writer)).await
+            .map_err(|_| "login timeout")??;
+        self.logged_in = true;
+        online.fetch_add(1, Ordering::SeqCst);
+        info!("upstream {} logged in", self.cfg.name);
+
+        let period = Duration::from_secs(self.cfg.active_test.max(1));
+        let mut ticker = time::interval_at(Instant::now() + period, period);
+        let mut sweep = time::interval(PENDING_SWEEP_INTERVAL);
+        let mut idle = 0;
+
+        loop {
+            tokio::select! {
+                msg = read_message(&mut self.buf, &mut self.decoder, &mut reader) => {
+                    idle = 0;
+                    match msg? {
+                        Some(msg) => {
+                            if !self.on_message(msg, &mut writer, relay).await? {
+                                return Ok(());
+                            }
+                        }
+                        None => return Ok(()),
+                    }
+                }
+                out = queue.pop(), if self.pending.len() < self.cfg.window.max(1) => {
+                    // 提交方已超时放弃的不再发送
+                    if out.reply.is_closed()
+                        || out.state.compare_exchange(QUEUED, WRITTEN, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                        debug!("skip abandoned submit to {}", self.cfg.name);
+                        continue;
+                    }
+                    let seq_id = self.next_seq();
+                    let mut submit = out.submit;
+                    submit.seq_id = seq_id;
+                    submit.msg_id = 0;
+                    submit.msg_src = self.cfg.sp_id.clone();
+                    writer.write_all(&submit.pack()?).await?;
+                    self.pending.insert(seq_id, (out.reply, Instant::now()));
+                }
+                _ = sweep.tick() => self.expire_pending(),
+                _ = ticker.tick() => {
+                    if idle >= MAX_IDLE_TICKS {
+                        return Err("active test timeout".into());
+                    }
+                    idle += 1;
+                    let seq_id = self.next_seq();
+                    writer.write_all(&CmppActiveTestReqPkt::parse_frame(seq_id)?.pack(seq_id)?).await?;
+                }
+            }
+        }
+    }
+
+    async fn login<R, W>(&mut self, reader: &mut R, writer: &mut W) -> Result<()>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+        let timestamp: u32 = Local::now().format("%m%d%H%M%S").to_string().parse()?;
+        let seq_id = self.next_seq();
+        let req = CmppConnReqPkt::login(&self.cfg.sp_id, &self.cfg.password, self.cfg.version, timestamp, seq_id);
+        writer.write_all(&req.pack()?).await?;
+
+        let msg = read_message(&mut self.buf, &mut self.decoder, reader).await?
+            .ok_or("connection closed during login")?;
+        if msg.command_id != CMPP_CONNECT_RESP {
+            return Err(format!("unexpected command during login: {:#010x}", msg.command_id).into());
+        }
+        let rsp = Cmpp3ConnRspPkt::parse_frame(msg.seq_id, &msg.body_data)?;
+        if rsp.status != 0 {
+            return Err(format!("login rejected, status: {}", rsp.status).into());
+        }
+        Ok(())
+    }
+
+    /// 处理上游的消息, 返回 false 表示上游拆除连接
+    async fn on_message<W: AsyncWrite + Unpin>(&mut self, msg: CmppMessage, writer: &mut W, relay: &Relay) -> Result<bool> {
+        match msg.command_id {
+            CMPP_SUBMIT_RESP => {
+                let rsp = Cmpp3SubmitRspPkt::parse_frame(msg.seq_id, &msg.body_data)?;
+                match self.pending.remove(&msg.seq_id) {
+                    Some((reply, _)) => {
+                        if reply.send(rsp).is_err() {
+                            warn!("late submit resp from {}, seq_id: {}", self.cfg.name, msg.seq_id);
+                        }
+                    }
+                    None => warn!("unexpected submit resp from {}, seq_id: {}", self.cfg.name, msg.seq_id),
+                }
+            }
+            CMPP_DELIVER => {
+                let deliver = Cmpp3DeliverReqPkt::parse_frame(msg.seq_id, &msg.body_data)?;
+                let res = Cmpp3DeliverResPkt { msg_id: deliver.msg_id, result: 0, seq_id: msg.seq_id };
+                writer.write_all(&res.pack()?).await?;
+                match deliver.report {
+                    Some(ref report) => relay.on_report(&self.cfg.name, report),
+                    None => match relay.mo {
+                        Some(ref mo) => mo.on_upstream(&self.cfg.name, deliver),
+                        None => info!("mo from upstream {} ignored: {:?}", self.cfg.name, deliver),
+                    },
+                }
+            }
+            CMPP_ACTIVE_TEST => {
+                let rsp = CmppActiveTestReqPkt::parse_frame(msg.seq_id)?.apply()?;
+                writer.write_all(&rsp.pack()?).await?;
+            }
+            CMPP_ACTIVE_TEST_RESP => {}
+            CMPP_TERMINATE => {
+                let rsp = CmppTerminateReqPkt::parse_frame(msg.seq_id)?.apply()?;
+                writer.write_all(&rsp.pack()?).await?;
+                return Ok(false);
+            }
+            CMPP_TERMINATE_RESP => return Ok(false),
+            command_id => warn!("unexpected command from {}: {:#010x}", self.cfg.name, command_id),
+        }
+        Ok(true)
+    }
+}
+
+async fn read_message<S: AsyncRead + Unpin>(buf: &mut BytesMut, decoder: &mut CmppDecoder, reader: &mut S) -> Result<Option<CmppMessage>> {
+    loop {
+        if let Some(msg) = decoder.decode(buf)? {
+            return Ok(Some(msg));
+        }
+        if 0 == reader.read_buf(buf).await? {
+            return if buf.is_empty() {
+                Ok(None)
+            } else {
+                Err("connection reset by peer".into())
+            };
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::oneshot;
+
+    use crate::server::cmd::submit::Cmpp3SubmitReqPkt;
+    use crate::server::upstream::{Lanes, Outbound};
+
+    fn outbound(level: u8) -> Outbound {
+        let mut submit = Cmpp3SubmitReqPkt::default();
+        submit.msg_level = level;
+        Outbound { submit, reply: oneshot::channel().0, state: Arc::default() }
+    }
+
+    #[tokio::test]
+    async fn test_lanes() {
+        let lanes = Lanes::new(64);
+        for _ in 0..20 {
+            lanes.push(0, outbound(0)).await;
+            lanes.push(9, outbound(9)).await;
+        }
+        assert_eq!(lanes.len(), 40);
+
+        // 高级别优先, 低级别按 1:10 的权重穿插
+        let levels: Vec<u8> = (0..22).map(|_| lanes.try_pop().unwrap().submit.msg_level).collect();
+        assert_eq!(levels.iter().filter(|&&l| l == 0).count(), 2);
+        assert_eq!(levels[0], 9);
+
+        // 队列满时等待出队
+        let lanes = Lanes::new(1);
+        lanes.push(5, outbound(5)).await;
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(50), lanes.push(5, outbound(5))).await.is_err());
+        assert_eq!(lanes.pop().await.submit.msg_level, 5);
+        assert!(lanes.try_pop().is_none());
+    }
+}
@@ -0,0 +1,195 @@
+use async_trait::async_trait;
+
+use crate::server::cmd::{ERRNO_SUBMIT_DEST_TERMINAL_ID, ERRNO_SUBMIT_FEE_CODE, ERRNO_SUBMIT_FEE_TERMINAL_ID,
+                         ERRNO_SUBMIT_MSG_LENGTH, ERRNO_SUBMIT_MSG_SRC, ERRNO_SUBMIT_OVERSIZE, ERRNO_SUBMIT_SERVICE_ID,
+                         ERRNO_SUBMIT_SRC_ID, ERRNO_SUBMIT_STRUCTURE};
+use crate::server::cmd::submit::Cmpp3SubmitReqPkt;
+use crate::server::config::{Account, ConfigRx};
+use crate::server::pipeline::{Flow, SubmitContext, SubmitStage};
+
+// 单条短消息的最大长度, ASCII 编码为 160 字节, 其余编码为 140 字节
+const MAX_MSG_LEN: usize = 140;
+const MAX_ASCII_MSG_LEN: usize = 160;
+// 单次提交的最大接收号码数
+const MAX_DEST_USR: u8 = 100;
+// 合法的 Msg_Fmt: ASCII, 短信写卡, 二进制, UCS2, GB 汉字
+const MSG_FMTS: [u8; 5] = [0, 3, 4, 8, 15];
+// 合法的 FeeType: 免费, 按条, 包月, 封顶, SP 计费
+const FEE_TYPES: [&str; 5] = ["01", "02", "03", "04", "05"];
+
+/// 按 CMPP 3.0 规范校验 CMPP_SUBMIT 的字段, 不合法时返回对应的 result
+pub fn validate_submit(sp_id: &str, account: Option<&Account>, submit: &Cmpp3SubmitReqPkt) -> Option<u32> {
+    // 消息结构
+    if submit.pk_total == 0 || submit.pk_number == 0 || submit.pk_number > submit.pk_total
+        || submit.registered_delivery > 1 || submit.msg_level > 9 || submit.tp_udhi > 1
+        || submit.fee_terminal_type > 1 || submit.dest_terminal_type > 1
+        || !MSG_FMTS.contains(&submit.msg_fmt)
+        || submit.dest_usr_tl == 0 || submit.dest_usr_tl > MAX_DEST_USR {
+        return Some(ERRNO_SUBMIT_STRUCTURE);
+    }
+
+    // 消息长度
+    let content_len = submit.msg_bytes.len();
+    if submit.msg_length() as usize != content_len || (submit.msg_fmt == 8 && !content_len.is_multiple_of(2)) {
+        return Some(ERRNO_SUBMIT_MSG_LENGTH);
+    }
+    let max_len = if submit.msg_fmt == 0 { MAX_ASCII_MSG_LEN } else { MAX_MSG_LEN };
+    if content_len > max_len {
+        return Some(ERRNO_SUBMIT_OVERSIZE);
+    }
+
+    // 资费
+    if submit.fee_user_type > 3 || !FEE_TYPES.contains(&submit.fee_type.as_str())
+        || !submit.fee_code.bytes().all(|b| b.is_ascii_digit()) {
+        return Some(ERRNO_SUBMIT_FEE_CODE);
+    }
+    // 计费用户类型为 3 时按 Fee_terminal_Id 计费
+    if submit.fee_user_type == 3 && !is_msisdn(&submit.fee_terminal_id) {
+        return Some(ERRNO_SUBMIT_FEE_TERMINAL_ID);
+    }
+
+    let service_ids = account.map(|a| a.service_ids.as_slice()).unwrap_or_default();
+    if submit.service_id.is_empty() || (!service_ids.is_empty() && !service_ids.contains(&submit.service_id)) {
+        return Some(ERRNO_SUBMIT_SERVICE_ID);
+    }
+
+    let src_ids = account.map(|a| a.src_ids.as_slice()).unwrap_or_default();
+    if !is_msisdn(&submit.src_id) || (!src_ids.is_empty() && !src_ids.iter().any(|p| submit.src_id.starts_with(p))) {
+        return Some(ERRNO_SUBMIT_SRC_ID);
+    }
+
+    if submit.msg_src != sp_id {
+        return Some(ERRNO_SUBMIT_MSG_SRC);
+    }
+
+    if submit.dest_terminal_id.len() != submit.dest_usr_tl as usize
+        || !submit.dest_terminal_id.iter().all(|d| is_msisdn(d)) {
+        return Some(ERRNO_SUBMIT_DEST_TERMINAL_ID);
+    }
+
+    None
+}
+
+fn is_msisdn(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// 校验环节, `Server` 默认注册在 `Phase::Validation`
+pub struct SubmitValidator {
+    config: ConfigRx,
+}
+
+impl SubmitValidator {
+    pub fn new(config: ConfigRx) -> SubmitValidator {
+        SubmitValidator { config }
+    }
+}
+
+#[async_trait]
+impl SubmitStage for SubmitValidator {
+    fn name(&self) -> &str {
+        "validator"
+    }
+
+    async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+        let cfg = self.config.borrow().clone();
+        let account = cfg.account(&ctx.sp_id);
+        if let Some(result) = validate_submit(&ctx.sp_id, account, &ctx.submit) {
+            return Flow::Reject(result);
+        }
+        // 超过账号允许的最高优先级时降级, 不拒绝
+        if let Some(max) = account.and_then(|a| a.max_msg_level) {
+            ctx.submit.msg_level = ctx.submit.msg_level.min(max);
+        }
+        Flow::Continue
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::server::cmd::submit::Cmpp3SubmitReqPkt;
+    use crate::server::config::{Config, ConfigHandle};
+    use crate::server::pipeline::{Flow, SubmitContext, SubmitStage};
+    use crate::server::validate::{validate_submit, SubmitValidator};
+
+    fn submit() -> Cmpp3SubmitReqPkt {
+        let mut body = vec![0u8; 8];
+        body.extend_from_slice(&[1, 1, 1, 0]);
+        body.extend_from_slice(b"svc\0\0\0\0\0\0\0");
+        body.push(0);
+        body.extend_from_slice(&[0u8; 32]);
+        body.extend_from_slice(&[0, 0, 0, 8]);
+        body.extend_from_slice(b"900001");
+        body.extend_from_slice(b"01");
+        body.extend_from_slice(&[0u8; 6 + 17 + 17]);
+        let mut src_id = b"10690001".to_vec();
+        src_id.resize(21, 0);
+        body.extend_from_slice(&src_id);
+        body.push(1);
+        let mut dest = b"13800138000".to_vec();
+        dest.resize(32, 0);
+        body.extend_from_slice(&dest);
+        body.push(0);
+        body.push(4);
+        body.extend_from_slice(&[0, 0x68, 0, 0x69]);
+        body.extend_from_slice(&[0u8; 20]);
+        Cmpp3SubmitReqPkt::parse_frame(1, &mut body).unwrap()
+    }
+
+    #[test]
+    fn test_validate_submit() {
+        let mut cfg = Config::demo();
+        cfg.accounts[0].src_ids = vec!["1069".to_string()];
+        let account = cfg.account("900001");
+
+        let pkt = submit();
+        assert_eq!(pkt.msg_content, "hi");
+        assert_eq!(validate_submit("900001", account, &pkt), None);
+
+        let mut pkt = submit();
+        pkt.dest_usr_tl = 0;
+        assert_eq!(validate_submit("900001", account, &pkt), Some(1));
+
+        let mut pkt = submit();
+        pkt.msg_bytes.push(0);
+        assert_eq!(validate_submit("900001", account, &pkt), Some(4));
+
+        let mut pkt = submit();
+        pkt.fee_type = "09".to_string();
+        assert_eq!(validate_submit("900001", account, &pkt), Some(5));
+
+        let mut pkt = submit();
+        pkt.service_id = "".to_string();
+        assert_eq!(validate_submit("900001", account, &pkt), Some(7));
+
+        let mut pkt = submit();
+        pkt.src_id = "95555".to_string();
+        assert_eq!(validate_submit("900001", account, &pkt), Some(10));
+
+        assert_eq!(validate_submit("900002", account, &submit()), Some(11));
+
+        let mut pkt = submit();
+        pkt.dest_terminal_id = vec!["abc".to_string()];
+        assert_eq!(validate_submit("900001", account, &pkt), Some(13));
+    }
+
+    #[tokio::test]
+    async fn test_max_msg_level() {
+        let mut cfg = Config::demo();
+        cfg.accounts[0].max_msg_level = Some(3);
+        let validator = SubmitValidator::new(ConfigHandle::new(cfg).subscribe());
+
+        let mut pkt = submit();
+        pkt.msg_level = 9;
+        let mut ctx = SubmitContext::new("900001", "127.0.0.1:5000".parse().unwrap(), pkt);
+        assert_eq!(validator.process(&mut ctx).await, Flow::Continue);
+        assert_eq!(ctx.submit.msg_level, 3);
+
+        let mut pkt = submit();
+        pkt.msg_level = 1;
+        let mut ctx = SubmitContext::new("900001", "127.0.0.1:5000".parse().unwrap(), pkt);
+        assert_eq!(validator.process(&mut ctx).await, Flow::Continue);
+        assert_eq!(ctx.submit.msg_level, 1);
+    }
+}
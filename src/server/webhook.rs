@@ -0,0 +1,341 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::Local;
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use crate::server::cmd::deliver::Cmpp3DeliverReqPkt;
+use crate::server::config::{ConfigRx, WebhookTarget};
+use crate::server::session::DeliverHook;
+use crate::server::Result;
+
+// 同时进行的推送数
+const MAX_CONCURRENT: usize = 64;
+
+/// 待推送的事件
+struct Job {
+    sp_id: String,
+    target: WebhookTarget,
+    event: Value,
+}
+
+/// 按账号配置将状态报告和上行以 HTTP POST 推送给 SP
+///
+/// 请求体为 JSON, 带 `X-Timestamp` 和 `X-Signature` 请求头; 非 2xx 响应或请求失败时按指数退避重试,
+/// 达到最多尝试次数后写入死信文件。
+pub struct Webhooks {
+    config: ConfigRx,
+    queue: mpsc::Sender<Job>,
+    tls: TlsConnector,
+    dead_letter: Mutex<()>,
+}
+
+impl Webhooks {
+    /// 启动推送任务, 队列长度修改后需要重启, 需在 tokio 运行时中调用
+    pub fn start(config: ConfigRx) -> Arc<Webhooks> {
+        let (queue, rx) = mpsc::channel(config.borrow().webhook.queue_size.max(1));
+        let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        let tls = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let webhooks = Arc::new(Webhooks {
+            config,
+            queue,
+            tls: TlsConnector::from(Arc::new(tls)),
+            dead_letter: Mutex::new(()),
+        });
+        tokio::spawn(Self::run(Arc::downgrade(&webhooks), rx));
+        webhooks
+    }
+
+    /// 等待推送的事件数, 不含重试中的事件
+    pub fn queued(&self) -> usize {
+        self.queue.max_capacity() - self.queue.capacity()
+    }
+
+    async fn run(webhooks: Weak<Webhooks>, mut rx: mpsc::Receiver<Job>) {
+        let permits = Arc::new(Semaphore::new(MAX_CONCURRENT));
+        while let Some(job) = rx.recv().await {
+            let permit = permits.clone().acquire_owned().await.unwrap();
+            let webhooks = match webhooks.upgrade() {
+                Some(webhooks) => webhooks,
+                None => return,
+            };
+            tokio::spawn(async move {
+                webhooks.push(job).await;
+                drop(permit);
+            });
+        }
+    }
+
+    /// 推送一个事件, 失败时按指数退避重试
+    async fn push(&self, job: Job) {
+        let body = job.event.to_string();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let cfg = self.config.borrow().webhook.clone();
+            let err = match time_limit(cfg.timeout, self.post(&job.target, &body)).await {
+                Ok(status) if status.is_success() => {
+                    log::debug!("webhook delivered, sp_id: {}, url: {}, attempts: {}", job.sp_id, job.target.url, attempts);
+                    return;
+                }
+                Ok(status) => format!("http status {}", status),
+                Err(e) => e.to_string(),
+            };
+
+            if attempts >= cfg.max_attempts {
+                self.dead_letter(&job, attempts, &err);
+                return;
+            }
+            let backoff = cfg.backoff.saturating_mul(1 << (attempts - 1).min(16)).min(cfg.max_backoff);
+            log::warn!("webhook failed, sp_id: {}, url: {}, attempts: {}, retry in {}s, {}",
+                job.sp_id, job.target.url, attempts, backoff, err);
+            tokio::time::sleep(Duration::from_secs(backoff)).await;
+        }
+    }
+
+    async fn post(&self, target: &WebhookTarget, body: &str) -> Result<StatusCode> {
+        let uri: Uri = target.url.parse()?;
+        let https = match uri.scheme_str() {
+            Some("http") => false,
+            Some("https") => true,
+            _ => return Err(format!("unsupported webhook url: {}", target.url).into()),
+        };
+        let host = uri.host().ok_or("webhook url without host")?;
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+        let timestamp = Local::now().timestamp().to_string();
+        let req = Request::post(uri.path_and_query().map_or("/", |p| p.as_str()))
+            .header(HOST, uri.authority().map_or(host, |a| a.as_str()))
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Timestamp", &timestamp)
+            .header("X-Signature", format!("sha256={}", sign(&target.secret, &timestamp, body)))
+            .body(Full::new(Bytes::from(body.to_string())))?;
+
+        let stream = TcpStream::connect((host.trim_matches(['[', ']']), port)).await?;
+        if https {
+            let name = ServerName::try_from(host.trim_matches(['[', ']']).to_string())?;
+            send(self.tls.connect(name, stream).await?, req).await
+        } else {
+            send(stream, req).await
+        }
+    }
+
+    fn dead_letter(&self, job: &Job, attempts: u32, err: &str) {
+        // 事件中的上行内容不写日志, 只写入死信文件
+        log::error!("webhook gave up, sp_id: {}, url: {}, attempts: {}, {}, type: {}, msg_id: {}",
+            job.sp_id, job.target.url, attempts, err, job.event["type"], job.event["msg_id"]);
+        let path = match self.config.borrow().webhook.dead_letter.clone() {
+            Some(path) => path,
+            None => return,
+        };
+
+        let record = json!({
+            "time": Local::now().to_rfc3339(),
+            "sp_id": job.sp_id,
+            "url": job.target.url,
+            "attempts": attempts,
+            "error": err,
+            "event": job.event,
+        });
+        let _guard = self.dead_letter.lock().unwrap();
+        let res = OpenOptions::new().create(true).append(true).open(&path)
+            .and_then(|mut file| writeln!(file, "{}", record));
+        if let Err(e) = res {
+            log::error!("write webhook dead letter {} failed: {}", path.display(), e);
+        }
+    }
+}
+
+impl DeliverHook for Webhooks {
+    fn intercept(&self, sp_id: &str, deliver: Cmpp3DeliverReqPkt) -> Option<Cmpp3DeliverReqPkt> {
+        let target = self.config.borrow().account(sp_id).and_then(|a| a.webhook.clone());
+        let target = match target {
+            Some(target) => target,
+            None => return Some(deliver),
+        };
+
+        let job = Job { sp_id: sp_id.to_string(), target, event: event(sp_id, &deliver) };
+        if let Err(e) = self.queue.try_send(job) {
+            let job = match e {
+                mpsc::error::TrySendError::Full(job) | mpsc::error::TrySendError::Closed(job) => job,
+            };
+            self.dead_letter(&job, 0, "queue full");
+        }
+        None
+    }
+}
+
+/// 推送的 JSON, `type` 为 report 或 mo
+fn event(sp_id: &str, deliver: &Cmpp3DeliverReqPkt) -> Value {
+    match deliver.report {
+        Some(ref report) => json!({
+            "type": "report",
+            "sp_id": sp_id,
+            "msg_id": report.msg_id,
+            "dest_terminal_id": report.dest_terminal_id,
+            "stat": report.stat,
+            "submit_time": report.submit_time,
+            "done_time": report.done_time,
+        }),
+        None => json!({
+            "type": "mo",
+            "sp_id": sp_id,
+            "msg_id": deliver.msg_id,
+            "src_terminal_id": deliver.src_terminal_id,
+            "dest_id": deliver.dest_id,
+            "service_id": deliver.service_id,
+            "msg_fmt": deliver.msg_fmt,
+            "content": deliver.msg_content,
+            "link_id": deliver.link_id,
+        }),
+    }
+}
+
+/// "时间戳.请求体" 的 HMAC-SHA256, 小写 hex
+fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+async fn send<S>(stream: S, req: Request<Full<Bytes>>) -> Result<StatusCode>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            log::debug!("webhook connection error: {}", e);
+        }
+    });
+    Ok(sender.send_request(req).await?.status())
+}
+
+async fn time_limit<T>(secs: u64, fut: impl std::future::Future<Output = Result<T>>) -> Result<T> {
+    match tokio::time::timeout(Duration::from_secs(secs), fut).await {
+        Ok(res) => res,
+        Err(_) => Err(format!("timeout after {}s", secs).into()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use crate::server::cmd::deliver::{Cmpp3DeliverReqPkt, CmppReport};
+    use crate::server::config::{Config, ConfigHandle, WebhookTarget};
+    use crate::server::session::Sessions;
+    use crate::server::webhook::{sign, Webhooks};
+
+    /// 依次以 `statuses` 响应, 收到的请求发送到返回的通道
+    async fn serve(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; 4096];
+                let mut len = 0;
+                // 请求头之后按 Content-Length 读取请求体
+                loop {
+                    len += socket.read(&mut buf[len..]).await.unwrap();
+                    let req = String::from_utf8_lossy(&buf[..len]).to_string();
+                    if let Some((head, body)) = req.split_once("\r\n\r\n") {
+                        let content_len = head.lines()
+                            .find_map(|l| l.to_lowercase().strip_prefix("content-length: ").map(|v| v.parse::<usize>().unwrap()))
+                            .unwrap_or(0);
+                        if body.len() >= content_len {
+                            tx.send(req).unwrap();
+                            break;
+                        }
+                    }
+                }
+                let rsp = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+                socket.write_all(rsp.as_bytes()).await.unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn report() -> Cmpp3DeliverReqPkt {
+        let mut deliver = Cmpp3DeliverReqPkt::new();
+        deliver.report = Some(CmppReport { msg_id: 7, stat: "DELIVRD".to_string(), dest_terminal_id: "13800138000".to_string(), ..Default::default() });
+        deliver
+    }
+
+    #[tokio::test]
+    async fn test_webhook_retry() {
+        let (url, mut requests) = serve(vec![500, 200]).await;
+        let mut cfg = Config::demo();
+        cfg.accounts[0].webhook = Some(WebhookTarget { url, secret: "key".to_string() });
+        let handle = ConfigHandle::new(cfg);
+        let sessions = Arc::new(Sessions::default());
+        sessions.add_hook(Webhooks::start(handle.subscribe()));
+        let (tx, mut rx) = mpsc::channel(8);
+        let _guard = sessions.register("900001", "127.0.0.1:5000".parse().unwrap(), tx);
+
+        sessions.deliver("900001", report());
+        for _ in 0..2 {
+            let req = tokio::time::timeout(Duration::from_secs(5), requests.recv()).await.unwrap().unwrap();
+            assert!(req.starts_with("POST /hook HTTP/1.1"));
+            let (head, body) = req.split_once("\r\n\r\n").unwrap();
+            assert!(body.contains(r#""type":"report""#) && body.contains(r#""msg_id":7"#), "{}", body);
+            let header = |name: &str| head.lines().find_map(|l| l.strip_prefix(name)).unwrap().to_string();
+            let timestamp = header("x-timestamp: ");
+            assert_eq!(header("x-signature: "), format!("sha256={}", sign("key", &timestamp, body)));
+        }
+        // 推送的状态报告不再投递给 CMPP 会话
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_webhook_dead_letter() {
+        let (url, mut requests) = serve(vec![503]).await;
+        let path = std::env::temp_dir().join(format!("cmpp-webhook-{}.jsonl", std::process::id()));
+        let mut cfg = Config::demo();
+        cfg.accounts[0].webhook = Some(WebhookTarget { url, secret: "key".to_string() });
+        cfg.webhook.max_attempts = 1;
+        cfg.webhook.dead_letter = Some(path.clone());
+        let handle = ConfigHandle::new(cfg);
+        let sessions = Arc::new(Sessions::default());
+        sessions.add_hook(Webhooks::start(handle.subscribe()));
+
+        sessions.deliver("900001", report());
+        requests.recv().await.unwrap();
+        let mut content = String::new();
+        for _ in 0..50 {
+            content = std::fs::read_to_string(&path).unwrap_or_default();
+            if !content.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        std::fs::remove_file(&path).ok();
+        assert!(content.contains(r#""error":"http status 503 Service Unavailable""#), "{}", content);
+        assert!(content.contains(r#""msg_id":7"#));
+    }
+}
@@ -18,7 +18,7 @@ pub fn octet_string(s: String, fixed_length: usize) -> String {
 
 
 pub fn oct_string(v: Vec<u8>) -> String {
-    String::from_utf8(v).unwrap().replace("\0", "")
+    String::from_utf8_lossy(&v).replace("\0", "")
 }
 
 // 将假定为大端序UTF-16（即UCS-2）编码的字节切片转换为UTF-8字符串
@@ -26,7 +26,7 @@ pub fn ucs2_to_utf8(ucs2_bytes: &[u8]) -> Result<String, FromUtf16Error> {
 
 
     // 确保字节数组的长度是 2 的倍数，因为每个 UCS-2 字符是 2 个字节
-    if ucs2_bytes.len() % 2 != 0 {
+    if !ucs2_bytes.len().is_multiple_of(2) {
         panic!("UCS-2 byte array length must be even");
     }
 
@@ -40,6 +40,11 @@ pub fn ucs2_to_utf8(ucs2_bytes: &[u8]) -> Result<String, FromUtf16Error> {
     String::from_utf16(&utf16_chars)
 }
 
+// 将字符串编码为大端序UCS-2, 超出基本平面的字符按 UTF-16 代理对编码
+pub fn utf8_to_ucs2(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| c.to_be_bytes()).collect()
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -48,6 +53,6 @@ mod tests {
     #[test]
     fn test_octet_string() {
         let c = octet_string(String::from("a"), 3);
-        assert_eq!(c, "a  ")
+        assert_eq!(c, "a\0\0")
     }
 }
\ No newline at end of file
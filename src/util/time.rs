@@ -29,8 +29,7 @@ pub fn duration_to_nanos(d: Duration) -> u64 {
 #[inline]
 pub fn format_date(date: DateTime<Local>, format: &str) -> String {
     // 格式化本地时间为字符串
-    let formatted_local = date.format(format).to_string();
-    return formatted_local;
+    date.format(format).to_string()
 }
 
 
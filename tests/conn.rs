@@ -0,0 +1,1100 @@
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use bytes::BufMut;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::net::TcpListener;
+
+    use cmpp::server::cmd::{CMPP_ACTIVE_TEST, CMPP_ACTIVE_TEST_RESP, CMPP_CONNECT, CMPP_CONNECT_RESP, CMPP_SUBMIT,
+                            CMPP_SUBMIT_RESP, CMPP_TERMINATE, CMPP_TERMINATE_RESP};
+    use cmpp::server::{Account, Billing, Blacklist, BlacklistAction, Cdr, CdrConfig, Config, ConfigHandle, Conn, Counter,
+                       Dispatcher, Flow, KeywordAction, KeywordFilter, Metrics, MsgIdGen, PeerInfo, Phase, Pipeline, Reviews,
+                       RouteFile, RouteTable, Router, Scheduler, Sessions, SubmitContext, SubmitStage, SubmitValidator,
+                       UnknownCommandPolicy, UpstreamConfig};
+    #[cfg(feature = "http")]
+    use cmpp::server::{WebhookTarget, Webhooks};
+
+    const CMPP_DELIVER: u32 = 5;
+    const CMPP_DELIVER_RESP: u32 = 0x8000_0005;
+
+    fn peer() -> PeerInfo {
+        PeerInfo {
+            listener: Config::default().addr,
+            addr: "127.0.0.1:50000".parse().unwrap(),
+            cert: None,
+        }
+    }
+
+    // 带演示账号 900001 的配置
+    fn config() -> Config {
+        Config { accounts: vec![Account::new("900001", "888888")], ..Config::default() }
+    }
+
+    fn frame(command_id: u32, seq_id: u32, body: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(12 + body.len());
+        buf.put_u32(12 + body.len() as u32);
+        buf.put_u32(command_id);
+        buf.put_u32(seq_id);
+        buf.extend_from_slice(body);
+        buf
+    }
+
+    fn fixed(s: &str, len: usize) -> Vec<u8> {
+        let mut v = s.as_bytes().to_vec();
+        v.resize(len, 0);
+        v
+    }
+
+    fn connect_frame(sp_id: &str, password: &str) -> Vec<u8> {
+        let timestamp = 1019120000u32;
+        let mut src = fixed(sp_id, 6);
+        src.extend_from_slice(&[0u8; 9]);
+        src.extend_from_slice(password.as_bytes());
+        src.extend_from_slice(format!("{:010}", timestamp).as_bytes());
+
+        let mut body = fixed(sp_id, 6);
+        body.extend_from_slice(md5::compute(&src).as_slice());
+        body.put_u8(0x30);
+        body.put_u32(timestamp);
+        frame(CMPP_CONNECT, 1, &body)
+    }
+
+    fn submit_frame(seq_id: u32, msg_id: u64, dest: &str, content: &str) -> Vec<u8> {
+        submit_frame_to(seq_id, msg_id, &[dest], content)
+    }
+
+    fn submit_frame_to(seq_id: u32, msg_id: u64, dests: &[&str], content: &str) -> Vec<u8> {
+        let ucs2: Vec<u8> = content.encode_utf16().flat_map(|c| c.to_be_bytes()).collect();
+        let mut body = Vec::new();
+        body.put_u64(msg_id);
+        body.put_u8(1); // pk_total
+        body.put_u8(1); // pk_number
+        body.put_u8(1); // registered_delivery
+        body.put_u8(0); // msg_level
+        body.extend_from_slice(&fixed("svc", 10));
+        body.put_u8(0); // fee_user_type
+        body.extend_from_slice(&fixed("", 32));
+        body.put_u8(0); // fee_terminal_type
+        body.put_u8(0); // tp_pid
+        body.put_u8(0); // tp_udhi
+        body.put_u8(8); // msg_fmt
+        body.extend_from_slice(&fixed("900001", 6));
+        body.extend_from_slice(&fixed("01", 2));
+        body.extend_from_slice(&fixed("", 6));
+        body.extend_from_slice(&fixed("", 17));
+        body.extend_from_slice(&fixed("", 17));
+        body.extend_from_slice(&fixed("10690001", 21));
+        body.put_u8(dests.len() as u8); // dest_usr_tl
+        for dest in dests {
+            body.extend_from_slice(&fixed(dest, 32));
+        }
+        body.put_u8(0); // dest_terminal_type
+        body.put_u8(ucs2.len() as u8);
+        body.extend_from_slice(&ucs2);
+        body.extend_from_slice(&fixed("", 20));
+        frame(CMPP_SUBMIT, seq_id, &body)
+    }
+
+    async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> (u32, u32, Vec<u8>) {
+        let total_length = stream.read_u32().await.unwrap();
+        let command_id = stream.read_u32().await.unwrap();
+        let seq_id = stream.read_u32().await.unwrap();
+        let mut body = vec![0u8; total_length as usize - 12];
+        stream.read_exact(&mut body).await.unwrap();
+        (command_id, seq_id, body)
+    }
+
+    fn start(cfg: Config) -> DuplexStream {
+        start_with(cfg, Pipeline::new())
+    }
+
+    fn start_with(cfg: Config, pipeline: Pipeline) -> DuplexStream {
+        start_session(cfg, pipeline, Arc::new(Sessions::default()))
+    }
+
+    fn start_session(cfg: Config, pipeline: Pipeline, sessions: Arc<Sessions>) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(8192);
+        let handle = ConfigHandle::new(cfg);
+        let mut conn = Conn::new(handle.subscribe(), peer())
+            .with_pipeline(Arc::new(pipeline))
+            .with_sessions(sessions);
+        tokio::spawn(async move {
+            let _handle = handle;
+            let _ = conn.run(server).await;
+        });
+        client
+    }
+
+    #[tokio::test]
+    async fn test_connect_and_submit() {
+        let mut client = start(config());
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        let (command_id, seq_id, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_CONNECT_RESP);
+        assert_eq!(seq_id, 1);
+        assert_eq!(&body[..4], &[0, 0, 0, 0]);
+
+        client.write_all(&submit_frame(2, 7, "13800138000", "验证码 1234")).await.unwrap();
+        let (command_id, seq_id, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+        assert_eq!(seq_id, 2);
+        assert_eq!(&body[8..12], &[0, 0, 0, 0]);
+
+        // 状态报告中为回复给 SP 的 Msg_Id
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+        assert_eq!(&body[77..85], &7u64.to_be_bytes());
+        assert_eq!(&body[85..92], b"DELIVRD");
+    }
+
+    #[tokio::test]
+    async fn test_connect_bad_password() {
+        let mut client = start(config());
+
+        client.write_all(&connect_frame("900001", "000000")).await.unwrap();
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_CONNECT_RESP);
+        assert_eq!(&body[..4], &[0, 0, 0, 3]);
+
+        // 认证失败后会话关闭
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let cfg = Config { connect_timeout: 1, ..config() };
+        let mut client = start(cfg);
+
+        // 未发送 CMPP_CONNECT, 到期后会话关闭
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(std::time::Duration::from_secs(3), client.read_to_end(&mut rest)).await;
+        assert!(read.is_ok());
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_submit_before_connect() {
+        let mut client = start(config());
+
+        client.write_all(&submit_frame(1, 7, "13800138000", "hi")).await.unwrap();
+        let (command_id, seq_id, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+        assert_eq!(seq_id, 1);
+        assert_eq!(&body[8..12], &[0, 0, 0, 2]);
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_session_state() {
+        let mut client = start(config());
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        let (command_id, _, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_CONNECT_RESP);
+
+        client.write_all(&frame(CMPP_ACTIVE_TEST, 2, &[])).await.unwrap();
+        let (command_id, seq_id, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_ACTIVE_TEST_RESP);
+        assert_eq!(seq_id, 2);
+
+        client.write_all(&frame(CMPP_TERMINATE, 3, &[])).await.unwrap();
+        let (command_id, seq_id, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_TERMINATE_RESP);
+        assert_eq!(seq_id, 3);
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        let handle = ConfigHandle::new(config());
+        let start = |metrics: &Arc<Metrics>| {
+            let (client, server) = tokio::io::duplex(8192);
+            let mut conn = Conn::new(handle.subscribe(), peer()).with_metrics(metrics.clone());
+            tokio::spawn(async move { conn.run(server).await });
+            client
+        };
+
+        let mut client = start(&metrics);
+        client.write_all(&connect_frame("900001", "000000")).await.unwrap();
+        read_frame(&mut client).await;
+        assert_eq!(metrics.get(Counter::AuthFailures, &[("reason", "auth")]), 1);
+
+        let mut client = start(&metrics);
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+        client.write_all(&submit_frame(2, 7, "13800138000", "hi")).await.unwrap();
+        read_frame(&mut client).await;
+        let (command_id, seq_id, deliver) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+        let mut body = deliver[..8].to_vec();
+        body.put_u32(0);
+        client.write_all(&frame(CMPP_DELIVER_RESP, seq_id, &body)).await.unwrap();
+        client.write_all(&frame(CMPP_TERMINATE, 3, &[])).await.unwrap();
+        read_frame(&mut client).await;
+
+        assert_eq!(metrics.get(Counter::ConnectionsAuthenticated, &[("account", "900001")]), 1);
+        assert_eq!(metrics.get(Counter::Submits, &[("account", "900001"), ("result", "0")]), 1);
+        assert_eq!(metrics.get(Counter::DeliversSent, &[("account", "900001")]), 1);
+        assert_eq!(metrics.get(Counter::DeliversAcked, &[("account", "900001")]), 1);
+        assert!(metrics.render().contains("cmpp_submit_duration_seconds_count 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_kick_session() {
+        let sessions = Arc::new(Sessions::default());
+        let mut client = start_session(config(), Pipeline::new(), sessions.clone());
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+        client.write_all(&submit_frame(2, 7, "13800138000", "hi")).await.unwrap();
+        read_frame(&mut client).await;
+        read_frame(&mut client).await;
+
+        let list = sessions.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!((list[0].version, list[0].submits, list[0].delivers, list[0].window), (0x30, 1, 1, 1));
+
+        // 管理员断开会话: 发送 CMPP_TERMINATE, 收到响应后关闭
+        assert!(sessions.kick(list[0].id));
+        let (command_id, seq_id, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_TERMINATE);
+        client.write_all(&frame(CMPP_TERMINATE_RESP, seq_id, &[])).await.unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(sessions.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_twice() {
+        let mut client = start(config());
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        let (_, _, body) = read_frame(&mut client).await;
+        assert_eq!(&body[..4], &[0, 0, 0, 0]);
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_CONNECT_RESP);
+        assert_eq!(&body[..4], &[0, 0, 0, 5]);
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command_nack() {
+        let cfg = Config { unknown_command: UnknownCommandPolicy::Nack, ..config() };
+        let mut client = start(cfg);
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        client.write_all(&frame(0x0000_0010, 2, &[1, 2, 3])).await.unwrap();
+        let (command_id, seq_id, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, 0x8000_0010);
+        assert_eq!(seq_id, 2);
+        assert_eq!(body, vec![0, 0, 0, 2]);
+
+        // 回复通用错误后会话继续
+        client.write_all(&frame(CMPP_ACTIVE_TEST, 3, &[])).await.unwrap();
+        let (command_id, _, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_ACTIVE_TEST_RESP);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command_disconnect() {
+        let cfg = Config {
+            unknown_command: UnknownCommandPolicy::Disconnect,
+            unknown_command_limit: 2,
+            ..config()
+        };
+        let mut client = start(cfg);
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        client.write_all(&frame(0x0000_0010, 2, &[])).await.unwrap();
+        let (command_id, _, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, 0x8000_0010);
+
+        client.write_all(&frame(0x0000_0010, 3, &[])).await.unwrap();
+        let (command_id, _, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, 0x8000_0010);
+        // 达到阈值后发送 CMPP_TERMINATE 并断开
+        let (command_id, _, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_TERMINATE);
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    // 拒绝发往指定号码的消息
+    struct DenyDest(&'static str);
+
+    #[async_trait]
+    impl SubmitStage for DenyDest {
+        fn name(&self) -> &str {
+            "deny-dest"
+        }
+
+        async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+            if ctx.submit.dest_terminal_id.iter().any(|d| d == self.0) {
+                return Flow::Reject(13);
+            }
+            ctx.msg_id += 1000;
+            Flow::Continue
+        }
+    }
+
+    #[tokio::test]
+    async fn test_submit_pipeline() {
+        let pipeline = Pipeline::new().stage(Phase::Filtering, DenyDest("13900139000"));
+        let mut client = start_with(config(), pipeline);
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        client.write_all(&submit_frame(2, 7, "13900139000", "hi")).await.unwrap();
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+        assert_eq!(&body[8..12], &13u32.to_be_bytes());
+
+        // 被拒绝的消息没有状态报告, 下一条响应即为新的提交
+        client.write_all(&submit_frame(3, 8, "13800138000", "hi")).await.unwrap();
+        let (command_id, seq_id, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+        assert_eq!(seq_id, 3);
+        assert_eq!(&body[..8], &1008u64.to_be_bytes());
+        assert_eq!(&body[8..12], &[0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_submit_validation() {
+        let handle = ConfigHandle::new(config());
+        let pipeline = Pipeline::new().stage(Phase::Validation, SubmitValidator::new(handle.subscribe()));
+        let mut client = start_with(config(), pipeline);
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        // 消息体被截断, 回复消息结构错且会话继续
+        let mut truncated = submit_frame(2, 7, "13800138000", "hi");
+        truncated.truncate(100);
+        truncated[..4].copy_from_slice(&100u32.to_be_bytes());
+        client.write_all(&truncated).await.unwrap();
+        let (command_id, seq_id, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+        assert_eq!(seq_id, 2);
+        assert_eq!(&body[..8], &7u64.to_be_bytes());
+        assert_eq!(&body[8..12], &1u32.to_be_bytes());
+
+        // 接收号码不合法
+        client.write_all(&submit_frame(3, 8, "1380013800x", "hi")).await.unwrap();
+        let (command_id, seq_id, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+        assert_eq!(seq_id, 3);
+        assert_eq!(&body[8..12], &13u32.to_be_bytes());
+
+        client.write_all(&submit_frame(4, 9, "13800138000", "hi")).await.unwrap();
+        let (_, seq_id, body) = read_frame(&mut client).await;
+        assert_eq!(seq_id, 4);
+        assert_eq!(&body[8..12], &[0, 0, 0, 0]);
+    }
+
+    // 模拟上游网关: 接受登录和一条提交, 随后下发状态报告
+    async fn fake_ismg(listener: TcpListener) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        let (command_id, seq_id, body) = read_frame(&mut socket).await;
+        assert_eq!(command_id, CMPP_CONNECT);
+        assert_eq!(&body[..6], b"901234");
+        let mut rsp = vec![0u8; 4];
+        rsp.extend_from_slice(&[0u8; 16]);
+        rsp.put_u8(0x30);
+        socket.write_all(&frame(CMPP_CONNECT_RESP, seq_id, &rsp)).await.unwrap();
+
+        let (command_id, seq_id, body) = read_frame(&mut socket).await;
+        assert_eq!(command_id, CMPP_SUBMIT);
+        // Msg_src 替换为本网关在上游的账号
+        assert_eq!(&body[59..65], b"901234");
+        let mut rsp = Vec::new();
+        rsp.put_u64(0xABCDEF);
+        rsp.put_u32(0);
+        socket.write_all(&frame(CMPP_SUBMIT_RESP, seq_id, &rsp)).await.unwrap();
+
+        let mut report = Vec::new();
+        report.put_u64(0xABCDEF);
+        report.extend_from_slice(b"DELIVRD");
+        report.extend_from_slice(b"2410190930");
+        report.extend_from_slice(b"2410190931");
+        report.extend_from_slice(&fixed("13800138000", 32));
+        report.put_u32(1);
+        let mut body = Vec::new();
+        body.put_u64(0x1234);
+        body.extend_from_slice(&fixed("10690001", 21));
+        body.extend_from_slice(&fixed("svc", 10));
+        body.extend_from_slice(&[0, 0, 0]);
+        body.extend_from_slice(&fixed("13800138000", 32));
+        body.put_u8(0);
+        body.put_u8(1); // registered_delivery
+        body.put_u8(report.len() as u8);
+        body.extend_from_slice(&report);
+        body.extend_from_slice(&fixed("", 20));
+        socket.write_all(&frame(CMPP_DELIVER, 100, &body)).await.unwrap();
+
+        let (command_id, seq_id, body) = read_frame(&mut socket).await;
+        assert_eq!(command_id, CMPP_DELIVER_RESP);
+        assert_eq!(seq_id, 100);
+        assert_eq!(&body[..8], &0x1234u64.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = UpstreamConfig {
+            name: "ismg".to_string(),
+            addr: listener.local_addr().unwrap().to_string(),
+            sp_id: "901234".to_string(),
+            password: "secret".to_string(),
+            version: 0x30,
+            connections: 1,
+            window: 16,
+            active_test: 30,
+        };
+        let ismg = tokio::spawn(fake_ismg(listener));
+
+        let cfg = Config { upstreams: vec![upstream], ..config() };
+        let sessions = Arc::new(Sessions::default());
+        let dispatcher = Dispatcher::start(&cfg, Arc::new(MsgIdGen::new(1)), sessions.clone(), None);
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Phase::Dispatch, dispatcher);
+        let mut client = start_session(cfg, pipeline, sessions);
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        client.write_all(&submit_frame(2, 0, "13800138000", "hi")).await.unwrap();
+        let (command_id, seq_id, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+        assert_eq!(seq_id, 2);
+        assert_eq!(&body[8..12], &[0, 0, 0, 0]);
+        let msg_id = body[..8].to_vec();
+        assert_ne!(msg_id, 0xABCDEFu64.to_be_bytes());
+
+        // 上游的状态报告转换为本网关的 Msg_Id 后投递给 SP
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+        assert_eq!(&body[8..16], b"10690001");
+        assert_eq!(body[75], 1);
+        assert_eq!(body[76], 71);
+        assert_eq!(&body[77..85], msg_id.as_slice());
+        assert_eq!(&body[85..92], b"DELIVRD");
+
+        ismg.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_route_failover() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = |name: &str, addr: String| UpstreamConfig {
+            name: name.to_string(),
+            addr,
+            sp_id: "901234".to_string(),
+            password: "secret".to_string(),
+            version: 0x30,
+            connections: 1,
+            window: 16,
+            active_test: 30,
+        };
+        // 无法连接的上游
+        let down = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let upstreams = vec![upstream("down", down), upstream("ismg", listener.local_addr().unwrap().to_string())];
+        let ismg = tokio::spawn(fake_ismg(listener));
+
+        let file: RouteFile = toml::from_str(r#"
+[[routes]]
+name = "cmcc"
+prefixes = ["134-139"]
+channels = [{ upstream = "down" }]
+failover = ["ismg"]
+"#).unwrap();
+        let router = Arc::new(Router::with_table(RouteTable::new(file.routes).unwrap()));
+        assert_eq!(router.which(Some("900001"), "13800138000").unwrap().route, "cmcc");
+        assert!(router.which(Some("900001"), "17700000000").is_none());
+
+        let cfg = Config { upstreams, submit_timeout: 1, ..config() };
+        let sessions = Arc::new(Sessions::default());
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Phase::Routing, router);
+        pipeline.add(Phase::Dispatch, Dispatcher::start(&cfg, Arc::new(MsgIdGen::new(1)), sessions.clone(), None));
+        let mut client = start_session(cfg, pipeline, sessions);
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        client.write_all(&submit_frame(2, 0, "13800138000", "hi")).await.unwrap();
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+        assert_eq!(&body[8..12], &[0, 0, 0, 0]);
+
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+        assert_eq!(&body[85..92], b"DELIVRD");
+
+        ismg.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keyword_filter() {
+        let mut cfg = config();
+        cfg.keywords.words = vec!["发票".to_string()];
+        cfg.keywords.result = Some(99);
+        let handle = ConfigHandle::new(cfg.clone());
+        let sessions = Arc::new(Sessions::default());
+        let reviews = Arc::new(Reviews::new(Arc::new(MsgIdGen::new(1)), sessions.clone()));
+        let filter = KeywordFilter::start(handle.subscribe(), reviews.clone()).unwrap();
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Phase::Filtering, filter);
+        let mut client = start_session(cfg.clone(), pipeline, sessions);
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        // 全角字符按半角匹配
+        client.write_all(&submit_frame(2, 0, "13800138000", "代开发票")).await.unwrap();
+        let (_, seq_id, body) = read_frame(&mut client).await;
+        assert_eq!(seq_id, 2);
+        assert_eq!(&body[8..12], &99u32.to_be_bytes());
+
+        // 转人工审核: 回复成功, 拒绝后下发 REJECTD 状态报告
+        cfg.keywords.action = KeywordAction::Review;
+        handle.update(cfg);
+        client.write_all(&submit_frame(3, 0, "13800138000", "代开发票")).await.unwrap();
+        let (command_id, seq_id, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+        assert_eq!(seq_id, 3);
+        assert_eq!(&body[8..12], &[0, 0, 0, 0]);
+        let held = reviews.list();
+        assert_eq!(held.len(), 1);
+        assert_eq!(&body[..8], &held[0].ctx.msg_id.to_be_bytes());
+
+        assert!(reviews.reject(held[0].ctx.msg_id).await);
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+        assert_eq!(&body[77..85], &held[0].ctx.msg_id.to_be_bytes());
+        assert_eq!(&body[85..92], b"REJECTD");
+    }
+
+    #[tokio::test]
+    async fn test_blacklist() {
+        let mut cfg = config();
+        cfg.accounts[0].blacklist = vec!["13800138000".to_string()];
+        let handle = ConfigHandle::new(cfg.clone());
+        let blacklist = Blacklist::start(handle.subscribe()).unwrap();
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Phase::Filtering, blacklist.clone());
+        let mut client = start_with(cfg, pipeline);
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        client.write_all(&submit_frame(2, 0, "13800138000", "hi")).await.unwrap();
+        let (_, _, body) = read_frame(&mut client).await;
+        assert_eq!(&body[8..12], &13u32.to_be_bytes());
+
+        // 用户退订后不能再发送
+        assert!(blacklist.on_mo("900001", "13800138001", "0000"));
+        client.write_all(&submit_frame(3, 0, "13800138001", "hi")).await.unwrap();
+        let (_, _, body) = read_frame(&mut client).await;
+        assert_eq!(&body[8..12], &13u32.to_be_bytes());
+    }
+
+    // 直接运行会话, 返回会话结束时的错误信息
+    fn run_conn(cfg: Config) -> (DuplexStream, tokio::task::JoinHandle<Option<String>>) {
+        let (client, server) = tokio::io::duplex(8192);
+        let handle = ConfigHandle::new(cfg);
+        let mut conn = Conn::new(handle.subscribe(), peer());
+        let run = tokio::spawn(async move {
+            let _handle = handle;
+            conn.run(server).await.err().map(|e| e.to_string())
+        });
+        (client, run)
+    }
+
+    #[tokio::test]
+    async fn test_short_frames() {
+        // 过短的 CMPP_CONNECT 结束会话而不是使任务 panic
+        let (mut client, run) = run_conn(config());
+        client.write_all(&frame(CMPP_CONNECT, 1, &[])).await.unwrap();
+        let err = run.await.unwrap().unwrap();
+        assert!(err.contains("connect body too short"), "{}", err);
+
+        let (mut client, run) = run_conn(config());
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+        client.write_all(&frame(CMPP_DELIVER_RESP, 2, &[0u8; 4])).await.unwrap();
+        let err = run.await.unwrap().unwrap();
+        assert!(err.contains("deliver resp body too short"), "{}", err);
+
+        // 错误信息为拒绝时的状态
+        let (mut client, run) = run_conn(config());
+        client.write_all(&submit_frame(1, 7, "13800138000", "hi")).await.unwrap();
+        read_frame(&mut client).await;
+        let err = run.await.unwrap().unwrap();
+        assert!(err.contains("AwaitingConnect"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_account_deny_ips() {
+        let mut cfg = config();
+        cfg.accounts[0].allow_ips = vec!["127.0.0.0/8".parse().unwrap()];
+        cfg.accounts[0].deny_ips = vec!["127.0.0.1".parse().unwrap()];
+        let mut client = start(cfg);
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_CONNECT_RESP);
+        assert_eq!(&body[..4], &[0, 0, 0, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retransmit() {
+        let metrics = Arc::new(Metrics::default());
+        let handle = ConfigHandle::new(Config { deliver_timeout: 1, deliver_retries: 1, ..config() });
+        let (mut client, server) = tokio::io::duplex(8192);
+        let mut conn = Conn::new(handle.subscribe(), peer()).with_metrics(metrics.clone());
+        tokio::spawn(async move { conn.run(server).await });
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+        client.write_all(&submit_frame(2, 7, "13800138000", "hi")).await.unwrap();
+        read_frame(&mut client).await;
+        let (command_id, seq_id, first) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+
+        // 未回复 CMPP_DELIVER_RESP, 超时后以相同内容重发一次
+        let (command_id, resent_seq_id, resent) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+        assert_eq!((resent_seq_id, resent), (seq_id, first));
+        assert_eq!(metrics.get(Counter::Retransmissions, &[("account", "900001")]), 1);
+
+        // 达到重发次数后不再重发
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(std::time::Duration::from_millis(2500), client.read(&mut buf)).await;
+        assert!(read.is_err());
+        assert_eq!(metrics.get(Counter::Retransmissions, &[("account", "900001")]), 1);
+    }
+
+    // 发往指定号码的消息在流水线中等待一段时间
+    struct SlowDest(&'static str);
+
+    #[async_trait]
+    impl SubmitStage for SlowDest {
+        fn name(&self) -> &str {
+            "slow-dest"
+        }
+
+        async fn process(&self, ctx: &mut SubmitContext) -> Flow {
+            if ctx.submit.dest_terminal_id.iter().any(|d| d == self.0) {
+                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            }
+            Flow::Continue
+        }
+    }
+
+    #[tokio::test]
+    async fn test_submit_concurrent() {
+        let pipeline = Pipeline::new().stage(Phase::Dispatch, SlowDest("13900139000"));
+        let mut client = start_with(config(), pipeline);
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        // 慢的提交不阻塞之后的提交
+        client.write_all(&submit_frame(2, 7, "13900139000", "hi")).await.unwrap();
+        client.write_all(&submit_frame(3, 8, "13800138000", "hi")).await.unwrap();
+        let (command_id, seq_id, _) = read_frame(&mut client).await;
+        assert_eq!((command_id, seq_id), (CMPP_SUBMIT_RESP, 3));
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+        assert_eq!(&body[77..85], &8u64.to_be_bytes());
+        let (command_id, seq_id, _) = read_frame(&mut client).await;
+        assert_eq!((command_id, seq_id), (CMPP_SUBMIT_RESP, 2));
+    }
+
+    // 模拟响应缓慢的上游网关: 接受登录后只记录收到的提交数, 不回复
+    async fn slow_ismg(listener: TcpListener, submits: Arc<AtomicUsize>) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let (command_id, seq_id, _) = read_frame(&mut socket).await;
+        assert_eq!(command_id, CMPP_CONNECT);
+        let mut rsp = vec![0u8; 4];
+        rsp.extend_from_slice(&[0u8; 16]);
+        rsp.put_u8(0x30);
+        socket.write_all(&frame(CMPP_CONNECT_RESP, seq_id, &rsp)).await.unwrap();
+
+        while let Ok(total_length) = socket.read_u32().await {
+            let mut rest = vec![0u8; total_length as usize - 4];
+            if socket.read_exact(&mut rest).await.is_err() {
+                break;
+            }
+            if rest[..4] == CMPP_SUBMIT.to_be_bytes() {
+                submits.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_timeout() {
+        let slow = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = |name: &str, addr: String, window: usize| UpstreamConfig {
+            name: name.to_string(),
+            addr,
+            sp_id: "901234".to_string(),
+            password: "secret".to_string(),
+            version: 0x30,
+            connections: 1,
+            window,
+            active_test: 30,
+        };
+        let upstreams = vec![
+            upstream("slow", slow.local_addr().unwrap().to_string(), 1),
+            upstream("ismg", listener.local_addr().unwrap().to_string(), 16),
+        ];
+        let submits = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(slow_ismg(slow, submits.clone()));
+        let ismg = tokio::spawn(fake_ismg(listener));
+
+        let file: RouteFile = toml::from_str(r#"
+[[routes]]
+name = "cmcc"
+prefixes = ["134-139"]
+channels = [{ upstream = "slow" }]
+failover = ["ismg"]
+"#).unwrap();
+        let router = Arc::new(Router::with_table(RouteTable::new(file.routes).unwrap()));
+        let cfg = Config { upstreams, submit_timeout: 1, ..config() };
+        let sessions = Arc::new(Sessions::default());
+        let dispatcher = Dispatcher::start(&cfg, Arc::new(MsgIdGen::new(1)), sessions.clone(), None);
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Phase::Routing, router);
+        pipeline.add(Phase::Dispatch, dispatcher.clone());
+        // 两个上游都登录后再提交, 保证先尝试 slow
+        while dispatcher.online("slow") == 0 || dispatcher.online("ismg") == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let mut client = start_session(cfg, pipeline, sessions);
+
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        // 第一条写出后 slow 不回复并占满窗口, 第二条排队到超时
+        client.write_all(&submit_frame(2, 0, "13800138000", "hi")).await.unwrap();
+        client.write_all(&submit_frame(3, 0, "13800138000", "hi")).await.unwrap();
+        let mut results = std::collections::HashMap::new();
+        let mut reports = 0;
+        for _ in 0..3 {
+            let (command_id, seq_id, body) = read_frame(&mut client).await;
+            match command_id {
+                CMPP_SUBMIT_RESP => {
+                    results.insert(seq_id, u32::from_be_bytes(body[8..12].try_into().unwrap()));
+                }
+                CMPP_DELIVER => {
+                    assert_eq!(&body[85..92], b"DELIVRD");
+                    reports += 1;
+                }
+                _ => panic!("unexpected command: {:#x}", command_id),
+            }
+        }
+        // 已写出未响应的不改投, 按流量控制错回复; 未写出的改投 ismg
+        assert_eq!(results[&2], 8);
+        assert_eq!(results[&3], 0);
+        assert_eq!(reports, 1);
+        ismg.await.unwrap();
+
+        // slow 的窗口释放后不再发送已放弃的提交
+        tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+        assert_eq!(submits.load(Ordering::SeqCst), 1);
+        assert_eq!(dispatcher.queued("slow"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_requeue_unacked() {
+        let sessions = Arc::new(Sessions::default());
+        let mut client = start_session(config(), Pipeline::new(), sessions.clone());
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+        client.write_all(&submit_frame(2, 7, "13800138000", "hi")).await.unwrap();
+        read_frame(&mut client).await;
+        let (command_id, _, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+
+        // 未确认的状态报告在会话结束后交还会话表
+        client.write_all(&frame(CMPP_TERMINATE, 3, &[])).await.unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(sessions.queued("900001"), 1);
+
+        // 同一 SP 重新登录后补发
+        let mut client = start_session(config(), Pipeline::new(), sessions.clone());
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+        assert_eq!(&body[77..85], &7u64.to_be_bytes());
+        assert_eq!(sessions.queued("900001"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_billing_refund() {
+        let mut cfg = config();
+        cfg.accounts[0].balance = Some(1);
+        let handle = ConfigHandle::new(cfg.clone());
+        let billing = Billing::start(handle.subscribe()).unwrap();
+        let mut pipeline = Pipeline::new().stage(Phase::Dispatch, DenyDest("13900139000"));
+        pipeline.add(Phase::Billing, billing.clone());
+        let mut client = start_with(cfg, pipeline);
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        // 发送失败时退还预付费, 余额仍可用于下一条
+        client.write_all(&submit_frame(2, 7, "13900139000", "hi")).await.unwrap();
+        let (_, _, body) = read_frame(&mut client).await;
+        assert_eq!(&body[8..12], &13u32.to_be_bytes());
+        assert_eq!(billing.balance("900001"), Some(1));
+
+        client.write_all(&submit_frame(3, 8, "13800138000", "hi")).await.unwrap();
+        let (_, _, body) = read_frame(&mut client).await;
+        assert_eq!(&body[8..12], &[0, 0, 0, 0]);
+        assert_eq!(billing.balance("900001"), Some(0));
+        assert_eq!(billing.usage("900001").segments, 1);
+    }
+
+    #[tokio::test]
+    async fn test_schedule_shutdown() {
+        let sessions = Arc::new(Sessions::default());
+        let scheduler = Scheduler::start(Arc::new(MsgIdGen::new(1)), sessions.clone());
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Phase::Persistence, scheduler.clone());
+        let mut client = start_session(config(), pipeline.clone(), sessions);
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        // At_Time 为一小时后, 回复成功但不下发状态报告
+        let mut submit = submit_frame(2, 0, "13800138000", "hi");
+        submit[103..119].copy_from_slice(b"000000010000000R");
+        client.write_all(&submit).await.unwrap();
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+        assert_eq!(&body[8..12], &[0, 0, 0, 0]);
+        let msg_id = body[..8].to_vec();
+        assert_eq!(scheduler.len(), 1);
+
+        // 停机时取消并下发失败报告
+        assert_eq!(scheduler.cancel_all(&pipeline).await, 1);
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+        assert_eq!(&body[77..85], msg_id.as_slice());
+        assert_eq!(&body[85..92], b"DELETED");
+    }
+
+    #[tokio::test]
+    async fn test_blacklist_drop() {
+        let mut cfg = config();
+        cfg.blacklist.action = BlacklistAction::Drop;
+        cfg.accounts[0].blacklist = vec!["13800138000".to_string()];
+        let handle = ConfigHandle::new(cfg.clone());
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Phase::Filtering, Blacklist::start(handle.subscribe()).unwrap());
+        let mut client = start_with(cfg, pipeline);
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        client.write_all(&submit_frame_to(2, 7, &["13800138000", "13800138001"], "hi")).await.unwrap();
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+        assert_eq!(&body[8..12], &[0, 0, 0, 0]);
+
+        // 去掉的号码下发 BLKLIST, 其余号码正常发送
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+        assert_eq!(&body[42..53], b"13800138000");
+        assert_eq!(&body[77..85], &7u64.to_be_bytes());
+        assert_eq!(&body[85..92], b"BLKLIST");
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+        assert_eq!(&body[42..53], b"13800138001");
+        assert_eq!(&body[85..92], b"DELIVRD");
+    }
+
+    #[tokio::test]
+    async fn test_cdr_final_stat() {
+        let dir = std::env::temp_dir().join(format!("cmpp-conn-cdr-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cfg = Config { cdr: Some(CdrConfig { dir: dir.clone(), ..CdrConfig::default() }), ..config() };
+        let handle = ConfigHandle::new(cfg.clone());
+        let cdr = Cdr::start(handle.subscribe(), Arc::new(MsgIdGen::new(1)));
+        let sessions = Arc::new(Sessions::default());
+        sessions.add_hook(cdr.clone());
+
+        // 写入话单后在发送环节被拒绝
+        let mut pipeline = Pipeline::new().stage(Phase::Dispatch, DenyDest("13900139000"));
+        pipeline.add(Phase::Persistence, cdr.clone());
+        let mut client = start_session(cfg.clone(), pipeline, sessions.clone());
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+        client.write_all(&submit_frame(2, 0, "13900139000", "hi")).await.unwrap();
+        let (_, _, body) = read_frame(&mut client).await;
+        assert_eq!(&body[8..12], &13u32.to_be_bytes());
+        // 状态报告投递给账号的任一在线会话, 先等第一个会话下线
+        drop(client);
+        while sessions.online("900001") > 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // 本网关的状态报告经过会话表的投递拦截更新话单
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Phase::Persistence, cdr.clone());
+        let mut client = start_session(cfg, pipeline, sessions);
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+        client.write_all(&submit_frame(2, 0, "13800138000", "hi")).await.unwrap();
+        read_frame(&mut client).await;
+        let (command_id, _, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_DELIVER);
+        assert_eq!(cdr.pending(), 0);
+
+        let mut content = String::new();
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            content.push_str(&std::fs::read_to_string(entry.unwrap().path()).unwrap());
+        }
+        assert!(content.contains(",13900139000,1,RJ:0013,"), "{}", content);
+        assert!(content.contains(",13800138000,1,DELIVRD,"), "{}", content);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // 接收一个 HTTP 请求并回复 200, 返回完整的请求
+    #[cfg(feature = "http")]
+    async fn webhook_receiver(listener: TcpListener) -> String {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 4096];
+        let mut len = 0;
+        let req = loop {
+            len += socket.read(&mut buf[len..]).await.unwrap();
+            let req = String::from_utf8_lossy(&buf[..len]).to_string();
+            let complete = req.split_once("\r\n\r\n").is_some_and(|(head, body)| {
+                head.lines()
+                    .find_map(|l| l.to_lowercase().strip_prefix("content-length: ").map(|v| v.parse::<usize>().unwrap()))
+                    .is_some_and(|content_len| body.len() >= content_len)
+            });
+            if complete {
+                break req;
+            }
+        };
+        socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await.unwrap();
+        req
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn test_webhook_report() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let receiver = tokio::spawn(webhook_receiver(listener));
+        let mut cfg = config();
+        cfg.accounts[0].webhook = Some(WebhookTarget { url, secret: "key".to_string() });
+        let handle = ConfigHandle::new(cfg.clone());
+        let sessions = Arc::new(Sessions::default());
+        sessions.add_hook(Webhooks::start(handle.subscribe()));
+        let mut client = start_session(cfg, Pipeline::new(), sessions);
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        client.write_all(&submit_frame(2, 7, "13800138000", "hi")).await.unwrap();
+        let (command_id, _, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+
+        // 配置了回调的账号以 HTTP POST 接收状态报告
+        let req = tokio::time::timeout(std::time::Duration::from_secs(5), receiver).await.unwrap().unwrap();
+        assert!(req.starts_with("POST /hook HTTP/1.1"), "{}", req);
+        assert!(req.to_lowercase().contains("x-signature: sha256="), "{}", req);
+        let body = req.split_once("\r\n\r\n").unwrap().1;
+        assert!(body.contains(r#""type":"report""#) && body.contains(r#""msg_id":7"#) && body.contains("DELIVRD"), "{}", body);
+
+        // 不再通过 CMPP_DELIVER 投递
+        client.write_all(&frame(CMPP_ACTIVE_TEST, 3, &[])).await.unwrap();
+        let (command_id, seq_id, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_ACTIVE_TEST_RESP);
+        assert_eq!(seq_id, 3);
+    }
+    #[tokio::test]
+    async fn test_blacklist_dispatch() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = UpstreamConfig {
+            name: "ismg".to_string(),
+            addr: listener.local_addr().unwrap().to_string(),
+            sp_id: "901234".to_string(),
+            password: "secret".to_string(),
+            version: 0x30,
+            connections: 1,
+            window: 16,
+            active_test: 30,
+        };
+        let ismg = tokio::spawn(fake_ismg(listener));
+
+        let mut cfg = Config { upstreams: vec![upstream], ..config() };
+        cfg.blacklist.action = BlacklistAction::Drop;
+        cfg.accounts[0].blacklist = vec!["13800138001".to_string()];
+        let handle = ConfigHandle::new(cfg.clone());
+        let sessions = Arc::new(Sessions::default());
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Phase::Filtering, Blacklist::start(handle.subscribe()).unwrap());
+        pipeline.add(Phase::Dispatch, Dispatcher::start(&cfg, Arc::new(MsgIdGen::new(1)), sessions.clone(), None));
+        let mut client = start_session(cfg, pipeline, sessions);
+        client.write_all(&connect_frame("900001", "888888")).await.unwrap();
+        read_frame(&mut client).await;
+
+        client.write_all(&submit_frame_to(2, 0, &["13800138000", "13800138001"], "hi")).await.unwrap();
+        let (command_id, _, body) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_SUBMIT_RESP);
+        assert_eq!(&body[8..12], &[0, 0, 0, 0]);
+
+        // 去掉的号码只下发一次 BLKLIST, 其余号码由上游返回状态报告
+        let (mut blocked, mut delivered) = (0, 0);
+        while blocked == 0 || delivered == 0 {
+            let (command_id, _, body) = read_frame(&mut client).await;
+            assert_eq!(command_id, CMPP_DELIVER);
+            match &body[85..92] {
+                b"BLKLIST" => {
+                    assert_eq!(&body[42..53], b"13800138001");
+                    blocked += 1;
+                }
+                b"DELIVRD" => delivered += 1,
+                stat => panic!("unexpected stat {:?}", stat),
+            }
+        }
+        client.write_all(&frame(CMPP_ACTIVE_TEST, 3, &[])).await.unwrap();
+        let (command_id, seq_id, _) = read_frame(&mut client).await;
+        assert_eq!(command_id, CMPP_ACTIVE_TEST_RESP);
+        assert_eq!(seq_id, 3);
+        assert_eq!(blocked, 1);
+
+        ismg.await.unwrap();
+    }
+}
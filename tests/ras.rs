@@ -2,7 +2,7 @@
 #[cfg(test)]
 mod tests {
     use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
-    use rsa::pkcs1::{DecodeRsaPublicKey, DecodeRsaPrivateKey, EncodeRsaPrivateKey, EncodeRsaPublicKey};
+    use rsa::pkcs1::{DecodeRsaPublicKey, DecodeRsaPrivateKey, EncodeRsaPublicKey};
     use base64::{Engine as _, engine::general_purpose};
 
     pub fn encrypt(message: &str, public_key_pem: &str) -> String {
@@ -54,7 +54,7 @@ gaWlAoGBAIw6H98nq5QfI2K6yApLKENkn3d7sXW2Exokql/sOs9c47Hl4NknU7xa
         let public_key_pem = private_key.to_public_key().to_pkcs1_pem(rsa::pkcs1::LineEnding::LF).unwrap();
         println!("pub_key_pem: {}", public_key_pem);
 
-        let aa = encrypt("12345", &public_key_pem);
+        let _aa = encrypt("12345", &public_key_pem);
         let bb = decrypt("d163Z/iLQEbnmBjL3X9TcLD8cEHonwePEnPhd4FFQq83fCjBu9lvWsTB0+7c+lv2RiagH1FPUAWj2pP3EVgf7WCekCpRKuk6CqS/wBCYYE/6ae0+6/rUOvlkqaAeYGXwi2Ppe1Ef3fjAj7dEHrgxvAeumF7JGwXA10NPeY02xyG9bISN8Z0W0rNDRAYSI6M0OoRoieaxTJytoxgfEEXqZCCuX06BkJf6JuPqZt6fJ78SAobvEWrWyIZ5O/zFiCB1pikSxoxTb6V0frBbrx1Qztqa9P88R4dM7xOMo6bXtObmysGiQMAES4eBxxMb/EmOu+1iwrP2iBOkWdX0mkqIGA", private_key_pem);
         println!("bb: {}", bb);
 